@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use analysis::cfg::Cfg;
+use disasm::Disassembly;
+use errors::*;
+use memory::Addr;
+
+/// Calls observed while running, attributed to the function that made them; this
+/// covers indirect calls which static analysis cannot resolve
+pub struct Recorder {
+    root: u16,
+    calls: BTreeMap<(u16, u16), Observed>,
+}
+
+#[derive(Clone, Default)]
+struct Observed {
+    count: u64,
+    sites: BTreeSet<u16>,
+}
+
+impl Recorder {
+    /// `root` is the function execution starts in
+    pub fn new(root: Addr) -> Recorder {
+        Recorder {
+            root: u16::from(root),
+            calls: BTreeMap::new(),
+        }
+    }
+
+    /// The function execution started in, which makes any calls made outside
+    /// of a call
+    pub fn root(&self) -> Addr {
+        Addr::from(self.root)
+    }
+
+    pub fn call(&mut self, caller: Addr, site: Addr, target: Addr) {
+        let o = self.calls
+            .entry((u16::from(caller), u16::from(target)))
+            .or_insert_with(Observed::default);
+        o.count += 1;
+        o.sites.insert(u16::from(site));
+    }
+
+    /// Starts again from `root`, e.g. after loading a save file, keeping the counts
+    pub fn reset(&mut self, root: Addr) {
+        self.root = u16::from(root);
+    }
+
+    /// Functions called while running, whether or not statically visible
+    pub fn callees(&self) -> Vec<Addr> {
+        self.calls
+            .keys()
+            .map(|&(_, callee)| callee)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(Addr::from)
+            .collect()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CallEdge {
+    /// call instructions making this call
+    pub sites: BTreeSet<u16>,
+    /// whether a direct `call <addr>` makes this call
+    pub direct: bool,
+    /// times the call was observed while running
+    pub count: u64,
+}
+
+/// Callers and callees, from direct calls in the code merged with any observed
+/// while running
+pub struct CallGraph {
+    edges: BTreeMap<u16, BTreeMap<u16, CallEdge>>,
+}
+
+impl CallGraph {
+    pub fn new(cfg: &Cfg, recorder: Option<&Recorder>) -> CallGraph {
+        let mut edges = BTreeMap::new();
+        for f in cfg.functions() {
+            let callees = edges.entry(f.entry).or_insert_with(BTreeMap::new);
+            for &(site, target) in &f.calls {
+                if let Some(t) = target {
+                    let e = callees.entry(t).or_insert_with(CallEdge::default);
+                    e.direct = true;
+                    e.sites.insert(site);
+                }
+            }
+        }
+        if let Some(r) = recorder {
+            for (&(caller, callee), o) in &r.calls {
+                let e = edges.entry(caller)
+                    .or_insert_with(BTreeMap::new)
+                    .entry(callee)
+                    .or_insert_with(CallEdge::default);
+                e.count += o.count;
+                e.sites.extend(o.sites.iter().cloned());
+            }
+        }
+        CallGraph { edges: edges }
+    }
+
+    pub fn callees(&self, f: Addr) -> Vec<(Addr, &CallEdge)> {
+        self.edges
+            .get(&u16::from(f))
+            .map(|cs| cs.iter().map(|(&c, e)| (Addr::from(c), e)).collect())
+            .unwrap_or_else(Vec::new)
+    }
+
+    pub fn callers(&self, f: Addr) -> Vec<(Addr, &CallEdge)> {
+        let f = u16::from(f);
+        self.edges
+            .iter()
+            .filter_map(|(&caller, cs)| cs.get(&f).map(|e| (Addr::from(caller), e)))
+            .collect()
+    }
+
+    /// Indented tree of the calls made from `root`; each function's callees are
+    /// only expanded the first time it appears
+    pub fn write_tree<W: Write>(&self, disasm: &Disassembly, root: Addr, w: &mut W) -> Result<()> {
+        writeln!(w, "{}", name(disasm, u16::from(root)))?;
+        let mut path = vec![u16::from(root)];
+        let mut shown = BTreeSet::new();
+        shown.insert(u16::from(root));
+        self.write_subtree(disasm, &mut path, &mut shown, w)
+    }
+
+    fn write_subtree<W: Write>(&self,
+                               disasm: &Disassembly,
+                               path: &mut Vec<u16>,
+                               shown: &mut BTreeSet<u16>,
+                               w: &mut W)
+                               -> Result<()> {
+        let f = *path.last().unwrap();
+        let callees = match self.edges.get(&f) {
+            Some(cs) => cs,
+            None => return Ok(()),
+        };
+        for (&c, e) in callees {
+            let mut line = format!("{}{}", "  ".repeat(path.len()), name(disasm, c));
+            if e.count > 0 {
+                line.push_str(&format!("  x{}", e.count));
+            }
+            if !e.direct {
+                line.push_str(" (indirect)");
+            }
+            if path.contains(&c) {
+                writeln!(w, "{} (recursive)", line)?;
+            } else if !shown.insert(c) {
+                writeln!(w, "{} ...", line)?;
+            } else {
+                writeln!(w, "{}", line)?;
+                path.push(c);
+                self.write_subtree(disasm, path, shown, w)?;
+                path.pop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Graphviz digraph; calls only seen while running are dashed, and edges are
+    /// labelled with their observed counts
+    pub fn write_dot<W: Write>(&self, disasm: &Disassembly, w: &mut W) -> Result<()> {
+        writeln!(w, "digraph calls {{")?;
+        writeln!(w, "    node [shape=box fontname=monospace];")?;
+        let mut nodes = BTreeSet::new();
+        for (&caller, cs) in &self.edges {
+            nodes.insert(caller);
+            nodes.extend(cs.keys().cloned());
+        }
+        for n in nodes {
+            writeln!(w, "    f_{:04x} [label=\"{}\"];", n, name(disasm, n))?;
+        }
+        for (&caller, cs) in &self.edges {
+            for (&callee, e) in cs {
+                let mut attrs = Vec::new();
+                if e.count > 0 {
+                    attrs.push(format!("label=\"{}\"", e.count));
+                }
+                if !e.direct {
+                    attrs.push("style=dashed".to_owned());
+                }
+                let attrs = if attrs.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", attrs.join(" "))
+                };
+                writeln!(w, "    f_{:04x} -> f_{:04x}{};", caller, callee, attrs)?;
+            }
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+}
+
+fn name(disasm: &Disassembly, f: u16) -> String {
+    disasm.label(Addr::from(f)).unwrap_or_else(|| format!("sub_{:04x}", f))
+}
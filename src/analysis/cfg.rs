@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, BTreeSet, btree_map};
+use std::io::Write;
+
+use disasm::{Disassembly, Item};
+use errors::*;
+use memory::Addr;
+use op_code::{Flow, OpCode};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    /// unconditional jump
+    Jump,
+    /// conditional jump, when taken
+    Taken,
+    /// on to the following instruction, including a conditional jump not taken
+    Next,
+    /// jump, or falling through, to the entry of another function
+    TailCall,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Edge {
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub start: u16,
+    /// addresses of the instructions in the block, in order
+    pub instrs: Vec<u16>,
+    pub succs: Vec<Edge>,
+}
+
+impl Block {
+    /// Address of the instruction ending the block
+    pub fn last(&self) -> u16 {
+        *self.instrs.last().unwrap_or(&self.start)
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub entry: u16,
+    pub blocks: BTreeMap<u16, Block>,
+    /// call sites within the function, along with the target when it is a literal
+    pub calls: Vec<(u16, Option<u16>)>,
+}
+
+impl Function {
+    /// Blocks with an edge to `addr`
+    pub fn preds(&self, addr: u16) -> Vec<u16> {
+        self.blocks
+            .values()
+            .filter(|b| b.succs.iter().any(|e| e.to == addr && e.kind != EdgeKind::TailCall))
+            .map(|b| b.start)
+            .collect()
+    }
+
+    /// Graphviz digraph with one node per block
+    pub fn write_dot<W: Write>(&self, disasm: &Disassembly, w: &mut W) -> Result<()> {
+        let name = disasm.label(Addr::from(self.entry))
+            .unwrap_or_else(|| format!("sub_{:04x}", self.entry));
+        writeln!(w, "digraph \"{}\" {{", name)?;
+        writeln!(w, "    node [shape=box fontname=monospace];")?;
+        for b in self.blocks.values() {
+            let mut label = String::new();
+            if let Some(l) = disasm.label(Addr::from(b.start)) {
+                label.push_str(&format!("{}:\\l", l));
+            }
+            for &a in &b.instrs {
+                if let Some((op, _)) = instr_at(disasm, a) {
+                    let text = format!("{:?}  {}", Addr::from(a), disasm.instr_text(op));
+                    label.push_str(&dot_escape(&text));
+                    label.push_str("\\l");
+                }
+            }
+            writeln!(w, "    b_{:04x} [label=\"{}\"];", b.start, label)?;
+            for e in &b.succs {
+                let style = match e.kind {
+                    EdgeKind::Jump => "",
+                    EdgeKind::Taken => " [color=green]",
+                    EdgeKind::Next if b.succs.len() > 1 => " [color=red]",
+                    EdgeKind::Next => "",
+                    EdgeKind::TailCall => " [style=dashed]",
+                };
+                if e.kind == EdgeKind::TailCall {
+                    writeln!(w,
+                             "    t_{:04x} [label=\"{}\" shape=plaintext];",
+                             e.to,
+                             disasm.label(Addr::from(e.to))
+                                 .unwrap_or_else(|| format!("{:?}", Addr::from(e.to))))?;
+                    writeln!(w, "    b_{:04x} -> t_{:04x}{};", b.start, e.to, style)?;
+                } else {
+                    writeln!(w, "    b_{:04x} -> b_{:04x}{};", b.start, e.to, style)?;
+                }
+            }
+        }
+        writeln!(w, "}}")?;
+        Ok(())
+    }
+}
+
+/// Control-flow graphs for every function found by the disassembly
+pub struct Cfg {
+    functions: BTreeMap<u16, Function>,
+}
+
+impl Cfg {
+    /// Functions start at the disassembly's entry points and call targets
+    pub fn new(disasm: &Disassembly) -> Cfg {
+        let entries = disasm.subroutines().into_iter().map(u16::from).collect::<BTreeSet<_>>();
+        let functions = entries.iter()
+            .map(|&e| (e, function(disasm, e, &entries)))
+            .collect();
+        Cfg { functions: functions }
+    }
+
+    pub fn functions(&self) -> btree_map::Values<u16, Function> {
+        self.functions.values()
+    }
+
+    pub fn function(&self, entry: Addr) -> Option<&Function> {
+        self.functions.get(&u16::from(entry))
+    }
+
+    /// The function whose blocks include `addr`, preferring the closest entry
+    pub fn function_containing(&self, addr: Addr) -> Option<&Function> {
+        let a = u16::from(addr);
+        self.functions
+            .values()
+            .rev()
+            .filter(|f| f.entry <= a)
+            .chain(self.functions.values().rev().filter(|f| f.entry > a))
+            .find(|f| f.blocks.values().any(|b| b.instrs.contains(&a)))
+    }
+}
+
+pub fn instr_at(disasm: &Disassembly, addr: u16) -> Option<(&OpCode, u16)> {
+    match disasm.item(Addr::from(addr)) {
+        Some(&Item::Instr(ref op, len)) => Some((op, len)),
+        _ => None,
+    }
+}
+
+fn function(disasm: &Disassembly, entry: u16, entries: &BTreeSet<u16>) -> Function {
+    let mut seen = BTreeSet::new();
+    let mut leaders = BTreeSet::new();
+    let mut calls = Vec::new();
+    let mut work = vec![entry];
+    leaders.insert(entry);
+    // other functions' entries end the walk; jumps or falling through there are
+    // tail calls
+    let local = |t: u16| t == entry || !entries.contains(&t);
+
+    while let Some(a) = work.pop() {
+        let (op, len) = match instr_at(disasm, a) {
+            Some(i) if !seen.contains(&a) => i,
+            _ => continue,
+        };
+        seen.insert(a);
+        let next = a + len;
+        let flow = op.instruction().flow;
+        if flow == Flow::Call {
+            calls.push((a, op.dest()));
+        }
+        match op.dest() {
+            Some(t) if (flow == Flow::Jump || flow == Flow::Branch) && local(t) => {
+                leaders.insert(t);
+                work.push(t);
+            }
+            _ => {}
+        }
+        if flow == Flow::Branch {
+            leaders.insert(next);
+        }
+        if op.instruction().falls_through() && local(next) {
+            work.push(next);
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &l in leaders.iter().filter(|l| seen.contains(l)) {
+        let mut instrs = vec![l];
+        let mut a = l;
+        // extend the block until control flow changes or another block starts
+        loop {
+            let (op, len) = instr_at(disasm, a).expect("seen instructions are decoded");
+            let next = a + len;
+            match op.instruction().flow {
+                Flow::Normal | Flow::Call if seen.contains(&next) &&
+                                             !leaders.contains(&next) => {
+                    instrs.push(next);
+                    a = next;
+                }
+                _ => break,
+            }
+        }
+        let succs = succs(disasm, a, &seen, &local);
+        blocks.insert(l,
+                      Block {
+                          start: l,
+                          instrs: instrs,
+                          succs: succs,
+                      });
+    }
+    Function {
+        entry: entry,
+        blocks: blocks,
+        calls: calls,
+    }
+}
+
+/// Edges leaving the block ending with the instruction at `last`
+fn succs<F>(disasm: &Disassembly, last: u16, seen: &BTreeSet<u16>, local: &F) -> Vec<Edge>
+    where F: Fn(u16) -> bool
+{
+    let (op, len) = instr_at(disasm, last).expect("seen instructions are decoded");
+    let instr = op.instruction();
+    let mut succs = Vec::new();
+    if let Some(t) = op.dest() {
+        let kind = match instr.flow {
+            _ if !local(t) => EdgeKind::TailCall,
+            Flow::Jump => EdgeKind::Jump,
+            _ => EdgeKind::Taken,
+        };
+        // a jump to somewhere that did not decode leads nowhere known
+        let known = kind == EdgeKind::TailCall || seen.contains(&t);
+        if (instr.flow == Flow::Jump || instr.flow == Flow::Branch) && known {
+            succs.push(Edge {
+                to: t,
+                kind: kind,
+            });
+        }
+    }
+    let next = last + len;
+    if instr.falls_through() && !local(next) {
+        succs.push(Edge {
+            to: next,
+            kind: EdgeKind::TailCall,
+        });
+    } else if instr.falls_through() && seen.contains(&next) {
+        succs.push(Edge {
+            to: next,
+            kind: EdgeKind::Next,
+        });
+    }
+    succs
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,514 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use analysis::cfg::{self, Edge, EdgeKind, Function};
+use analysis::signature::{RegSet, Signatures};
+use disasm::Disassembly;
+use errors::*;
+use memory::{Addr, Value};
+use op_code::{Flow, OpCode};
+
+/// Stands in for the single exit of a function when computing post-dominators;
+/// no instruction can live there
+const EXIT: u16 = 0xffff;
+/// Literals at least this large read better as a subtraction (arithmetic is mod 32768)
+const NEGATIVE: u16 = 16384;
+
+enum Line {
+    Label(u16, usize),
+    Text(String, usize),
+}
+
+struct Loop {
+    header: u16,
+    exit: Option<u16>,
+}
+
+/// Lifts a function to structured pseudo-code; if/else and loops are recovered
+/// from the control-flow graph, falling back to `goto` where that fails
+pub fn decompile<W: Write>(disasm: &Disassembly,
+                           sigs: &Signatures,
+                           f: &Function,
+                           w: &mut W)
+                           -> Result<()> {
+    let mut d = Decompiler::new(disasm, sigs, f);
+    let sig = sigs.get(Addr::from(f.entry)).cloned().unwrap_or_default();
+    let args = sig.args.regs().iter().map(|r| format!("r{}", r)).collect::<Vec<_>>();
+    let ret = if sig.returns.is_empty() {
+        String::new()
+    } else {
+        format!(" -> {}", regs_text(sig.returns))
+    };
+    writeln!(w, "fn {}({}){} {{", name(disasm, f.entry), args.join(", "), ret)?;
+    if !sig.saved.is_empty() {
+        writeln!(w, "    // saves {}", sig.saved)?;
+    }
+    d.seq(f.entry, None, 1);
+    for line in &d.lines {
+        match *line {
+            Line::Label(a, depth) if d.gotos.contains(&a) => {
+                writeln!(w, "{}{}:", "    ".repeat(depth - 1), label(disasm, a))?
+            }
+            Line::Label(..) => {}
+            Line::Text(ref t, depth) => writeln!(w, "{}{}", "    ".repeat(depth), t)?,
+        }
+    }
+    writeln!(w, "}}")?;
+    Ok(())
+}
+
+struct Decompiler<'a> {
+    disasm: &'a Disassembly,
+    sigs: &'a Signatures,
+    f: &'a Function,
+    /// push/pop pairs saving a register across a call, rendered as assignments
+    saves: BTreeMap<u16, String>,
+    doms: BTreeMap<u16, BTreeSet<u16>>,
+    pdoms: BTreeMap<u16, BTreeSet<u16>>,
+    loops: Vec<Loop>,
+    emitted: BTreeSet<u16>,
+    gotos: BTreeSet<u16>,
+    lines: Vec<Line>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(disasm: &'a Disassembly, sigs: &'a Signatures, f: &'a Function) -> Decompiler<'a> {
+        let mut d = Decompiler {
+            disasm: disasm,
+            sigs: sigs,
+            f: f,
+            saves: BTreeMap::new(),
+            doms: BTreeMap::new(),
+            pdoms: BTreeMap::new(),
+            loops: Vec::new(),
+            emitted: BTreeSet::new(),
+            gotos: BTreeSet::new(),
+            lines: Vec::new(),
+        };
+        d.find_saves();
+        d.doms = dominators(f);
+        d.pdoms = post_dominators(f);
+        d
+    }
+
+    fn op(&self, a: u16) -> Option<&'a OpCode> {
+        cfg::instr_at(self.disasm, a).map(|(op, _)| op)
+    }
+
+    fn is_frame(&self, a: u16) -> bool {
+        self.sigs
+            .frame(Addr::from(self.f.entry))
+            .map(|fr| fr.prologue.contains(&a) || fr.epilogue.contains(&a))
+            .unwrap_or(false)
+    }
+
+    /// A register pushed and popped back within a block with a call in between is
+    /// being saved across the call, rather than passed on the stack
+    fn find_saves(&mut self) {
+        for b in self.f.blocks.values() {
+            let mut pushed: Vec<(u16, Option<usize>, bool)> = Vec::new();
+            for &a in &b.instrs {
+                if self.is_frame(a) {
+                    continue;
+                }
+                match self.op(a) {
+                    Some(&OpCode::Push { val }) => {
+                        let reg = match val {
+                            Value::FromRegister(r) => Some(usize::from(r)),
+                            Value::Literal(_) => None,
+                        };
+                        pushed.push((a, reg, false));
+                    }
+                    Some(&OpCode::Pop { reg }) => {
+                        let r = usize::from(reg);
+                        match pushed.pop() {
+                            Some((p, Some(pr), true)) if pr == r => {
+                                self.saves.insert(p, format!("saved_r{} = r{};", r, r));
+                                self.saves.insert(a, format!("r{} = saved_r{};", r, r));
+                            }
+                            _ => {}
+                        }
+                    }
+                    Some(op) if op.instruction().flow == Flow::Call => {
+                        for p in &mut pushed {
+                            p.2 = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, text: String, depth: usize) {
+        self.lines.push(Line::Text(text, depth));
+    }
+
+    /// Emits blocks from `start` until reaching `stop`
+    fn seq(&mut self, start: u16, stop: Option<u16>, depth: usize) {
+        let mut cur = Some(start);
+        while let Some(b) = cur {
+            if Some(b) == stop {
+                break;
+            }
+            let (header, exit) = match self.loops.last() {
+                Some(l) => (Some(l.header), l.exit),
+                None => (None, None),
+            };
+            if Some(b) == header && self.emitted.contains(&b) {
+                self.push("continue;".to_owned(), depth);
+                break;
+            }
+            if Some(b) == exit {
+                self.push("break;".to_owned(), depth);
+                break;
+            }
+            if !self.f.blocks.contains_key(&b) {
+                // falling through into another function, or a jump to somewhere
+                // that did not disassemble as an instruction
+                let s = match self.sigs.get(Addr::from(b)) {
+                    Some(_) => self.tail_call(b),
+                    None => format!("goto {:?};", Addr::from(b)),
+                };
+                self.push(s, depth);
+                break;
+            }
+            if self.emitted.contains(&b) {
+                self.gotos.insert(b);
+                self.push(format!("goto {};", label(self.disasm, b)), depth);
+                break;
+            }
+            if self.is_loop_header(b) && !self.loops.iter().any(|l| l.header == b) {
+                let exit = self.loop_exit(b);
+                self.loops.push(Loop {
+                    header: b,
+                    exit: exit,
+                });
+                self.push("loop {".to_owned(), depth);
+                self.seq(b, None, depth + 1);
+                self.push("}".to_owned(), depth);
+                self.loops.pop();
+                cur = exit;
+                continue;
+            }
+            cur = self.block(b, depth);
+        }
+    }
+
+    /// Emits a block, returning where control continues afterwards
+    fn block(&mut self, b: u16, depth: usize) -> Option<u16> {
+        self.emitted.insert(b);
+        self.lines.push(Line::Label(b, depth));
+        let block = &self.f.blocks[&b];
+        let last = block.last();
+        for &a in &block.instrs {
+            if a == last {
+                break;
+            }
+            if let Some(s) = self.stmt(a) {
+                self.push(s, depth);
+            }
+        }
+        let op = match self.op(last) {
+            Some(op) => op,
+            None => return None,
+        };
+        let local = |k: EdgeKind| k != EdgeKind::TailCall;
+        match op.instruction().flow {
+            Flow::Branch => {
+                let follow = self.ipdom(b);
+                let fall = last + op.instruction().len();
+                let is_next = |e: &&Edge| {
+                    e.kind == EdgeKind::Next || (e.kind == EdgeKind::TailCall && e.to == fall)
+                };
+                let taken = block.succs.iter().find(|e| !is_next(e)).cloned();
+                let next = block.succs.iter().find(is_next).map(|e| e.to);
+                let (cond, negated) = condition(op);
+                match taken {
+                    Some(t) if !local(t.kind) => {
+                        self.push(format!("if {} {{", cond), depth);
+                        let call = self.tail_call(t.to);
+                        self.push(call, depth + 1);
+                        self.push("}".to_owned(), depth);
+                        next
+                    }
+                    Some(t) if Some(t.to) == follow && next.is_some() => {
+                        self.push(format!("if {} {{", negated), depth);
+                        self.seq(next.unwrap(), follow, depth + 1);
+                        self.push("}".to_owned(), depth);
+                        follow
+                    }
+                    Some(t) => {
+                        self.push(format!("if {} {{", cond), depth);
+                        self.seq(t.to, follow, depth + 1);
+                        match next {
+                            Some(n) if Some(n) != follow => {
+                                self.push("} else {".to_owned(), depth);
+                                self.seq(n, follow, depth + 1);
+                            }
+                            _ => {}
+                        }
+                        self.push("}".to_owned(), depth);
+                        follow
+                    }
+                    // a conditional jump through a register, or to somewhere that did
+                    // not decode; only the fall through is known
+                    None => {
+                        let target = op.operands()[1];
+                        self.push(format!("if {} {{", cond), depth);
+                        self.push(goto(target), depth + 1);
+                        self.push("}".to_owned(), depth);
+                        next
+                    }
+                }
+            }
+            Flow::Jump => {
+                match block.succs.first() {
+                    Some(e) if local(e.kind) => Some(e.to),
+                    Some(e) => {
+                        let call = self.tail_call(e.to);
+                        self.push(call, depth);
+                        None
+                    }
+                    None => {
+                        self.push(goto(op.operands()[0]), depth);
+                        None
+                    }
+                }
+            }
+            _ => {
+                if let Some(s) = self.stmt(last) {
+                    self.push(s, depth);
+                }
+                block.succs.first().map(|e| e.to)
+            }
+        }
+    }
+
+    fn is_loop_header(&self, b: u16) -> bool {
+        self.f.preds(b).iter().any(|p| self.doms.get(p).map(|d| d.contains(&b)).unwrap_or(false))
+    }
+
+    /// Blocks of the natural loop at `header`
+    fn loop_body(&self, header: u16) -> BTreeSet<u16> {
+        let mut body = BTreeSet::new();
+        body.insert(header);
+        let mut work = self.f
+            .preds(header)
+            .into_iter()
+            .filter(|p| self.doms.get(p).map(|d| d.contains(&header)).unwrap_or(false))
+            .collect::<Vec<_>>();
+        while let Some(b) = work.pop() {
+            if body.insert(b) {
+                work.extend(self.f.preds(b));
+            }
+        }
+        body
+    }
+
+    fn loop_exit(&self, header: u16) -> Option<u16> {
+        let body = self.loop_body(header);
+        match self.ipdom(header) {
+            Some(p) if !body.contains(&p) => return Some(p),
+            _ => {}
+        }
+        body.iter()
+            .flat_map(|b| self.f.blocks[b].succs.iter())
+            .filter(|e| e.kind != EdgeKind::TailCall && !body.contains(&e.to))
+            .map(|e| e.to)
+            .min()
+    }
+
+    fn ipdom(&self, b: u16) -> Option<u16> {
+        let mut strict = match self.pdoms.get(&b) {
+            Some(p) => p.clone(),
+            None => return None,
+        };
+        strict.remove(&b);
+        strict.iter()
+            .find(|d| self.pdoms.get(d) == Some(&strict))
+            .cloned()
+            .and_then(|d| if d == EXIT { None } else { Some(d) })
+    }
+
+    fn call_text(&self, target: u16) -> (String, RegSet) {
+        let sig = self.sigs.get(Addr::from(target)).cloned().unwrap_or_default();
+        let args = sig.args.regs().iter().map(|r| format!("r{}", r)).collect::<Vec<_>>();
+        (format!("{}({})", name(self.disasm, target), args.join(", ")), sig.returns)
+    }
+
+    fn tail_call(&self, target: u16) -> String {
+        format!("return {};", self.call_text(target).0)
+    }
+
+    fn stmt(&self, a: u16) -> Option<String> {
+        if self.is_frame(a) {
+            return None;
+        }
+        if let Some(s) = self.saves.get(&a) {
+            return Some(s.clone());
+        }
+        let op = match self.op(a) {
+            Some(op) => op,
+            None => return None,
+        };
+        Some(match *op {
+            OpCode::Halt {} => "halt();".to_owned(),
+            OpCode::Set { reg, val } => format!("{} = {};", reg, value(val)),
+            OpCode::Push { val } => format!("push({});", value(val)),
+            OpCode::Pop { reg } => format!("{} = pop();", reg),
+            OpCode::Eq { reg, val1, val2 } => format!("{} = {} == {};", reg, value(val1), value(val2)),
+            OpCode::Gt { reg, val1, val2 } => format!("{} = {} > {};", reg, value(val1), value(val2)),
+            OpCode::Add { reg, val1, val2 } => {
+                match val2 {
+                    Value::Literal(l) if l >= NEGATIVE => {
+                        format!("{} = {} - {};", reg, value(val1), 32768 - l as u32)
+                    }
+                    _ => format!("{} = {} + {};", reg, value(val1), value(val2)),
+                }
+            }
+            OpCode::Mult { reg, val1, val2 } => format!("{} = {} * {};", reg, value(val1), value(val2)),
+            OpCode::Mod { reg, val1, val2 } => format!("{} = {} % {};", reg, value(val1), value(val2)),
+            OpCode::And { reg, val1, val2 } => format!("{} = {} & {};", reg, value(val1), value(val2)),
+            OpCode::Or { reg, val1, val2 } => format!("{} = {} | {};", reg, value(val1), value(val2)),
+            OpCode::Not { reg, val } => format!("{} = ~{};", reg, value(val)),
+            OpCode::Rmem { reg, addr } => format!("{} = mem[{}];", reg, value(addr)),
+            OpCode::Wmem { addr, val } => format!("mem[{}] = {};", value(addr), value(val)),
+            OpCode::Call { addr: Value::Literal(t) } => {
+                let (call, returns) = self.call_text(t);
+                if returns.is_empty() {
+                    format!("{};", call)
+                } else {
+                    format!("{} = {};", regs_text(returns), call)
+                }
+            }
+            OpCode::Call { addr } => format!("(*{})();", value(addr)),
+            OpCode::Ret {} => {
+                let returns = self.sigs
+                    .get(Addr::from(self.f.entry))
+                    .map(|s| s.returns)
+                    .unwrap_or_default();
+                if returns.is_empty() {
+                    "return;".to_owned()
+                } else {
+                    format!("return {};", regs_text(returns))
+                }
+            }
+            OpCode::Out { c: Value::Literal(c) } if c >= 32 && c < 127 && c != '\'' as u16 => {
+                format!("out('{}');", c as u8 as char)
+            }
+            OpCode::Out { c: Value::Literal(10) } => "out('\\n');".to_owned(),
+            OpCode::Out { c } => format!("out({});", value(c)),
+            OpCode::In { reg } => format!("{} = in();", reg),
+            OpCode::Noop {} => return None,
+            // jumps only appear as block terminators
+            OpCode::Jmp { .. } | OpCode::Jt { .. } | OpCode::Jf { .. } => return None,
+        })
+    }
+}
+
+/// The condition under which a conditional jump is taken, and its negation
+fn condition(op: &OpCode) -> (String, String) {
+    let c = value(op.operands()[0]);
+    match *op {
+        OpCode::Jt { .. } => (format!("{} != 0", c), format!("{} == 0", c)),
+        _ => (format!("{} == 0", c), format!("{} != 0", c)),
+    }
+}
+
+fn value(v: Value) -> String {
+    match v {
+        Value::Literal(l) => format!("{}", l),
+        Value::FromRegister(r) => format!("{}", r),
+    }
+}
+
+/// A jump to a destination with no block
+fn goto(dest: Value) -> String {
+    match dest {
+        Value::Literal(a) => format!("goto {:?};", Addr::from(a)),
+        Value::FromRegister(r) => format!("goto *{};", r),
+    }
+}
+
+fn regs_text(regs: RegSet) -> String {
+    if regs.regs().len() == 1 {
+        format!("{}", regs)
+    } else {
+        format!("({})", regs)
+    }
+}
+
+fn name(disasm: &Disassembly, f: u16) -> String {
+    disasm.label(Addr::from(f)).unwrap_or_else(|| format!("sub_{:04x}", f))
+}
+
+fn label(disasm: &Disassembly, a: u16) -> String {
+    disasm.label(Addr::from(a)).unwrap_or_else(|| format!("loc_{:04x}", a))
+}
+
+fn dominators(f: &Function) -> BTreeMap<u16, BTreeSet<u16>> {
+    let all = f.blocks.keys().cloned().collect::<BTreeSet<_>>();
+    let mut doms = f.blocks.keys().map(|&b| (b, all.clone())).collect::<BTreeMap<_, _>>();
+    doms.insert(f.entry, Some(f.entry).into_iter().collect());
+    let preds = f.blocks.keys().map(|&b| (b, f.preds(b))).collect::<BTreeMap<_, _>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in f.blocks.keys().filter(|&&b| b != f.entry) {
+            let mut d = intersect_all(preds[&b].iter().map(|p| &doms[p])).unwrap_or_default();
+            d.insert(b);
+            if d != doms[&b] {
+                doms.insert(b, d);
+                changed = true;
+            }
+        }
+    }
+    doms
+}
+
+/// Post-dominators, treating every block without a successor, or jumping to an
+/// address with no block, as leading to `EXIT`
+fn post_dominators(f: &Function) -> BTreeMap<u16, BTreeSet<u16>> {
+    let mut all = f.blocks.keys().cloned().collect::<BTreeSet<_>>();
+    all.insert(EXIT);
+    let succs = f.blocks
+        .values()
+        .map(|b| {
+            let mut s = b.succs
+                .iter()
+                .filter(|e| e.kind != EdgeKind::TailCall)
+                .map(|e| if f.blocks.contains_key(&e.to) { e.to } else { EXIT })
+                .collect::<Vec<_>>();
+            if s.is_empty() {
+                s.push(EXIT);
+            }
+            (b.start, s)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut pdoms = f.blocks.keys().map(|&b| (b, all.clone())).collect::<BTreeMap<_, _>>();
+    pdoms.insert(EXIT, Some(EXIT).into_iter().collect());
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in f.blocks.keys().rev() {
+            let mut p = intersect_all(succs[&b].iter().map(|s| &pdoms[s])).unwrap_or_default();
+            p.insert(b);
+            if p != pdoms[&b] {
+                pdoms.insert(b, p);
+                changed = true;
+            }
+        }
+    }
+    pdoms
+}
+
+fn intersect_all<'s, I>(mut sets: I) -> Option<BTreeSet<u16>>
+    where I: Iterator<Item = &'s BTreeSet<u16>>
+{
+    let first = match sets.next() {
+        Some(s) => s.clone(),
+        None => return None,
+    };
+    Some(sets.fold(first, |acc, s| acc.intersection(s).cloned().collect()))
+}
@@ -0,0 +1,6 @@
+pub mod call_graph;
+pub mod cfg;
+pub mod decompile;
+pub mod signature;
+pub mod strings;
+pub mod xref;
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use analysis::cfg::{self, Block, Cfg, EdgeKind, Function};
+use disasm::Disassembly;
+use memory::{Addr, Value};
+use op_code::{Flow, OpCode, Role};
+
+/// Rounds of inference over all functions before giving up on a fixed point
+const MAX_ROUNDS: usize = 16;
+
+/// A set of registers, one bit per register
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegSet(u8);
+
+impl RegSet {
+    pub fn empty() -> RegSet {
+        RegSet(0)
+    }
+
+    pub fn all() -> RegSet {
+        RegSet(0xff)
+    }
+
+    pub fn contains(&self, r: usize) -> bool {
+        self.0 & (1 << r) != 0
+    }
+
+    pub fn insert(&mut self, r: usize) {
+        self.0 |= 1 << r;
+    }
+
+    pub fn union(&self, other: RegSet) -> RegSet {
+        RegSet(self.0 | other.0)
+    }
+
+    pub fn intersect(&self, other: RegSet) -> RegSet {
+        RegSet(self.0 & other.0)
+    }
+
+    pub fn minus(&self, other: RegSet) -> RegSet {
+        RegSet(self.0 & !other.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn regs(&self) -> Vec<usize> {
+        (0..8).filter(|&r| self.contains(r)).collect()
+    }
+}
+
+impl fmt::Display for RegSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regs = self.regs().iter().map(|r| format!("r{}", r)).collect::<Vec<_>>();
+        write!(f, "{}", regs.join(", "))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Signature {
+    /// registers read before being written
+    pub args: RegSet,
+    /// registers written and still live in a caller after the `ret`
+    pub returns: RegSet,
+    /// registers pushed on entry and popped before every `ret`
+    pub saved: RegSet,
+    /// registers the function (or anything it calls) may overwrite
+    pub clobbered: RegSet,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({})", self.args)?;
+        if !self.returns.is_empty() {
+            write!(f, " -> {}", self.returns)?;
+        }
+        Ok(())
+    }
+}
+
+impl Signature {
+    /// The signature followed by the registers saved and clobbered, e.g.
+    /// `(r0, r1) -> r0; saves r2; clobbers r1`
+    pub fn summary(&self) -> String {
+        let mut s = format!("{}", self);
+        if !self.saved.is_empty() {
+            s.push_str(&format!("; saves {}", self.saved));
+        }
+        let clobbered = self.clobbered.minus(self.returns);
+        if !clobbered.is_empty() {
+            s.push_str(&format!("; clobbers {}", clobbered));
+        }
+        s
+    }
+}
+
+/// The pushes and pops saving and restoring callee-saved registers
+#[derive(Debug, Default)]
+pub struct Frame {
+    pub saved: RegSet,
+    /// addresses of the saving `push` instructions
+    pub prologue: BTreeSet<u16>,
+    /// addresses of the restoring `pop` instructions
+    pub epilogue: BTreeSet<u16>,
+}
+
+impl Frame {
+    /// Pushes at the start of the entry block matched, in reverse, by the pops
+    /// immediately before every `ret`
+    pub fn new(disasm: &Disassembly, f: &Function) -> Frame {
+        let pushes = match f.blocks.get(&f.entry) {
+            Some(b) => {
+                b.instrs
+                    .iter()
+                    .map(|&a| (a, cfg::instr_at(disasm, a).map(|(op, _)| op)))
+                    .take_while(|&(_, op)| match op {
+                        Some(&OpCode::Push { val: Value::FromRegister(_) }) => true,
+                        _ => false,
+                    })
+                    .map(|(a, op)| (a, pushed_reg(op.unwrap())))
+                    .collect::<Vec<_>>()
+            }
+            None => Vec::new(),
+        };
+        let mut epilogues = Vec::new();
+        for b in f.blocks.values() {
+            match cfg::instr_at(disasm, b.last()) {
+                Some((&OpCode::Ret {}, _)) => {}
+                _ => continue,
+            }
+            let pops = b.instrs[..(b.instrs.len() - 1)]
+                .iter()
+                .rev()
+                .map(|&a| (a, cfg::instr_at(disasm, a).map(|(op, _)| op)))
+                .take_while(|&(_, op)| match op {
+                    Some(&OpCode::Pop { .. }) => true,
+                    _ => false,
+                })
+                .map(|(a, op)| (a, popped_reg(op.unwrap())))
+                .collect::<Vec<_>>();
+            epilogues.push(pops);
+        }
+        if epilogues.is_empty() {
+            return Frame::default();
+        }
+        // pops nearest the `ret` restore the earliest pushes
+        let mut frame = Frame::default();
+        let mut n = 0;
+        for (i, &(a, r)) in pushes.iter().enumerate() {
+            if !epilogues.iter().all(|e| e.get(i).map(|&(_, p)| p == r).unwrap_or(false)) {
+                break;
+            }
+            frame.saved.insert(r);
+            frame.prologue.insert(a);
+            n += 1;
+        }
+        for e in &epilogues {
+            frame.epilogue.extend(e[..n].iter().map(|&(a, _)| a));
+        }
+        frame
+    }
+}
+
+fn pushed_reg(op: &OpCode) -> usize {
+    match *op {
+        OpCode::Push { val: Value::FromRegister(r) } => usize::from(r),
+        _ => unreachable!(),
+    }
+}
+
+fn popped_reg(op: &OpCode) -> usize {
+    match *op {
+        OpCode::Pop { reg } => usize::from(reg),
+        _ => unreachable!(),
+    }
+}
+
+/// Registers read and written by an instruction's operands
+pub fn uses(op: &OpCode) -> (RegSet, RegSet) {
+    let (mut reads, mut writes) = (RegSet::empty(), RegSet::empty());
+    for (role, v) in op.roles() {
+        if let Value::FromRegister(r) = v {
+            match role {
+                Role::Write => writes.insert(usize::from(r)),
+                // a register holding a store address is read, not written
+                Role::Read | Role::Load | Role::Store | Role::Dest => reads.insert(usize::from(r)),
+            }
+        }
+    }
+    (reads, writes)
+}
+
+/// Signatures of every function in a control-flow graph
+pub struct Signatures {
+    sigs: BTreeMap<u16, Signature>,
+    frames: BTreeMap<u16, Frame>,
+}
+
+impl Signatures {
+    pub fn new(disasm: &Disassembly, cfg: &Cfg) -> Signatures {
+        let frames = cfg.functions()
+            .map(|f| (f.entry, Frame::new(disasm, f)))
+            .collect::<BTreeMap<_, _>>();
+        let mut sigs = cfg.functions()
+            .map(|f| {
+                (f.entry,
+                 Signature {
+                     saved: frames[&f.entry].saved,
+                     ..Signature::default()
+                 })
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        // arguments and clobbers depend on those of the callees
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for f in cfg.functions() {
+                let (args, clobbered) = scan(disasm, f, &frames[&f.entry], &sigs);
+                let sig = sigs.get_mut(&f.entry).unwrap();
+                if sig.args != args || sig.clobbered != clobbered {
+                    sig.args = args;
+                    sig.clobbered = clobbered;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // a clobbered register is returned if a caller reads it after the call
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for f in cfg.functions() {
+                for &(site, target) in &f.calls {
+                    let callee = match target.and_then(|t| sigs.get(&t)) {
+                        Some(c) => c.clone(),
+                        None => continue,
+                    };
+                    let live = live_after(disasm, f, site, &sigs);
+                    let returns = callee.returns.union(callee.clobbered.intersect(live));
+                    if returns != callee.returns {
+                        sigs.get_mut(&target.unwrap()).unwrap().returns = returns;
+                        changed = true;
+                    }
+                }
+                // a tail call returns to the caller's callers, so what either
+                // returns the other does too
+                for t in tail_calls(f) {
+                    let (caller, callee) = match (sigs.get(&f.entry), sigs.get(&t)) {
+                        (Some(c), Some(t)) => (c.clone(), t.clone()),
+                        _ => continue,
+                    };
+                    let returns = callee.returns.union(callee.clobbered.intersect(caller.returns));
+                    if returns != callee.returns {
+                        sigs.get_mut(&t).unwrap().returns = returns;
+                        changed = true;
+                    }
+                    let returns = caller.returns.union(returns);
+                    if returns != caller.returns {
+                        sigs.get_mut(&f.entry).unwrap().returns = returns;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        Signatures {
+            sigs: sigs,
+            frames: frames,
+        }
+    }
+
+    pub fn get(&self, f: Addr) -> Option<&Signature> {
+        self.sigs.get(&u16::from(f))
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = (Addr, &'a Signature)> + 'a> {
+        Box::new(self.sigs.iter().map(|(&f, s)| (Addr::from(f), s)))
+    }
+
+    pub fn frame(&self, f: Addr) -> Option<&Frame> {
+        self.frames.get(&u16::from(f))
+    }
+
+    /// Registers read and written by an instruction, taking a call's effects from
+    /// the callee's signature
+    pub fn effects(&self, op: &OpCode) -> (RegSet, RegSet) {
+        effects(op, &self.sigs)
+    }
+}
+
+fn effects(op: &OpCode, sigs: &BTreeMap<u16, Signature>) -> (RegSet, RegSet) {
+    let (reads, writes) = uses(op);
+    if op.instruction().flow != Flow::Call {
+        return (reads, writes);
+    }
+    match op.dest().and_then(|t| sigs.get(&t)) {
+        Some(sig) => (reads.union(sig.args), writes.union(sig.clobbered)),
+        None => (reads, writes),
+    }
+}
+
+/// Arguments (read before written on some path) and clobbered registers
+fn scan(disasm: &Disassembly,
+        f: &Function,
+        frame: &Frame,
+        sigs: &BTreeMap<u16, Signature>)
+        -> (RegSet, RegSet) {
+    // registers written on every path to the start of each block
+    let mut defined = f.blocks.keys().map(|&b| (b, RegSet::all())).collect::<BTreeMap<_, _>>();
+    defined.insert(f.entry, RegSet::empty());
+    let mut args = RegSet::empty();
+    let mut clobbered = RegSet::empty();
+    let mut work = vec![f.entry];
+    let mut visited = BTreeSet::new();
+    while let Some(b) = work.pop() {
+        let block = &f.blocks[&b];
+        let mut def = defined[&b];
+        for &a in &block.instrs {
+            if frame.prologue.contains(&a) || frame.epilogue.contains(&a) {
+                continue;
+            }
+            let (reads, writes) = match cfg::instr_at(disasm, a) {
+                Some((op, _)) => effects(op, sigs),
+                None => continue,
+            };
+            args = args.union(reads.minus(def));
+            def = def.union(writes);
+            clobbered = clobbered.union(writes);
+        }
+        // a jump to another function's entry calls it, returning from this one
+        for t in tail_targets(block) {
+            if let Some(sig) = sigs.get(&t) {
+                args = args.union(sig.args.minus(def));
+                clobbered = clobbered.union(sig.clobbered);
+            }
+        }
+        let first = visited.insert(b);
+        for e in &block.succs {
+            if let Some(d) = defined.get_mut(&e.to) {
+                let meet = d.intersect(def);
+                if first || meet != *d {
+                    *d = meet;
+                    work.push(e.to);
+                }
+            }
+        }
+    }
+    (args, clobbered.minus(frame.saved))
+}
+
+/// Registers read after the call at `site` before being overwritten
+fn live_after(disasm: &Disassembly,
+              f: &Function,
+              site: u16,
+              sigs: &BTreeMap<u16, Signature>)
+              -> RegSet {
+    let returns = sigs.get(&f.entry).map(|s| s.returns).unwrap_or_default();
+    let block = match f.blocks.values().find(|b| b.instrs.contains(&site)) {
+        Some(b) => b,
+        None => return RegSet::empty(),
+    };
+    let mut live = RegSet::empty();
+    for r in 0..8 {
+        let mut work = vec![(block.start, block.instrs.iter().position(|&a| a == site).unwrap() + 1)];
+        let mut seen = BTreeSet::new();
+        'paths: while let Some((b, from)) = work.pop() {
+            let block = &f.blocks[&b];
+            for &a in &block.instrs[from..] {
+                let op = match cfg::instr_at(disasm, a) {
+                    Some((op, _)) => op,
+                    None => continue 'paths,
+                };
+                let (reads, writes) = effects(op, sigs);
+                if reads.contains(r) || (op.instruction().flow == Flow::Return && returns.contains(r)) {
+                    live.insert(r);
+                    break 'paths;
+                }
+                if writes.contains(r) {
+                    continue 'paths;
+                }
+            }
+            for t in tail_targets(block) {
+                match sigs.get(&t) {
+                    Some(sig) if sig.args.contains(r) ||
+                                 (returns.contains(r) && !sig.clobbered.contains(r)) => {
+                        live.insert(r);
+                        break 'paths;
+                    }
+                    _ => {}
+                }
+            }
+            for e in block.succs.iter().filter(|e| e.kind != EdgeKind::TailCall) {
+                if seen.insert(e.to) {
+                    work.push((e.to, 0));
+                }
+            }
+        }
+    }
+    live
+}
+
+/// Entries of the functions a block ends by jumping to
+fn tail_targets<'a>(block: &'a Block) -> Box<Iterator<Item = u16> + 'a> {
+    Box::new(block.succs.iter().filter(|e| e.kind == EdgeKind::TailCall).map(|e| e.to))
+}
+
+fn tail_calls<'a>(f: &'a Function) -> Box<Iterator<Item = u16> + 'a> {
+    Box::new(f.blocks.values().flat_map(tail_targets))
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use try_from::TryFrom;
+
+use analysis::cfg::{self, Cfg};
+use analysis::signature::Signatures;
+use disasm::{self, Disassembly};
+use machine::{Inspectable, Machine, OpResult};
+use memory::{Addr, Register, Value};
+use op_code::OpCode;
+
+/// Instructions a single decode may run before it is abandoned
+const MAX_STEPS: usize = 1_000_000;
+
+/// A string printed by a call to a print routine, recovered by running it
+pub struct Decrypted {
+    pub routine: u16,
+    /// the routine's arguments at the call, as (register, value)
+    pub args: Vec<(usize, u16)>,
+    pub text: String,
+    /// call instructions printing this string
+    pub refs: BTreeSet<u16>,
+    /// whether the routine asked for input, halted, faulted or ran too long
+    /// before returning
+    pub truncated: bool,
+}
+
+impl Decrypted {
+    /// The first argument, which for the usual print routines is the address
+    /// of the encoded string
+    pub fn table(&self) -> Option<u16> {
+        self.args.first().map(|&(_, v)| v)
+    }
+}
+
+impl fmt::Display for Decrypted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.table() {
+            Some(t) => write!(f, "{:?}", Addr::from(t))?,
+            None => write!(f, "{:>6}", "-")?,
+        }
+        write!(f, " \"{}\"", disasm::escape(&self.text))?;
+        if self.truncated {
+            write!(f, " (truncated)")?;
+        }
+        let refs = self.refs.iter().map(|&r| format!("{:?}", Addr::from(r))).collect::<Vec<_>>();
+        write!(f, " <- sub_{:04x} at {}", self.routine, refs.join(", "))
+    }
+}
+
+/// Functions executing an `out` themselves, the likely print routines
+pub fn printers(disasm: &Disassembly, cfg: &Cfg) -> Vec<Addr> {
+    cfg.functions()
+        .filter(|f| {
+            f.blocks.values().any(|b| {
+                b.instrs.iter().any(|&a| match cfg::instr_at(disasm, a) {
+                    Some((&OpCode::Out { .. }, _)) => true,
+                    _ => false,
+                })
+            })
+        })
+        .map(|f| Addr::from(f.entry))
+        .collect()
+}
+
+/// Runs each of `routines` in a fork of `base` for every distinct set of
+/// constant arguments it is called with, collecting what it prints
+pub fn decrypt(base: &Inspectable,
+               disasm: &Disassembly,
+               cfg: &Cfg,
+               sigs: &Signatures,
+               routines: &[Addr])
+               -> Vec<Decrypted> {
+    let routines = routines.iter().map(|&r| u16::from(r)).collect::<BTreeSet<_>>();
+    let mut calls = BTreeMap::new();
+    for (site, routine, args) in call_sites(disasm, cfg, sigs, &routines) {
+        calls.entry((routine, args)).or_insert_with(BTreeSet::new).insert(site);
+    }
+    calls.into_iter()
+        .map(|((routine, args), refs)| {
+            let (text, truncated) = run(base.fork(), routine, &args);
+            Decrypted {
+                routine: routine,
+                args: args,
+                text: text,
+                refs: refs,
+                truncated: truncated,
+            }
+        })
+        .collect()
+}
+
+/// Calls to `routines` whose arguments are all constant within the calling
+/// block, as (site, routine, arguments)
+fn call_sites(disasm: &Disassembly,
+              cfg: &Cfg,
+              sigs: &Signatures,
+              routines: &BTreeSet<u16>)
+              -> BTreeSet<(u16, u16, Vec<(usize, u16)>)> {
+    let mut sites = BTreeSet::new();
+    for f in cfg.functions() {
+        for b in f.blocks.values() {
+            let mut known = [None; 8];
+            for &a in &b.instrs {
+                let op = match cfg::instr_at(disasm, a) {
+                    Some((op, _)) => op,
+                    None => break,
+                };
+                if let Some(t) = op.dest() {
+                    if let OpCode::Call { .. } = *op {
+                        if routines.contains(&t) {
+                            let regs = sigs.get(Addr::from(t))
+                                .map(|s| s.args.regs())
+                                .unwrap_or_else(Vec::new);
+                            let args = regs.iter()
+                                .map(|&r| known[r].map(|v| (r, v)))
+                                .collect::<Option<Vec<_>>>();
+                            if let Some(args) = args {
+                                sites.insert((a, t, args));
+                            }
+                        }
+                    }
+                }
+                propagate(op, sigs, &mut known);
+            }
+        }
+    }
+    sites
+}
+
+/// Updates the registers known to hold constants across `op`
+fn propagate(op: &OpCode, sigs: &Signatures, known: &mut [Option<u16>; 8]) {
+    let result = {
+        let k = |v: &Value| match *v {
+            Value::Literal(n) => Some(n),
+            Value::FromRegister(r) => known[usize::from(r)],
+        };
+        match *op {
+            OpCode::Set { ref val, .. } => k(val),
+            OpCode::Add { ref val1, ref val2, .. } => {
+                k(val1).and_then(|a| k(val2).map(|b| (a + b) % 32768))
+            }
+            OpCode::Mult { ref val1, ref val2, .. } => {
+                k(val1).and_then(|a| k(val2).map(|b| ((a as u32 * b as u32) % 32768) as u16))
+            }
+            OpCode::Mod { ref val1, ref val2, .. } => {
+                k(val1).and_then(|a| k(val2).and_then(|b| a.checked_rem(b)))
+            }
+            OpCode::And { ref val1, ref val2, .. } => k(val1).and_then(|a| k(val2).map(|b| a & b)),
+            OpCode::Or { ref val1, ref val2, .. } => k(val1).and_then(|a| k(val2).map(|b| a | b)),
+            OpCode::Not { ref val, .. } => k(val).map(|a| !a & 0x7fff),
+            OpCode::Eq { ref val1, ref val2, .. } => {
+                k(val1).and_then(|a| k(val2).map(|b| (a == b) as u16))
+            }
+            OpCode::Gt { ref val1, ref val2, .. } => {
+                k(val1).and_then(|a| k(val2).map(|b| (a > b) as u16))
+            }
+            _ => None,
+        }
+    };
+    let (_, writes) = sigs.effects(op);
+    for r in writes.regs() {
+        known[r] = result;
+    }
+}
+
+/// Calls `routine` with `args` and collects its output until it returns
+fn run(mut m: Machine, routine: u16, args: &[(usize, u16)]) -> (String, bool) {
+    for &(r, v) in args {
+        if let Ok(reg) = Register::try_from(r as u8) {
+            m.write_reg(reg, Value::Literal(v));
+        }
+    }
+    m.enter(Addr::from(routine));
+    let depth = m.stack().len();
+    let mut text = String::new();
+    for _ in 0..MAX_STEPS {
+        m = match m.step() {
+            Ok(OpResult::Continue(m)) => m,
+            Ok(OpResult::Output(c, m)) => {
+                text.push(c);
+                m
+            }
+            Ok(OpResult::Input(_)) |
+            Ok(OpResult::Halted(_)) |
+            Err(_) => return (text, true),
+        };
+        // a `ret` (or an unbalanced `pop`) out of the routine
+        if m.stack().len() < depth {
+            return (text, false);
+        }
+    }
+    (text, true)
+}
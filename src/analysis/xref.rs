@@ -0,0 +1,131 @@
+use std::fmt;
+use std::io::Write;
+
+use analysis::cfg::Cfg;
+use disasm::{Disassembly, Item};
+use errors::*;
+use memory::{Addr, Register, Target, Value};
+use op_code::{Flow, OpAccess, OpCode, Role};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Jump,
+    Call,
+    Read,
+    Write,
+    /// the register is both an operand and the result, e.g. `add r1 r1 1`
+    ReadWrite,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Access::Jump => "jump",
+            Access::Call => "call",
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::ReadWrite => "rw",
+        };
+        f.pad(s)
+    }
+}
+
+/// An instruction referring to an address or register
+pub struct Xref {
+    pub from: Addr,
+    pub access: Access,
+}
+
+/// Instructions jumping to, calling, reading (`rmem`) or writing (`wmem`) `addr`
+/// through a literal operand
+pub fn to_addr(disasm: &Disassembly, addr: Addr) -> Vec<Xref> {
+    let a = u16::from(addr);
+    let mut xrefs = Vec::new();
+    for (from, op) in instrs(disasm) {
+        for (role, v) in op.roles() {
+            match v {
+                Value::Literal(l) if l == a => {}
+                _ => continue,
+            }
+            let access = match role {
+                Role::Dest if op.instruction().flow == Flow::Call => Access::Call,
+                Role::Dest => Access::Jump,
+                Role::Load => Access::Read,
+                Role::Store => Access::Write,
+                Role::Read | Role::Write => continue,
+            };
+            xrefs.push(Xref {
+                from: from,
+                access: access,
+            });
+        }
+    }
+    xrefs
+}
+
+/// Instructions reading or writing `reg`
+pub fn to_register(disasm: &Disassembly, reg: Register) -> Vec<Xref> {
+    let tgt = Target::Reg(reg);
+    instrs(disasm)
+        .filter_map(|(from, op)| {
+            let access = match (op.reads(&tgt), op.writes(&tgt)) {
+                (true, true) => Access::ReadWrite,
+                (true, false) => Access::Read,
+                (false, true) => Access::Write,
+                (false, false) => return None,
+            };
+            Some(Xref {
+                from: from,
+                access: access,
+            })
+        })
+        .collect()
+}
+
+/// Either kind of cross-reference, depending on `tgt`
+pub fn to_target(disasm: &Disassembly, tgt: Target) -> Vec<Xref> {
+    match tgt {
+        Target::Mem(addr) => to_addr(disasm, addr),
+        Target::Reg(reg) => to_register(disasm, reg),
+    }
+}
+
+/// Lists each reference with the function containing it and the instruction
+pub fn write_xrefs<W: Write>(disasm: &Disassembly,
+                             cfg: &Cfg,
+                             xrefs: &[Xref],
+                             w: &mut W)
+                             -> Result<()> {
+    for x in xrefs {
+        let func = match cfg.function_containing(x.from) {
+            Some(f) => {
+                let name = disasm.label(Addr::from(f.entry))
+                    .unwrap_or_else(|| format!("sub_{:04x}", f.entry));
+                let from = u16::from(x.from);
+                if from >= f.entry {
+                    match from - f.entry {
+                        0 => name,
+                        offset => format!("{}+0x{:x}", name, offset),
+                    }
+                } else {
+                    // blocks jumped to from the function can lie below its entry
+                    format!("{}-0x{:x}", name, f.entry - from)
+                }
+            }
+            None => String::new(),
+        };
+        let text = match disasm.item(x.from) {
+            Some(&Item::Instr(ref op, _)) => disasm.instr_text(op),
+            _ => String::new(),
+        };
+        writeln!(w, "{:?} {:<20} {:<5} {}", x.from, func, x.access, text)?;
+    }
+    Ok(())
+}
+
+fn instrs<'a>(disasm: &'a Disassembly) -> Box<Iterator<Item = (Addr, &'a OpCode)> + 'a> {
+    Box::new(disasm.items().filter_map(|(&a, item)| match *item {
+        Item::Instr(ref op, _) => Some((Addr::from(a), op)),
+        _ => None,
+    }))
+}
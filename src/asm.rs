@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use errors::*;
+use memory::{Addr, Register, Value};
+use op_code::Instruction;
+
+/// Highest address an `.org` may move to
+const MAX_ADDR: u16 = 32767;
+
+/// Largest number a value operand may hold; the words above it name registers
+const MAX_LITERAL: u16 = 32767;
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Reg(Register),
+    Num(u16),
+    Label(String),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Reg(r) => write!(f, "r{}", usize::from(r)),
+            Operand::Num(n) => write!(f, "{}", n),
+            Operand::Label(ref l) => write!(f, "{}", l),
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Operand> {
+        if s.len() == 2 && s.starts_with("r") {
+            if let Ok(r) = Register::from_str(&s[1..]) {
+                return Ok(Operand::Reg(r));
+            }
+        }
+        // `<n>` is how OpCode displays literals
+        let s = if s.starts_with("<") && s.ends_with(">") {
+            &s[1..(s.len() - 1)]
+        } else {
+            s
+        };
+        if s.starts_with("'") {
+            if s.len() < 3 || !s.ends_with("'") {
+                bail!("invalid character literal {}", s);
+            }
+            let c = unescape(&s[1..(s.len() - 1)])?;
+            let mut chars = c.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Operand::Num(c as u16)),
+                _ => bail!("invalid character literal {}", s),
+            };
+        }
+        if s.chars().next().map(|c| c.is_digit(10)).unwrap_or(false) {
+            let n = if s.starts_with("0x") {
+                u16::from_str_radix(&s[2..], 16)
+            } else if s.starts_with("0b") {
+                u16::from_str_radix(&s[2..], 2)
+            } else {
+                u16::from_str(s)
+            };
+            return n.map(Operand::Num).chain_err(|| format!("invalid number {}", s));
+        }
+        if is_label(s) {
+            Ok(Operand::Label(s.to_owned()))
+        } else {
+            bail!("invalid operand {}", s)
+        }
+    }
+}
+
+/// Whether `s` can be used as a label
+pub fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == '.' => {
+            chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+/// Resolves the escapes `disasm::escape` produces, plus `\0` and `\xNN`
+fn unescape(s: &str) -> Result<String> {
+    let mut u = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            u.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => u.push('\n'),
+            Some('t') => u.push('\t'),
+            Some('0') => u.push('\0'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                let n = u8::from_str_radix(&hex, 16)
+                    .chain_err(|| format!("invalid escape \\x{}", hex))?;
+                u.push(n as char);
+            }
+            Some(c) => u.push(c),
+            None => bail!("dangling escape"),
+        }
+    }
+    Ok(u)
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instr(&'static Instruction, Vec<Operand>),
+    Data(Vec<Operand>),
+    Str(String),
+    Org(u16),
+}
+
+impl Statement {
+    fn len(&self) -> u16 {
+        match *self {
+            Statement::Instr(instr, _) => instr.len(),
+            Statement::Data(ref ops) => ops.len() as u16,
+            Statement::Str(ref s) => s.chars().count() as u16,
+            Statement::Org(_) => 0,
+        }
+    }
+}
+
+/// Strips a trailing `;` comment, ignoring any `;` within quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if q == c => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits operands on whitespace and commas, keeping character literals intact
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut ops = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if quoted {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '\'' {
+                quoted = false;
+            }
+            continue;
+        }
+        if c.is_whitespace() || c == ',' {
+            if let Some(st) = start.take() {
+                ops.push(&s[st..i]);
+            }
+        } else {
+            if start.is_none() {
+                start = Some(i);
+            }
+            quoted = c == '\'';
+        }
+    }
+    if let Some(st) = start {
+        ops.push(&s[st..]);
+    }
+    ops
+}
+
+/// The result of assembling a program: its words, starting at `origin`
+pub struct Assembly {
+    pub origin: Addr,
+    pub words: Vec<u16>,
+    pub labels: HashMap<String, Addr>,
+}
+
+impl Assembly {
+    /// Little-endian image, as the VM loads ROMs
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.words.len() * 2);
+        for w in &self.words {
+            bytes.write_u16::<LittleEndian>(*w)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Assembles a program to be loaded at address 0
+pub fn assemble(src: &str) -> Result<Assembly> {
+    assemble_at(src, Addr::from(0u16))
+}
+
+/// Assembles `src` as if it were loaded at `origin`
+pub fn assemble_at(src: &str, origin: Addr) -> Result<Assembly> {
+    let origin = u16::from(origin);
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = origin as u32;
+    for (n, line) in src.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        let line = if let Some(i) = line.find(':') {
+            let (label, rest) = line.split_at(i);
+            if is_label(label) {
+                if labels.insert(label.to_owned(), Addr::from(addr as u16)).is_some() {
+                    bail!("line {}: duplicate label {}", n + 1, label);
+                }
+                rest[1..].trim()
+            } else {
+                line
+            }
+        } else {
+            line
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let stmt = parse_statement(line).chain_err(|| format!("line {}: {}", n + 1, line))?;
+        addr = match stmt {
+            Statement::Org(o) if (o as u32) < addr => {
+                bail!("line {}: .org 0x{:04x} would move backwards", n + 1, o)
+            }
+            Statement::Org(o) => o as u32,
+            ref s => addr + s.len() as u32,
+        };
+        if addr > MAX_ADDR as u32 + 1 {
+            bail!("line {}: program exceeds memory", n + 1);
+        }
+        statements.push((n + 1, stmt));
+    }
+
+    let mut words = Vec::new();
+    for (n, stmt) in statements {
+        encode(&stmt, origin, &labels, &mut words).chain_err(|| format!("line {}", n))?;
+    }
+    Ok(Assembly {
+        origin: Addr::from(origin),
+        words: words,
+        labels: labels,
+    })
+}
+
+fn parse_statement(line: &str) -> Result<Statement> {
+    let (op, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    match op {
+        ".string" => {
+            if rest.len() < 2 || !rest.starts_with("\"") || !rest.ends_with("\"") {
+                bail!(".string requires a quoted string");
+            }
+            unescape(&rest[1..(rest.len() - 1)]).map(Statement::Str)
+        }
+        ".data" => {
+            split_operands(rest)
+                .into_iter()
+                .map(Operand::from_str)
+                .collect::<Result<Vec<_>>>()
+                .map(Statement::Data)
+        }
+        ".org" => {
+            match Operand::from_str(rest)? {
+                Operand::Num(n) if n <= MAX_ADDR => Ok(Statement::Org(n)),
+                _ => bail!(".org requires an address"),
+            }
+        }
+        m => {
+            let instr = match Instruction::by_mnemonic(m) {
+                Some(i) => i,
+                None => bail!("unknown instruction {}", m),
+            };
+            let ops = split_operands(rest)
+                .into_iter()
+                .map(Operand::from_str)
+                .collect::<Result<Vec<_>>>()?;
+            if ops.len() != instr.operands.len() {
+                bail!("{} takes {} operand(s), {} given",
+                      m,
+                      instr.operands.len(),
+                      ops.len());
+            }
+            Ok(Statement::Instr(instr, ops))
+        }
+    }
+}
+
+fn resolve(op: &Operand, labels: &HashMap<String, Addr>) -> Result<u16> {
+    match *op {
+        Operand::Reg(r) => Ok(u16::from(Value::FromRegister(r))),
+        Operand::Num(n) => Ok(n),
+        Operand::Label(ref l) => {
+            match labels.get(l) {
+                Some(a) => Ok(u16::from(*a)),
+                None => bail!("undefined label {}", l),
+            }
+        }
+    }
+}
+
+fn encode(stmt: &Statement,
+          origin: u16,
+          labels: &HashMap<String, Addr>,
+          words: &mut Vec<u16>)
+          -> Result<()> {
+    match *stmt {
+        Statement::Instr(instr, ref ops) => {
+            words.push(instr.num);
+            for (role, op) in instr.operands.iter().zip(ops) {
+                let w = resolve(op, labels)?;
+                if role.is_register() && !is_reg(op) {
+                    bail!("operand {} must be a register", op);
+                }
+                if w > MAX_LITERAL && !is_reg(op) {
+                    bail!("literal {} out of range (0..{})", w, MAX_LITERAL);
+                }
+                words.push(w);
+            }
+        }
+        Statement::Data(ref ops) => {
+            for op in ops {
+                words.push(resolve(op, labels)?);
+            }
+        }
+        Statement::Str(ref s) => words.extend(s.chars().map(|c| c as u16)),
+        Statement::Org(o) => {
+            let len = (o - origin) as usize;
+            words.resize(len, 0);
+        }
+    }
+    Ok(())
+}
+
+fn is_reg(op: &Operand) -> bool {
+    match *op {
+        Operand::Reg(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disasm::{Disassembly, Mode};
+    use memory::Memory;
+
+    const PROGRAM: &'static str = "
+    set r0 32767
+    call f
+    jt r0 done
+    wmem buf r1
+    rmem r2 buf
+    add r3 r2 'a'
+    mult r4 r3 r0
+    mod r5 r4 7
+    out r3
+    jmp done
+f:
+    push r7
+    not r1 r0
+    and r1 r1 0x7ffe
+    gt r0 r1 5
+    pop r7
+    ret
+done:
+    halt
+buf:
+    .data 0 0x8000 65535
+    .string \"hi\\n\"
+";
+
+    fn listing(asm: &Assembly) -> String {
+        let mem = Memory::new(asm.to_bytes().unwrap()).unwrap();
+        let disasm = Disassembly::new(&mem, Mode::Recursive, &[asm.origin]);
+        let mut out = Vec::new();
+        disasm.write_listing(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn disassembly_reassembles() {
+        let asm = assemble(PROGRAM).unwrap();
+        let again = assemble(&listing(&asm)).unwrap();
+        assert_eq!(asm.words, again.words);
+    }
+
+    #[test]
+    fn literals_out_of_range() {
+        for src in &["set r0 32768",
+                     "add r0 r1 40000",
+                     "jmp 32768",
+                     "out 65535",
+                     "wmem 32775 r0",
+                     ".org 32768\nx: jmp x"] {
+            assert!(assemble(src).is_err(), "assembled {:?}", src);
+        }
+        assert_eq!(assemble("set r0 32767").unwrap().words, vec![1, 32768, 32767]);
+    }
+
+    #[test]
+    fn invalid_char_literals() {
+        for src in &["out '", ".data '", "out <'>", "out 'a", "out ''"] {
+            assert!(assemble(src).is_err(), "assembled {:?}", src);
+        }
+        assert_eq!(assemble("out 'a'").unwrap().words, vec![19, 97]);
+    }
+
+    #[test]
+    fn registers_required() {
+        for src in &["set 5 1", "pop 32768", "in 0"] {
+            assert!(assemble(src).is_err(), "assembled {:?}", src);
+        }
+    }
+}
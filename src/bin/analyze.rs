@@ -0,0 +1,304 @@
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+extern crate synacor;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use synacor::analysis::call_graph::CallGraph;
+use synacor::analysis::cfg::Cfg;
+use synacor::analysis::decompile;
+use synacor::analysis::signature::Signatures;
+use synacor::analysis::strings;
+use synacor::analysis::xref;
+use synacor::disasm::{Disassembly, Mode};
+use synacor::errors::*;
+use synacor::machine::{Inspectable, Machine};
+use synacor::memory::{Addr, Register, Target};
+use synacor::patch;
+use synacor::symbols::{Kind, Symbols};
+
+fn image_args(cmd: App<'static, 'static>) -> App<'static, 'static> {
+    cmd.arg(Arg::with_name("save")
+            .short("s")
+            .long("save")
+            .help("IMAGE is a debugger save file rather than a raw ROM"))
+        .arg(Arg::with_name("entry")
+            .short("e")
+            .long("entry")
+            .value_name("ADDR")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Additional entry point to follow"))
+        .arg(Arg::with_name("patch")
+            .short("p")
+            .long("patch")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Apply the patches in FILE to IMAGE first"))
+        .arg(Arg::with_name("symbols")
+            .short("y")
+            .long("symbols")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Name addresses as given in the symbol file FILE, whose functions are \
+                   followed as entry points and whose names may be used in place of addresses"))
+        .arg(Arg::with_name("IMAGE").required(true).index(1))
+}
+
+/// Loads the image named on the command line and disassembles it from its entry points
+fn load(matches: &ArgMatches) -> Result<(Machine, Disassembly)> {
+    let image = matches.value_of("IMAGE").unwrap();
+    let mut machine = if matches.is_present("save") {
+        Machine::from_save(image)?
+    } else {
+        Machine::new(image)?
+    };
+    if let Some(files) = matches.values_of("patch") {
+        for f in files {
+            for p in patch::load(f).chain_err(|| format!("unable to load patches from {}", f))? {
+                p.apply(machine.memory_mut())?;
+            }
+        }
+    }
+    let symbols = match matches.value_of("symbols") {
+        Some(f) => Symbols::load(f).chain_err(|| format!("unable to load symbols from {}", f))?,
+        None => Symbols::new(),
+    };
+    let mut entries = vec![machine.ip().unwrap_or(Addr::from(0u16))];
+    if let Some(es) = matches.values_of("entry") {
+        for e in es {
+            entries.push(symbols.parse_addr(e)?);
+        }
+    }
+    entries.extend(symbols.iter().filter(|&(_, s)| s.kind == Kind::Function).map(|(a, _)| a));
+    let mode = if matches.is_present("linear") {
+        Mode::Linear
+    } else {
+        Mode::Recursive
+    };
+    let mut disasm = Disassembly::new(machine.memory(), mode, &entries);
+    disasm.set_symbols(symbols);
+    Ok((machine, disasm))
+}
+
+fn disassemble(matches: &ArgMatches) -> Result<Disassembly> {
+    load(matches).map(|(_, disasm)| disasm)
+}
+
+fn cfg(matches: &ArgMatches) -> Result<()> {
+    let disasm = disassemble(matches)?;
+    let cfg = Cfg::new(&disasm);
+    let functions = match matches.values_of("function") {
+        Some(fs) => {
+            let mut functions = Vec::new();
+            for f in fs {
+                let addr = disasm.symbols().parse_addr(f)?;
+                match cfg.function(addr) {
+                    Some(f) => functions.push(f),
+                    None => bail!("no function starts at {}", addr),
+                }
+            }
+            functions
+        }
+        None => cfg.functions().collect(),
+    };
+
+    match matches.value_of("output") {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            for f in functions {
+                let path = Path::new(dir).join(format!("sub_{:04x}.dot", f.entry));
+                let mut out = BufWriter::new(File::create(&path)?);
+                f.write_dot(&disasm, &mut out)?;
+            }
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            for f in functions {
+                f.write_dot(&disasm, &mut out)?;
+            }
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn decompile(matches: &ArgMatches) -> Result<()> {
+    let disasm = disassemble(matches)?;
+    let cfg = Cfg::new(&disasm);
+    let sigs = Signatures::new(&disasm, &cfg);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let functions = match matches.values_of("function") {
+        Some(fs) => {
+            let mut functions = Vec::new();
+            for f in fs {
+                let addr = disasm.symbols().parse_addr(f)?;
+                match cfg.function(addr) {
+                    Some(f) => functions.push(f),
+                    None => bail!("no function starts at {}", addr),
+                }
+            }
+            functions
+        }
+        None => cfg.functions().collect(),
+    };
+    for (i, f) in functions.into_iter().enumerate() {
+        if i > 0 {
+            writeln!(out, "")?;
+        }
+        decompile::decompile(&disasm, &sigs, f, &mut out)?;
+    }
+    Ok(())
+}
+
+fn strings(matches: &ArgMatches) -> Result<()> {
+    let (machine, disasm) = load(matches)?;
+    let cfg = Cfg::new(&disasm);
+    let sigs = Signatures::new(&disasm, &cfg);
+    let routines = match matches.values_of("routine") {
+        Some(rs) => {
+            let mut routines = Vec::new();
+            for r in rs {
+                routines.push(disasm.symbols().parse_addr(r)?);
+            }
+            routines
+        }
+        None => strings::printers(&disasm, &cfg),
+    };
+    for s in strings::decrypt(&machine, &disasm, &cfg, &sigs, &routines) {
+        println!("{}", s);
+    }
+    Ok(())
+}
+
+fn signatures(matches: &ArgMatches) -> Result<()> {
+    let disasm = disassemble(matches)?;
+    let sigs = Signatures::new(&disasm, &Cfg::new(&disasm));
+    for (f, sig) in sigs.iter() {
+        let name = disasm.label(f).unwrap_or_else(|| format!("sub_{:04x}", u16::from(f)));
+        println!("{:?} {}{}", f, name, sig.summary());
+    }
+    Ok(())
+}
+
+fn xrefs(matches: &ArgMatches) -> Result<()> {
+    let disasm = disassemble(matches)?;
+    let target = matches.value_of("TARGET").unwrap();
+    let tgt = if target.len() == 2 && target.starts_with('r') {
+        Target::Reg(Register::from_str(&target[1..])?)
+    } else {
+        Target::Mem(disasm.symbols().parse_addr(target)?)
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    xref::write_xrefs(&disasm, &Cfg::new(&disasm), &xref::to_target(&disasm, tgt), &mut out)
+}
+
+fn call_graph(matches: &ArgMatches) -> Result<()> {
+    let disasm = disassemble(matches)?;
+    let graph = CallGraph::new(&Cfg::new(&disasm), None);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match matches.value_of("tree") {
+        Some(root) => graph.write_tree(&disasm, disasm.symbols().parse_addr(root)?, &mut out),
+        None => graph.write_dot(&disasm, &mut out),
+    }
+}
+
+fn run() -> Result<()> {
+    let matches = App::new("analyze")
+        .about("Static analyses of Synacor VM ROMs and save files")
+        .subcommand(image_args(SubCommand::with_name("cfg")
+                .about("Control-flow graph of each function, as Graphviz DOT"))
+            .arg(Arg::with_name("function")
+                .short("f")
+                .long("function")
+                .value_name("ADDR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only graph the function starting at ADDR"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Write one sub_XXXX.dot file per function to DIR rather than \
+                       all graphs to stdout")))
+        .subcommand(image_args(SubCommand::with_name("callgraph")
+                .about("Direct calls between functions, as Graphviz DOT"))
+            .arg(Arg::with_name("tree")
+                .short("t")
+                .long("tree")
+                .value_name("ROOT")
+                .takes_value(true)
+                .help("Print the calls made from ROOT as an indented tree instead")))
+        .subcommand(image_args(SubCommand::with_name("decompile")
+                .about("Structured pseudo-code for each function"))
+            .arg(Arg::with_name("function")
+                .short("f")
+                .long("function")
+                .value_name("ADDR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only decompile the function starting at ADDR")))
+        .subcommand(image_args(SubCommand::with_name("signatures")
+                .about("Arguments, returned, saved and clobbered registers of each function")))
+        .subcommand(image_args(SubCommand::with_name("strings")
+                .about("Strings printed by calls to print routines, decoded by running them"))
+            .arg(Arg::with_name("routine")
+                .short("r")
+                .long("routine")
+                .value_name("ADDR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Print routine to run; defaults to every function executing an `out`")))
+        .subcommand(image_args(SubCommand::with_name("xref")
+                .about("Instructions jumping to, calling, reading or writing an address through \
+                        a literal operand, or reading or writing a register"))
+            .arg(Arg::with_name("linear")
+                .short("l")
+                .long("linear")
+                .help("Search every word decoded in turn, rather than only code reachable from \
+                       the entry points"))
+            .arg(Arg::with_name("TARGET")
+                .required(true)
+                .index(2)
+                .help("Address, symbol or register (r0..r7)")))
+        .get_matches();
+
+    match matches.subcommand() {
+        ("cfg", Some(m)) => cfg(m),
+        ("callgraph", Some(m)) => call_graph(m),
+        ("decompile", Some(m)) => decompile(m),
+        ("signatures", Some(m)) => signatures(m),
+        ("strings", Some(m)) => strings(m),
+        ("xref", Some(m)) => xrefs(m),
+        _ => bail!("a subcommand is required; see --help"),
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("error: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        ::std::process::exit(1);
+    }
+}
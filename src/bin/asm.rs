@@ -0,0 +1,52 @@
+extern crate clap;
+extern crate synacor;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use clap::{App, Arg};
+
+use synacor::asm;
+use synacor::errors::*;
+
+fn run() -> Result<()> {
+    let matches = App::new("asm")
+        .about("Assembles Synacor VM programs into ROM images")
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write the image to FILE rather than SOURCE with a .bin extension"))
+        .arg(Arg::with_name("SOURCE").required(true).index(1))
+        .get_matches();
+
+    let source = matches.value_of("SOURCE").unwrap();
+    let mut src = String::new();
+    File::open(source)
+        .and_then(|mut f| f.read_to_string(&mut src))
+        .chain_err(|| format!("unable to read {}", source))?;
+    let assembly = asm::assemble(&src).chain_err(|| format!("unable to assemble {}", source))?;
+
+    let output = match matches.value_of("output") {
+        Some(o) => Path::new(o).to_path_buf(),
+        None => Path::new(source).with_extension("bin"),
+    };
+    let mut f = File::create(&output)
+        .chain_err(|| format!("unable to create {}", output.display()))?;
+    f.write_all(&assembly.to_bytes()?)?;
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("error: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        ::std::process::exit(1);
+    }
+}
@@ -0,0 +1,122 @@
+extern crate clap;
+extern crate synacor;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use clap::{App, Arg};
+
+use synacor::analysis::cfg::Cfg;
+use synacor::analysis::signature::Signatures;
+use synacor::disasm::{Disassembly, Mode};
+use synacor::errors::*;
+use synacor::machine::{Inspectable, Machine};
+use synacor::memory::Addr;
+use synacor::patch;
+use synacor::symbols::{Kind, Symbols};
+
+fn run() -> Result<()> {
+    let matches = App::new("disasm")
+        .about("Disassembles Synacor VM ROMs and save files")
+        .arg(Arg::with_name("linear")
+            .short("l")
+            .long("linear")
+            .help("Decode every word in turn, rather than following control flow from the \
+                   entry point and call targets"))
+        .arg(Arg::with_name("save")
+            .short("s")
+            .long("save")
+            .help("IMAGE is a debugger save file rather than a raw ROM"))
+        .arg(Arg::with_name("entry")
+            .short("e")
+            .long("entry")
+            .value_name("ADDR")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Additional entry point to follow"))
+        .arg(Arg::with_name("patch")
+            .short("p")
+            .long("patch")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Apply the patches in FILE before disassembling"))
+        .arg(Arg::with_name("symbols")
+            .short("y")
+            .long("symbols")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Name and comment addresses as given in the symbol file FILE; its \
+                   functions are followed as entry points"))
+        .arg(Arg::with_name("no-signatures")
+            .short("n")
+            .long("no-signatures")
+            .help("Leave out the inferred arguments, returns and saved registers noted at the \
+                   start of each function"))
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write the listing to FILE rather than stdout"))
+        .arg(Arg::with_name("IMAGE").required(true).index(1))
+        .get_matches();
+
+    let image = matches.value_of("IMAGE").unwrap();
+    let mut machine = if matches.is_present("save") {
+        Machine::from_save(image)?
+    } else {
+        Machine::new(image)?
+    };
+    if let Some(files) = matches.values_of("patch") {
+        for f in files {
+            for p in patch::load(f).chain_err(|| format!("unable to load patches from {}", f))? {
+                p.apply(machine.memory_mut())?;
+            }
+        }
+    }
+    let symbols = match matches.value_of("symbols") {
+        Some(f) => Symbols::load(f).chain_err(|| format!("unable to load symbols from {}", f))?,
+        None => Symbols::new(),
+    };
+    let mut entries = vec![machine.ip().unwrap_or(Addr::from(0u16))];
+    if let Some(es) = matches.values_of("entry") {
+        for e in es {
+            entries.push(symbols.parse_addr(e)?);
+        }
+    }
+    entries.extend(symbols.iter().filter(|&(_, s)| s.kind == Kind::Function).map(|(a, _)| a));
+    let mode = if matches.is_present("linear") {
+        Mode::Linear
+    } else {
+        Mode::Recursive
+    };
+    let mut disassembly = Disassembly::new(machine.memory(), mode, &entries);
+    disassembly.set_symbols(symbols);
+    if !matches.is_present("no-signatures") {
+        let sigs = Signatures::new(&disassembly, &Cfg::new(&disassembly));
+        disassembly.set_signatures(&sigs);
+    }
+
+    let stdout = io::stdout();
+    let mut out: Box<Write> = match matches.value_of("output") {
+        Some(f) => Box::new(BufWriter::new(File::create(f)?)),
+        None => Box::new(stdout.lock()),
+    };
+    writeln!(out, "; {} ({:?})", image, mode)?;
+    disassembly.write_listing(&mut out)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("error: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        ::std::process::exit(1);
+    }
+}
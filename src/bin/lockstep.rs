@@ -0,0 +1,129 @@
+extern crate clap;
+extern crate synacor;
+
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches};
+
+use synacor::errors::*;
+use synacor::lockstep::{self, Poke};
+use synacor::machine::{Inspectable, Machine};
+use synacor::patch;
+
+/// Loads one of the machines, applying its patches and pokes
+fn machine(matches: &ArgMatches, image: &str, side: &str) -> Result<Machine> {
+    let mut m = if matches.is_present("save") {
+        Machine::from_save(image)?
+    } else {
+        Machine::new(image)?
+    };
+    if let Some(files) = matches.values_of(format!("patch-{}", side)) {
+        for f in files {
+            for p in patch::load(f).chain_err(|| format!("unable to load patches from {}", f))? {
+                p.apply(m.memory_mut())?;
+            }
+        }
+    }
+    if let Some(pokes) = matches.values_of(format!("poke-{}", side)) {
+        for p in pokes {
+            Poke::from_str(p)?.apply(&mut m);
+        }
+    }
+    Ok(m)
+}
+
+fn run() -> Result<()> {
+    let matches = App::new("lockstep")
+        .about("Runs two Synacor VMs side by side and reports the first point their \
+                ip, registers, stack, memory writes or output differ")
+        .arg(Arg::with_name("save")
+            .short("s")
+            .long("save")
+            .help("A and B are debugger save files rather than raw ROMs"))
+        .arg(Arg::with_name("poke-a")
+            .long("poke-a")
+            .value_name("LOC=VALUE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Set a register (r7=5) or memory word (0x1571=21) in A before running"))
+        .arg(Arg::with_name("poke-b")
+            .short("b")
+            .long("poke-b")
+            .value_name("LOC=VALUE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Set a register or memory word in B before running"))
+        .arg(Arg::with_name("patch-a")
+            .long("patch-a")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Apply the patches in FILE to A"))
+        .arg(Arg::with_name("patch-b")
+            .long("patch-b")
+            .value_name("FILE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Apply the patches in FILE to B"))
+        .arg(Arg::with_name("input")
+            .short("i")
+            .long("input")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Lines to give both machines when they ask for input"))
+        .arg(Arg::with_name("steps")
+            .short("n")
+            .long("steps")
+            .value_name("N")
+            .takes_value(true)
+            .help("Give up after N instructions (default 10000000)"))
+        .arg(Arg::with_name("context")
+            .short("c")
+            .long("context")
+            .value_name("N")
+            .takes_value(true)
+            .help("Show the last N instructions before the divergence (default 8)"))
+        .arg(Arg::with_name("A").required(true).index(1))
+        .arg(Arg::with_name("B")
+            .index(2)
+            .help("The second image; A again if omitted, so that pokes or patches tell them \
+                   apart"))
+        .get_matches();
+
+    let a = matches.value_of("A").unwrap();
+    let b = matches.value_of("B").unwrap_or(a);
+    let ma = machine(&matches, a, "a")?;
+    let mb = machine(&matches, b, "b")?;
+    let mut input = String::new();
+    if let Some(f) = matches.value_of("input") {
+        File::open(f)?.read_to_string(&mut input)?;
+    }
+    let steps = match matches.value_of("steps") {
+        Some(n) => u64::from_str(n).chain_err(|| format!("invalid step count {}", n))?,
+        None => 10_000_000,
+    };
+    let context = match matches.value_of("context") {
+        Some(n) => usize::from_str(n).chain_err(|| format!("invalid context {}", n))?,
+        None => 8,
+    };
+    println!("{}", lockstep::run(ma, mb, &input, steps, context)?);
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("error: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        ::std::process::exit(1);
+    }
+}
@@ -0,0 +1,134 @@
+extern crate clap;
+#[macro_use]
+extern crate error_chain;
+extern crate synacor;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::str::FromStr;
+
+use clap::{App, Arg};
+
+use synacor::errors::*;
+use synacor::memory::AddrRange;
+use synacor::op_code::Instruction;
+use synacor::trace::{Filter, Format, Reader, Writer};
+
+fn run() -> Result<()> {
+    let matches = App::new("trace")
+        .about("Queries execution traces recorded by the debugger's '> bin' and '> json'")
+        .arg(Arg::with_name("ip")
+            .short("i")
+            .long("ip")
+            .value_name("RANGE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only instructions at an address in RANGE, e.g. 0x5fb or 0x5fb..0x61e"))
+        .arg(Arg::with_name("op")
+            .short("c")
+            .long("op")
+            .value_name("MNEMONIC")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only instructions with the given opcode"))
+        .arg(Arg::with_name("reg")
+            .short("r")
+            .long("reg")
+            .value_name("rN=VALUE")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only instructions run while the register held VALUE"))
+        .arg(Arg::with_name("depth")
+            .short("d")
+            .long("depth")
+            .value_name("N|MIN..MAX")
+            .takes_value(true)
+            .help("Only instructions run at the given call depth"))
+        .arg(Arg::with_name("format")
+            .short("f")
+            .long("format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["text", "bin", "json"])
+            .help("Write matching records as text (the default), binary or JSON lines"))
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write to FILE rather than stdout"))
+        .arg(Arg::with_name("TRACE").required(true).index(1))
+        .get_matches();
+
+    let mut filter = Filter::default();
+    if let Some(ips) = matches.values_of("ip") {
+        for ip in ips {
+            filter.ips.push(AddrRange::from_str(ip)?);
+        }
+    }
+    if let Some(ops) = matches.values_of("op") {
+        for op in ops {
+            match Instruction::by_mnemonic(op) {
+                Some(i) => filter.ops.push(i),
+                None => bail!("unknown opcode '{}'", op),
+            }
+        }
+    }
+    if let Some(regs) = matches.values_of("reg") {
+        for r in regs {
+            filter.regs.push(Filter::parse_reg(r)?);
+        }
+    }
+    if let Some(d) = matches.value_of("depth") {
+        filter.depth = Some(Filter::parse_depth(d)?);
+    }
+
+    let path = matches.value_of("TRACE").unwrap();
+    let reader = Reader::new(BufReader::new(File::open(path)?))
+        .chain_err(|| format!("unable to read trace {}", path))?;
+    let stdout = io::stdout();
+    let out: Box<Write> = match matches.value_of("output") {
+        Some(f) => Box::new(BufWriter::new(File::create(f)?)),
+        None => Box::new(stdout.lock()),
+    };
+    let format = match matches.value_of("format") {
+        Some("text") | None => None,
+        Some(f) => Some(Format::from_str(f)?),
+    };
+    match format {
+        Some(f) => {
+            let mut w = Writer::new(out, f)?;
+            for r in reader {
+                let r = r?;
+                if filter.matches(&r) {
+                    w.write(&r)?;
+                }
+            }
+        }
+        None => {
+            let mut out = out;
+            for r in reader {
+                let r = r?;
+                if filter.matches(&r) {
+                    writeln!(out, "{}", r)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("error: {}", e);
+
+        for e in e.iter().skip(1) {
+            println!("caused by: {}", e);
+        }
+
+        ::std::process::exit(1);
+    }
+}
@@ -1,34 +1,366 @@
 use std::{fmt, result};
-use std::str::FromStr;
 
 use errors::*;
-use memory::{Addr, Target};
-use op_code::{DecodedOpCode, OpAccess, OpCode};
+use memory::{Addr, AddrRange, Register, Target, Value};
+use op_code::{DecodedOpCode, Flow, Instruction, OpAccess, OpCode, Role};
+use super::expr::Expr;
 
+/// A breakpoint or watchpoint, along with what decides whether reaching it
+/// stops execution
 #[derive(Debug)]
-pub enum Breakpoint {
+pub struct Breakpoint {
+    pub kind: Kind,
+    /// only stop where this evaluates to non-zero
+    pub condition: Option<Expr>,
+    /// how many more times to pass it without stopping
+    pub ignore: u32,
+    /// times it has been reached with its condition holding
+    pub hits: u32,
+    /// deleted after it first stops execution
+    pub temporary: bool,
+    pub enabled: bool,
+    /// debugger commands run when it stops execution, each a list of words
+    pub commands: Vec<Vec<String>>,
+    /// whether execution carries on after the commands have run
+    pub continues: bool,
+}
+
+impl Breakpoint {
+    pub fn new(kind: Kind) -> Breakpoint {
+        Breakpoint {
+            kind: kind,
+            condition: None,
+            ignore: 0,
+            hits: 0,
+            temporary: false,
+            enabled: true,
+            commands: Vec::new(),
+            continues: false,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "{}", self.kind)?;
+        if let Some(ref c) = self.condition {
+            write!(f, " if {}", c)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Kind {
     At(Addr),
     Read(Target),
     Write(Target),
     Access(Target),
+    Change(Watched, Option<Condition>),
+    /// output ending in text matching the pattern
+    Output(Pattern),
+    /// any instruction of the class
+    Op(OpClass),
+    /// a call to the function at the address
+    Call(Addr),
+    /// a return from the function at the address
+    Return(Addr),
+    /// an `in` with no input left to read
+    Input,
 }
 
-impl fmt::Display for Breakpoint {
+/// The instruction about to run, and what it runs in
+pub struct Next<'a> {
+    pub ip: Addr,
+    pub op: &'a OpCode,
+    pub decoded: &'a DecodedOpCode,
+    /// entry of the function executing, as far as calls have been followed
+    pub function: Addr,
+    /// whether an `in` would read buffered input rather than ask for more
+    pub has_input: bool,
+}
+
+/// Instructions an `op` breakpoint stops at
+#[derive(Debug)]
+pub enum OpClass {
+    Instr(&'static Instruction),
+    /// `jmp`, `jt` and `jf`
+    Jump,
+    /// jumps and calls to an address held in a register
+    Indirect,
+}
+
+impl OpClass {
+    pub fn new(name: &str) -> Result<OpClass> {
+        Ok(match name {
+            "jump" => OpClass::Jump,
+            "indirect" => OpClass::Indirect,
+            m => {
+                match Instruction::by_mnemonic(m) {
+                    Some(i) => OpClass::Instr(i),
+                    None => bail!("unknown instruction class '{}'", m),
+                }
+            }
+        })
+    }
+
+    fn matches(&self, op: &OpCode) -> bool {
+        let flow = op.instruction().flow;
+        match *self {
+            OpClass::Instr(i) => op.num() == i.num,
+            OpClass::Jump => flow == Flow::Jump || flow == Flow::Branch,
+            OpClass::Indirect => {
+                op.roles().iter().any(|&(role, v)| match (role, v) {
+                    (Role::Dest, Value::FromRegister(_)) => true,
+                    _ => false,
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for OpClass {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
         match *self {
-            Breakpoint::At(addr) => write!(f, "@ {}", addr),
-            Breakpoint::Read(ref t) => write!(f, "read {}", t),
-            Breakpoint::Write(ref t) => write!(f, "write {}", t),
-            Breakpoint::Access(ref t) => write!(f, "access {}", t),
+            OpClass::Instr(i) => write!(f, "{}", i.mnemonic),
+            OpClass::Jump => write!(f, "jump"),
+            OpClass::Indirect => write!(f, "indirect"),
         }
     }
 }
 
+/// Locations covered by a value-change watchpoint
+#[derive(Debug)]
+pub enum Watched {
+    Mem(AddrRange),
+    Regs(Vec<Register>),
+}
+
+impl Watched {
+    pub fn covers(&self, tgt: &Target) -> bool {
+        match (self, *tgt) {
+            (&Watched::Mem(ref range), Target::Mem(addr)) => range.contains(addr),
+            (&Watched::Regs(ref regs), Target::Reg(reg)) => regs.contains(&reg),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Watched {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Watched::Mem(ref range) => write!(f, "{}", range),
+            Watched::Regs(ref regs) => {
+                let regs = regs.iter().map(|r| format!("{}", r)).collect::<Vec<_>>();
+                write!(f, "{}", regs.join(","))
+            }
+        }
+    }
+}
+
+/// Text to look for in the machine's output: `*` matches any run of characters,
+/// `?` any one character, and `\n`, `\t`, `\*`, `\?` and `\\` stand for themselves
+#[derive(Debug)]
+pub struct Pattern {
+    text: String,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Item {
+    Char(char),
+    AnyChar,
+    AnyRun,
+}
+
+impl Pattern {
+    pub fn new(text: &str) -> Result<Pattern> {
+        let mut items = Vec::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            items.push(match c {
+                '*' => Item::AnyRun,
+                '?' => Item::AnyChar,
+                '\\' => {
+                    match chars.next() {
+                        Some('n') => Item::Char('\n'),
+                        Some('t') => Item::Char('\t'),
+                        Some(c @ '*') | Some(c @ '?') | Some(c @ '\\') => Item::Char(c),
+                        Some(c) => bail!("unknown escape '\\{}' in pattern", c),
+                        None => bail!("pattern ends in '\\'"),
+                    }
+                }
+                c => Item::Char(c),
+            });
+        }
+        // output is matched as it is printed, so a trailing `*` would match as
+        // soon as what precedes it did, and a leading one matches anything before
+        while items.last() == Some(&Item::AnyRun) {
+            items.pop();
+        }
+        while items.first() == Some(&Item::AnyRun) {
+            items.remove(0);
+        }
+        if items.is_empty() {
+            bail!("pattern must match some text");
+        }
+        // matched backwards from the latest character printed
+        items.reverse();
+        Ok(Pattern {
+            text: text.to_owned(),
+            items: items,
+        })
+    }
+
+    /// Whether `output` ends with text matching the pattern
+    pub fn ends(&self, output: &[char]) -> bool {
+        let text = |t: usize| output[output.len() - 1 - t];
+        let (mut i, mut t) = (0, 0);
+        // the latest `*` and where in the text it stops matching
+        let mut star = None;
+        while i < self.items.len() {
+            match self.items[i] {
+                Item::AnyRun => {
+                    star = Some((i, t));
+                    i += 1;
+                    continue;
+                }
+                ref item => {
+                    if t < output.len() && (*item == Item::AnyChar || *item == Item::Char(text(t))) {
+                        i += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+            // let the latest `*` take one more character and retry from there
+            match star {
+                Some((s, end)) if end < output.len() => {
+                    star = Some((s, end + 1));
+                    i = s + 1;
+                    t = end + 1;
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "\"{}\"", self.text)
+    }
+}
+
+/// Further restricts which changes trigger a watchpoint
+#[derive(Debug)]
+pub enum Condition {
+    /// the new value is exactly the given value
+    To(u16),
+    /// the value moves from below the threshold to at/above it, or vice versa
+    Crosses(u16),
+}
+
+impl Condition {
+    pub fn new(op: &str, v: u16) -> Result<Condition> {
+        match op {
+            "to" => Ok(Condition::To(v)),
+            "crosses" => Ok(Condition::Crosses(v)),
+            o => bail!("unknown watch condition {}", o),
+        }
+    }
+
+    fn matches(&self, old: u16, new: u16) -> bool {
+        match *self {
+            Condition::To(v) => new == v,
+            Condition::Crosses(v) => (old < v) != (new < v),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Condition::To(v) => write!(f, "to {}", v),
+            Condition::Crosses(v) => write!(f, "crosses {}", v),
+        }
+    }
+}
+
+/// A store that altered the value held in a register or memory location
+#[derive(Debug)]
+pub struct Change {
+    pub ip: Addr,
+    pub instr: String,
+    pub target: Target,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f,
+               "{}: 0x{:04x} -> 0x{:04x} ({} -> {}) by {:?}: {}",
+               self.target,
+               self.old,
+               self.new,
+               self.old,
+               self.new,
+               self.ip,
+               self.instr)
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Kind::At(addr) => write!(f, "@ {}", addr),
+            Kind::Read(ref t) => write!(f, "read {}", t),
+            Kind::Write(ref t) => write!(f, "write {}", t),
+            Kind::Access(ref t) => write!(f, "access {}", t),
+            Kind::Change(ref w, Some(ref c)) => write!(f, "change {} {}", w, c),
+            Kind::Change(ref w, None) => write!(f, "change {}", w),
+            Kind::Output(ref p) => write!(f, "output {}", p),
+            Kind::Op(ref c) => write!(f, "op {}", c),
+            Kind::Call(addr) => write!(f, "call {}", addr),
+            Kind::Return(addr) => write!(f, "ret {}", addr),
+            Kind::Input => write!(f, "input"),
+        }
+    }
+}
+
+/// Why execution stopped; breakpoints are given with their numbers
 #[derive(Debug)]
 pub enum Reason<'bp> {
     Halted,
     Stalled,
-    Triggered(&'bp Breakpoint),
+    Triggered(usize, &'bp Breakpoint),
+    Changed(usize, &'bp Breakpoint, &'bp Change),
+    /// an output breakpoint, and the `out` that completed the text it matched
+    Printed(usize, &'bp Breakpoint, Addr),
+}
+
+impl<'bp> Reason<'bp> {
+    /// The number of the breakpoint that stopped execution, if any
+    pub fn number(&self) -> Option<usize> {
+        match *self {
+            Reason::Triggered(n, _) |
+            Reason::Changed(n, _, _) |
+            Reason::Printed(n, _, _) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The number of the temporary breakpoint that stopped execution, if any
+    pub fn temporary(&self) -> Option<usize> {
+        match *self {
+            Reason::Triggered(n, bp) |
+            Reason::Changed(n, bp, _) |
+            Reason::Printed(n, bp, _) if bp.temporary => Some(n),
+            _ => None,
+        }
+    }
 }
 
 impl<'bp> fmt::Display for Reason<'bp> {
@@ -36,37 +368,89 @@ impl<'bp> fmt::Display for Reason<'bp> {
         match *self {
             Reason::Stalled => write!(f, "machine stalled"),
             Reason::Halted => write!(f, "machine halted"),
-            Reason::Triggered(bp) => write!(f, "triggered {}", bp),
+            Reason::Triggered(n, bp) => write!(f, "triggered {}: {}", n, bp),
+            Reason::Changed(n, bp, change) => write!(f, "triggered {}: {}\n{}", n, bp, change),
+            Reason::Printed(n, bp, _) => write!(f, "triggered {}: {}", n, bp),
         }
     }
 }
 
-impl Breakpoint {
-    pub fn at(loc: &str) -> Result<Breakpoint> {
-        Target::from_str(loc).and_then(|tgt| match tgt {
-            Target::Mem(addr) => Ok(Breakpoint::At(addr)),
+impl Kind {
+    pub fn at(tgt: Target) -> Result<Kind> {
+        match tgt {
+            Target::Mem(addr) => Ok(Kind::At(addr)),
             _ => bail!("must specify memory address for @ breakpoint"),
-        })
+        }
+    }
+
+    pub fn is_triggered(&self, next: &Next) -> bool {
+        let (op_code, decoded_op) = (next.op, next.decoded);
+        match *self {
+            Kind::At(addr) => next.ip == addr,
+            Kind::Read(ref t) => op_code.reads(t) || decoded_op.reads(t),
+            Kind::Write(ref t) => op_code.writes(t) || decoded_op.writes(t),
+            Kind::Access(ref t) => op_code.accesses(t) || decoded_op.accesses(t),
+            Kind::Op(ref c) => c.matches(op_code),
+            Kind::Call(f) => {
+                match *decoded_op {
+                    DecodedOpCode::Call { addr } => addr == f,
+                    _ => false,
+                }
+            }
+            Kind::Return(f) => {
+                match *decoded_op {
+                    DecodedOpCode::Ret { .. } => next.function == f,
+                    _ => false,
+                }
+            }
+            Kind::Input => {
+                match *decoded_op {
+                    DecodedOpCode::In { .. } => !next.has_input,
+                    _ => false,
+                }
+            }
+            Kind::Change(..) |
+            Kind::Output(_) => false,
+        }
     }
 
-    pub fn read(loc: &str) -> Result<Breakpoint> {
-        Target::from_str(loc).map(Breakpoint::Read)
+    /// The memory address the breakpoint is on, or the start of the range it watches
+    pub fn addr(&self) -> Option<Addr> {
+        match *self {
+            Kind::At(addr) |
+            Kind::Read(Target::Mem(addr)) |
+            Kind::Write(Target::Mem(addr)) |
+            Kind::Access(Target::Mem(addr)) |
+            Kind::Call(addr) |
+            Kind::Return(addr) => Some(addr),
+            Kind::Change(Watched::Mem(ref range), _) => range.first(),
+            _ => None,
+        }
     }
 
-    pub fn write(loc: &str) -> Result<Breakpoint> {
-        Target::from_str(loc).map(Breakpoint::Write)
+    pub fn is_watching(&self) -> bool {
+        match *self {
+            Kind::Change(..) => true,
+            _ => false,
+        }
     }
 
-    pub fn access(loc: &str) -> Result<Breakpoint> {
-        Target::from_str(loc).map(Breakpoint::Access)
+    /// Whether the output so far, of which `output` is the most recent, ends in
+    /// text the breakpoint looks for
+    pub fn is_printed(&self, output: &[char]) -> bool {
+        match *self {
+            Kind::Output(ref p) => p.ends(output),
+            _ => false,
+        }
     }
 
-    pub fn is_triggered(&self, ip: &Addr, op_code: &OpCode, decoded_op: &DecodedOpCode) -> bool {
+    pub fn is_changed(&self, change: &Change) -> bool {
         match *self {
-            Breakpoint::At(ref addr) => ip == addr,
-            Breakpoint::Read(ref t) => op_code.reads(t) || decoded_op.reads(t),
-            Breakpoint::Write(ref t) => op_code.writes(t) || decoded_op.writes(t),
-            Breakpoint::Access(ref t) => op_code.accesses(t) || decoded_op.accesses(t),
+            Kind::Change(ref w, ref cond) => {
+                w.covers(&change.target) &&
+                cond.as_ref().map(|c| c.matches(change.old, change.new)).unwrap_or(true)
+            }
+            _ => false,
         }
     }
 }
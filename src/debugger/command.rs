@@ -0,0 +1,933 @@
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use errors::*;
+use super::{expr, Debugger, Until};
+
+/// A debugger command, run with its arguments after the name or an alias
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// the forms the command takes, e.g. `step [n]`
+    pub usage: &'static [&'static str],
+    pub summary: &'static str,
+    /// detail shown by `help <command>`, if any
+    pub help: &'static str,
+    /// whether entering an empty line runs the command again
+    pub repeats: bool,
+    pub run: fn(&mut Debugger, &[&str]) -> Result<()>,
+}
+
+pub static COMMANDS: &'static [Command] = &[
+    Command {
+        name: "continue",
+        aliases: &["c"],
+        usage: &["continue"],
+        summary: "continue execution until a breakpoint triggers",
+        help: "",
+        repeats: true,
+        run: continue_,
+    },
+    Command {
+        name: "step",
+        aliases: &["s"],
+        usage: &["step [n]"],
+        summary: "step execution n times (once if unspecified)",
+        help: "",
+        repeats: true,
+        run: step,
+    },
+    Command {
+        name: "next",
+        aliases: &["n"],
+        usage: &["next [n]"],
+        summary: "step n times (once if unspecified), running calls until they return",
+        help: "\
+calls are tracked as they are made, so a call returns when execution is back at the
+instruction after it with as many calls outstanding as before it",
+        repeats: true,
+        run: next,
+    },
+    Command {
+        name: "finish",
+        aliases: &["fin", "out"],
+        usage: &["finish"],
+        summary: "continue until the current function returns, showing what it returned",
+        help: "shows the registers the function's signature returns, or r0",
+        repeats: true,
+        run: finish,
+    },
+    Command {
+        name: "until",
+        aliases: &["u"],
+        usage: &["until addr"],
+        summary: "continue until execution reaches addr",
+        help: "",
+        repeats: false,
+        run: until,
+    },
+    Command {
+        name: "instr",
+        aliases: &["i"],
+        usage: &["instr"],
+        summary: "show the current instruction",
+        help: "\
+calls show the callee's inferred arguments, and returns the registers the function
+returns",
+        repeats: false,
+        run: instr,
+    },
+    Command {
+        name: "backtrace",
+        aliases: &["bt"],
+        usage: &["backtrace"],
+        summary: "show the calls leading to the current instruction",
+        help: "\
+calls are tracked as the machine makes them, as the stack holds data as well as return
+addresses; returns to an address no call pushed, and pops of return addresses, are
+listed as anomalies",
+        repeats: false,
+        run: backtrace,
+    },
+    Command {
+        name: "examine",
+        aliases: &["x"],
+        usage: &["examine addr[..addr]", "examine s [n]", "examine r[0-7]"],
+        summary: "examine memory, the stack or registers",
+        help: "\
+addr[..addr] - examine memory contents at addr. a range can be specified, e.g.
+               0x000f..0x00f0; omitting the first, second, or both addresses will
+               extend the range from the start or to the end
+s [n]        - show stack contents. If <n> is specified, at most <n> entries will be
+               shown (top down)
+r[0-7]       - show register contents ('r' shows all registers)",
+        repeats: false,
+        run: examine,
+    },
+    Command {
+        name: "write",
+        aliases: &["w"],
+        usage: &["write n val"],
+        summary: "write val (0..32767) to register n",
+        help: "n is either 'r<n>', or an expression yielding 0..7",
+        repeats: false,
+        run: write,
+    },
+    Command {
+        name: "print",
+        aliases: &["p"],
+        usage: &["print expr"],
+        summary: "evaluate and print expr; the result is also stored in $_",
+        help: "",
+        repeats: false,
+        run: print,
+    },
+    Command {
+        name: "display",
+        aliases: &["disp"],
+        usage: &["display [expr | /s [n] | /i]"],
+        summary: "show expr whenever execution stops, or with no arguments show all displays now",
+        help: "\
+displays are shown after any command that runs the machine, with a '*' against values
+that changed since they were last shown
+    expr   - an expression, e.g. 'display r7' or 'display [0x0aac]'
+    /s [n] - the top n stack entries (4 if unspecified)
+    /i     - the instruction at the ip",
+        repeats: false,
+        run: display,
+    },
+    Command {
+        name: "undisplay",
+        aliases: &[],
+        usage: &["undisplay n...|*"],
+        summary: "stop showing displays (\"*\" for all of them)",
+        help: "",
+        repeats: false,
+        run: undisplay,
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        usage: &["set $var expr"],
+        summary: "store the value of expr in the convenience variable $var",
+        help: "",
+        repeats: false,
+        run: set,
+    },
+    Command {
+        name: "break",
+        aliases: &["b"],
+        usage: &["break op loc [if expr]",
+                 "break c loc [to val | crosses val] [if expr]",
+                 "break o text... [if expr]",
+                 "break op <class> [if expr]",
+                 "break call|ret function [if expr]",
+                 "break input [if expr]"],
+        summary: "add a breakpoint or watchpoint",
+        help: "\
+breakpoints are numbered from 1, and keep their numbers until deleted; with 'if expr'
+a breakpoint only stops execution where expr is non-zero, e.g. 'b @ 0x1577 if r7 != 0'
+op loc
+    op: one of:
+      @ (at)     - break when instruction pointer hits given address
+      r (read)   - break when an instruction reads from given address or register
+      w (write)  - break when an instruction writes to given address or register
+      a (access) - break when an instruction reads or writes given address or register
+    loc: location to watch, either one of r[0...7] for registers,
+         or 0x<addr> for memory location.
+         NB: @ op requires a memory address
+c loc [to val | crosses val]
+    add a watchpoint, breaking after an instruction changes the value stored in loc
+    loc: either a comma separated list of registers (e.g. r1,r7; 'r' for all
+         registers), or a memory address range as for 'examine' (e.g. 0x0aac..0x0ab0)
+    to val      - only break if the new value is val
+    crosses val - only break if the value moves across the threshold val
+o text...
+    break after the output so far ends in text, e.g. 'b o teleporter', showing
+    where it was printed from; in text * matches anything, ? matches any one
+    character, and \\n is a newline ('b o \\n' breaks at the next one); a word
+    'if' in text starts the condition, so quote text containing one, e.g.
+    'b o \"what if\"'
+op <class>
+    'op' is typed as is, e.g. 'b op wmem': break at any instruction of the class,
+    a mnemonic, 'jump' for jmp, jt and jf, or 'indirect' for jumps and calls to
+    an address in a register
+call function / ret function
+    break at calls to the function at the given address, or at its ret
+input
+    break at an 'in' with no input left to read, before asking for more",
+        repeats: false,
+        run: break_,
+    },
+    Command {
+        name: "tbreak",
+        aliases: &["tb"],
+        usage: &["tbreak op loc [if expr]",
+                 "tbreak c loc [to val | crosses val] [if expr]",
+                 "tbreak o text... [if expr]",
+                 "tbreak op <class>|call function|ret function|input [if expr]"],
+        summary: "add a breakpoint deleted once it stops execution",
+        help: "takes the same arguments as 'break'",
+        repeats: false,
+        run: tbreak,
+    },
+    Command {
+        name: "condition",
+        aliases: &["cond"],
+        usage: &["condition n [expr]"],
+        summary: "only stop at breakpoint n where expr is non-zero",
+        help: "with no expr, breakpoint n stops execution whenever it is reached",
+        repeats: false,
+        run: condition,
+    },
+    Command {
+        name: "ignore",
+        aliases: &[],
+        usage: &["ignore n count"],
+        summary: "pass breakpoint n count times before stopping at it",
+        help: "",
+        repeats: false,
+        run: ignore,
+    },
+    Command {
+        name: "commands",
+        aliases: &[],
+        usage: &["commands n [command; ...]"],
+        summary: "run commands whenever breakpoint n stops execution",
+        help: "\
+replaces any commands breakpoint n had, or with none given removes them. A final
+'continue' carries on running after the others have run, so breakpoints can patch
+state or log as the program runs, e.g. for the teleporter:
+    b @ 0x1577
+    commands 1 \"w r1 1; w r7 25734; c\"
+no other command, nor one in a script they source, may step or continue execution;
+quote the commands to keep the ';' between them from ending the 'commands' command",
+        repeats: false,
+        run: commands,
+    },
+    Command {
+        name: "enable",
+        aliases: &["be"],
+        usage: &["enable n...|*"],
+        summary: "enable breakpoints",
+        help: "",
+        repeats: false,
+        run: enable,
+    },
+    Command {
+        name: "disable",
+        aliases: &["bd"],
+        usage: &["disable n...|*"],
+        summary: "disable breakpoints, keeping them to enable again later",
+        help: "",
+        repeats: false,
+        run: disable,
+    },
+    Command {
+        name: "breakpoints",
+        aliases: &["bl"],
+        usage: &["breakpoints"],
+        summary: "list breakpoints, with their conditions and how often they were hit",
+        help: "",
+        repeats: false,
+        run: breakpoints,
+    },
+    Command {
+        name: "delete",
+        aliases: &["bx"],
+        usage: &["delete n...|*"],
+        summary: "delete breakpoints (\"*\" for all breakpoints)",
+        help: "",
+        repeats: false,
+        run: delete,
+    },
+    Command {
+        name: "save",
+        aliases: &["v"],
+        usage: &["save file"],
+        summary: "save vm state to file",
+        help: "",
+        repeats: false,
+        run: save,
+    },
+    Command {
+        name: "load",
+        aliases: &["l"],
+        usage: &["load file"],
+        summary: "load vm state from file",
+        help: "",
+        repeats: false,
+        run: load,
+    },
+    Command {
+        name: "dump",
+        aliases: &["d"],
+        usage: &["dump file"],
+        summary: "dump the memory contents to file",
+        help: "",
+        repeats: false,
+        run: dump,
+    },
+    Command {
+        name: "log",
+        aliases: &[">"],
+        usage: &["log [file|-]", "log bin|json file"],
+        summary: "log or trace executed instructions",
+        help: "\
+file|-        - log instructions to file. if '-' is specified, instructions will be
+                printed to STDOUT; logging is turned off if no argument specified
+bin|json file - trace instructions to file as binary or JSON-lines records of the
+                step, ip, instruction, registers, call depth and the registers,
+                memory, stack and output each one changed; query them with the trace
+                tool",
+        repeats: false,
+        run: log,
+    },
+    Command {
+        name: "strings",
+        aliases: &["f"],
+        usage: &["strings", "strings addr..."],
+        summary: "scan memory for strings, or decode those printed by routines",
+        help: "\
+with no arguments, scans memory for strings and outputs them
+addr...   - run the print routines at addr in a copy of the VM for each call made
+            with constant arguments, listing the decoded strings with their first
+            argument (usually the string's address) and the calls printing them",
+        repeats: false,
+        run: strings,
+    },
+    Command {
+        name: "heatmap",
+        aliases: &["hm"],
+        usage: &["heatmap [on|off|reset]", "heatmap csv|ppm|png file"],
+        summary: "record and export per-word read/write/execute counts",
+        help: "\
+on|off|reset     - start, stop or restart recording counts (with no argument, shows
+                   whether counts are being recorded)
+csv|ppm|png file - export the recorded counts to file, either as CSV or as a 128x256
+                   image (one pixel per word; red: writes, green: executed, blue: reads)",
+        repeats: false,
+        run: heatmap,
+    },
+    Command {
+        name: "callgraph",
+        aliases: &["cg"],
+        usage: &["callgraph [root]", "callgraph dot file"],
+        summary: "show the call graph",
+        help: "\
+root     - show the call graph as a tree of the functions called from root (by
+           default the program entry); calls seen while running are counted ('x<n>'),
+           and calls only seen while running, e.g. 'call r5', are marked '(indirect)'
+dot file - write the call graph to file in Graphviz DOT format",
+        repeats: false,
+        run: callgraph,
+    },
+    Command {
+        name: "xref",
+        aliases: &[],
+        usage: &["xref [-l] loc"],
+        summary: "list the instructions referring to an address or register",
+        help: "\
+lists the instructions that jump to, call, read (rmem) or write (wmem) the address loc
+as a literal operand, or that read or write the register loc; searches code reachable
+from the entry point, functions called so far and the current instruction, or with -l,
+every word decoded in turn",
+        repeats: false,
+        run: xref,
+    },
+    Command {
+        name: "lockstep",
+        aliases: &[],
+        usage: &["lockstep [-s file] [loc=value]..."],
+        summary: "find where a copy of the vm with some values changed diverges",
+        help: "\
+runs a copy of the vm alongside another copy, or the save file, with the given
+registers (r7=5) or memory words (0x1571=21) set, until their ip, registers, stack,
+memory writes or output differ; shows both and the last instructions run",
+        repeats: false,
+        run: lockstep,
+    },
+    Command {
+        name: "patch",
+        aliases: &["pa"],
+        usage: &["patch", "patch load file", "patch apply|revert n"],
+        summary: "list, load, apply and revert patches",
+        help: "\
+with no arguments, lists patches, with the words they expect and write
+load file        - apply the patches in file (see src/patch.rs for the format);
+                   patches given with -p on the command line are loaded at startup,
+                   and applied patches are applied again after 'load'
+apply|revert n   - apply or revert patch n (\"*\" for all patches)",
+        repeats: false,
+        run: patch,
+    },
+    Command {
+        name: "symbol",
+        aliases: &["sym"],
+        usage: &["symbol [name [expr]]",
+                 "symbol fn|label|var name expr",
+                 "symbol rm name...",
+                 "symbol load|save [file]"],
+        summary: "define, show, remove, load and save symbols",
+        help: "\
+name [expr]          - define symbol name as the address expr; with no expr, shows the
+                       symbol, with no arguments, lists all symbols and comments
+fn|label|var name expr
+                     - define symbol name as the address expr, naming a function, other
+                       code, or a memory word holding data ('symbol name expr' defines a
+                       label)
+rm name...           - remove symbols
+load|save [file]     - replace the symbols with those in file, or write them to it (by
+                       default, the file last loaded or saved; the ROM's symbols are
+                       loaded from the file given after the ROM on the command line, or
+                       the ROM's path with a .sym extension)
+
+symbols name addresses in 'instr', the 'log' output, 'examine s', 'breakpoints',
+'callgraph' and 'strings' output, either exactly or as an offset from the closest
+function or label before them, e.g. <print+0x3>",
+        repeats: false,
+        run: symbol,
+    },
+    Command {
+        name: "comment",
+        aliases: &["cm"],
+        usage: &["comment addr [text]"],
+        summary: "attach comment text to addr, shown with the instruction",
+        help: "no text removes the comment",
+        repeats: false,
+        run: comment,
+    },
+    Command {
+        name: "source",
+        aliases: &["."],
+        usage: &["source [-e] [-v] file [arg...]"],
+        summary: "run the commands in a script",
+        help: "\
+runs file a line at a time, as if typed at the prompt; lines starting with '#' are
+comments, and $1..$9 are replaced with the arguments given after file ($# with how
+many there are), e.g. a script holding
+    # load a save and force the teleporter check
+    load $1; write 7 $2; break @ 0x1577; continue
+can be run with 'source teleport.dbg 6.bin 25734'
+-e - stop at the first command that fails
+-v - echo each line before running it
+the startup script, .synacorrc in the current directory unless another is given with
+-x on the command line (or -n for none), is sourced when the debugger starts",
+        repeats: false,
+        run: source_,
+    },
+    Command {
+        name: "help",
+        aliases: &["h", "?"],
+        usage: &["help [command]"],
+        summary: "list commands, or describe one",
+        help: "",
+        repeats: false,
+        run: help,
+    },
+    Command {
+        name: "quit",
+        aliases: &["q"],
+        usage: &["quit"],
+        summary: "quit",
+        help: "",
+        repeats: false,
+        run: quit,
+    },
+];
+
+/// Notes on the command line as a whole, listed after the commands by `help`
+const GENERAL_HELP: &'static str = "\
+commands are separated by ';', arguments containing spaces or ';' may be enclosed in
+double quotes, and an empty line repeats the last 'step', 'next', 'finish' or 'continue'
+
+addresses, values and registers may be given as expressions, e.g. 'x [r1]+2', 'w 1 r7+1':
+  literals    - 42, 0x2a, 0b101010 (or b101010), 'a'
+  registers   - r0..r7
+  memory      - [addr] is the word stored at addr
+  operators   - + - * / % << >> & | ^ ~ == != < <= > >= && || ! (arithmetic is modulo 32768)
+  symbols     - names defined with 'symbol' or loaded from a symbol file, e.g. 'b @ print'
+  variables   - $name as set by 'set', along with $ip, $sp (stack depth),
+                $s0, $s1, ... (stack entries from the top) and $_ (last 'print' result)";
+
+/// How deeply scripts may source other scripts
+const MAX_SCRIPT_DEPTH: usize = 16;
+
+/// Commands that run the machine, which breakpoint commands cannot do in turn
+pub const RESUMING: &'static [&'static str] = &["continue", "step", "next", "finish", "until"];
+
+/// The command with the given name or alias
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name || c.aliases.contains(&name))
+}
+
+/// Splits a line into `;`-separated commands, each a list of words. Double
+/// quotes group words, with `\"` and `\\` escapes, and a word that is a character
+/// literal such as `';'` is kept whole for expressions; any other `'` is plain
+pub fn parse(line: &str) -> Result<Vec<Vec<String>>> {
+    let mut commands = Vec::new();
+    let mut words = Vec::new();
+    // the word so far, and whether there is one, as `""` is an empty word
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == ';' || c.is_whitespace() {
+            if in_word {
+                words.push(word);
+                word = String::new();
+                in_word = false;
+            }
+            if c == ';' && !words.is_empty() {
+                commands.push(words);
+                words = Vec::new();
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.next() {
+                                Some(c) => word.push(c),
+                                None => bail!("unterminated quote"),
+                            }
+                        }
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated quote"),
+                    }
+                }
+            }
+            '\'' if !in_word => {
+                word.push(c);
+                let len = char_literal(chars.as_str());
+                word.extend(chars.by_ref().take(len));
+            }
+            c => word.push(c),
+        }
+        in_word = true;
+    }
+    if in_word {
+        words.push(word);
+    }
+    if !words.is_empty() {
+        commands.push(words);
+    }
+    Ok(commands)
+}
+
+/// Characters in the rest of a character literal such as `';'` or `'\n'` after
+/// its opening quote, if it makes up the rest of a word; otherwise 0
+fn char_literal(rest: &str) -> usize {
+    let mut chars = rest.chars();
+    let len = match (chars.next(), chars.next(), chars.next()) {
+        (Some('\\'), Some(c), Some('\'')) => 2 + c.len_utf8(),
+        (Some(c), Some('\''), _) => c.len_utf8() + 1,
+        _ => return 0,
+    };
+    match rest[len..].chars().next() {
+        Some(c) if c != ';' && !c.is_whitespace() => 0,
+        _ => rest[..len].chars().count(),
+    }
+}
+
+/// The words of a command as `parse` would read them back
+pub fn quote(words: &[String]) -> String {
+    words.iter()
+        .map(|w| if w.is_empty() || w.contains(|c: char| c.is_whitespace() || ";\"'".contains(c)) {
+            format!("\"{}\"", w.replace('\\', "\\\\").replace('"', "\\\""))
+        } else {
+            w.clone()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs the command named by the first word with the rest as its arguments
+pub fn execute(d: &mut Debugger, words: &[String]) -> Result<()> {
+    let c = match find(&words[0]) {
+        Some(c) => c,
+        None => bail!("unrecognized command, try 'help'"),
+    };
+    let args = words[1..].iter().map(|w| w.as_str()).collect::<Vec<_>>();
+    (c.run)(d, &args)
+}
+
+/// Prints the error from running a command
+pub fn report(name: &str, e: &Error) {
+    println!("{}: {}", name, e);
+    for e in e.iter().skip(1) {
+        println!("caused by: {}", e);
+    }
+}
+
+/// Runs each line of the script at `path` with `$1`.. replaced by `args`,
+/// stopping at the first failing command if `abort` is set, and printing
+/// lines before running them if `echo` is
+pub fn source(d: &mut Debugger, path: &str, args: &[&str], abort: bool, echo: bool) -> Result<()> {
+    if d.script_depth >= MAX_SCRIPT_DEPTH {
+        bail!("scripts nested too deeply");
+    }
+    let mut script = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut script))
+        .chain_err(|| format!("unable to read {}", path))?;
+    d.script_depth += 1;
+    let r = run_script(d, path, &script, args, abort, echo);
+    d.script_depth -= 1;
+    r
+}
+
+fn run_script(d: &mut Debugger,
+              path: &str,
+              script: &str,
+              args: &[&str],
+              abort: bool,
+              echo: bool)
+              -> Result<()> {
+    for (n, line) in script.lines().enumerate() {
+        let at = || format!("{}:{}", path, n + 1);
+        if line.trim().is_empty() || line.trim_left().starts_with('#') {
+            continue;
+        }
+        let commands = substitute(line, args).and_then(|line| {
+            if echo {
+                println!("+ {}", line);
+            }
+            parse(&line)
+        });
+        let commands = match commands {
+            Ok(commands) => commands,
+            Err(e) if abort => return Err(e).chain_err(&at),
+            Err(e) => {
+                report(&at(), &e);
+                continue;
+            }
+        };
+        for words in commands {
+            if let Err(e) = execute(d, &words) {
+                if abort {
+                    return Err(e).chain_err(|| format!("{}: {}", at(), words[0]));
+                }
+                report(&format!("{}: {}", at(), words[0]), &e);
+                break;
+            }
+            if d.quit {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `$1`..`$9` in `line` with the script's arguments, and `$#` with
+/// their number
+fn substitute(line: &str, args: &[&str]) -> Result<String> {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let n = match (c, chars.peek().cloned()) {
+            ('$', Some('#')) => {
+                chars.next();
+                out.push_str(&args.len().to_string());
+                continue;
+            }
+            ('$', Some(d)) if d.is_digit(10) && d != '0' => d.to_digit(10).unwrap() as usize,
+            _ => {
+                out.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        match args.get(n - 1) {
+            Some(a) => out.push_str(a),
+            None => bail!("script expects at least {} arguments", n),
+        }
+    }
+    Ok(out)
+}
+
+fn arg<'a>(args: &[&'a str], i: usize, what: &str) -> Result<&'a str> {
+    match args.get(i) {
+        Some(a) => Ok(a),
+        None => bail!("must specify {}", what),
+    }
+}
+
+fn continue_(d: &mut Debugger, _: &[&str]) -> Result<()> {
+    d.run(None).map(|_| ())
+}
+
+fn step_count(args: &[&str]) -> Result<u64> {
+    Ok(match args.first() {
+        Some(n) => u64::from_str(n).chain_err(|| format!("invalid step count: {}", n))?,
+        None => 1,
+    })
+}
+
+fn step(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.run(Some(Until::Steps(step_count(args)?))).map(|_| ())
+}
+
+fn next(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    for _ in 0..step_count(args)? {
+        if !d.step_over()? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn finish(d: &mut Debugger, _: &[&str]) -> Result<()> {
+    d.finish()
+}
+
+fn until(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let addr = d.eval_addr(arg(args, 0, "address")?)?;
+    d.run(Some(Until::Addr(addr))).map(|_| ())
+}
+
+fn backtrace(d: &mut Debugger, _: &[&str]) -> Result<()> {
+    d.show_backtrace()
+}
+
+fn instr(d: &mut Debugger, _: &[&str]) -> Result<()> {
+    println!("{}", d.curr_instr()?);
+    Ok(())
+}
+
+fn examine(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    match arg(args, 0, "location to examine")? {
+        "s" => d.show_stack(args.get(1).cloned()),
+        "r" => d.show_registers(None),
+        loc => {
+            match expr::Expr::from_str(loc) {
+                Ok(expr::Expr::Reg(r)) => d.show_registers(Some(r)),
+                _ => d.examine_mem(loc),
+            }
+        }
+    }
+}
+
+fn write(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.write_reg(arg(args, 0, "register")?, arg(args, 1, "value")?)
+}
+
+fn print(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    arg(args, 0, "expression to print")?;
+    d.print_expr(&args.join(" "))
+}
+
+fn display(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    if !args.is_empty() {
+        d.add_display(args)?;
+    }
+    d.show_displays();
+    Ok(())
+}
+
+fn undisplay(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.delete_displays(args)
+}
+
+fn set(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let name = arg(args, 0, "variable")?;
+    arg(args, 1, "expression")?;
+    d.set_var(name, &args[1..].join(" "))
+}
+
+fn add_breakpoint(d: &mut Debugger, args: &[&str], temporary: bool) -> Result<()> {
+    let n = d.add_breakpoint(arg(args, 0, "op")?, &args[1..], temporary)?;
+    println!("breakpoint {}: {}", n, d.breakpoints[&n]);
+    Ok(())
+}
+
+fn break_(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    add_breakpoint(d, args, false)
+}
+
+fn tbreak(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    add_breakpoint(d, args, true)
+}
+
+fn condition(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.set_condition(arg(args, 0, "breakpoint")?, &args[1..].join(" "))
+}
+
+fn commands(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.set_commands(arg(args, 0, "breakpoint")?, &args[1..].join(" "))
+}
+
+fn ignore(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.set_ignore(arg(args, 0, "breakpoint")?, arg(args, 1, "count")?)
+}
+
+fn enable(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.enable_breakpoints(args, true)
+}
+
+fn disable(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.enable_breakpoints(args, false)
+}
+
+fn breakpoints(d: &mut Debugger, _: &[&str]) -> Result<()> {
+    d.list_breakpoints();
+    Ok(())
+}
+
+fn delete(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.delete_breakpoints(args)
+}
+
+fn save(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let f = arg(args, 0, "output file")?;
+    d.save_vm(File::create(f).chain_err(|| format!("unable to create {}", f))?)
+}
+
+fn load(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let f = arg(args, 0, "input file")?;
+    d.load_vm(File::open(f).chain_err(|| format!("unable to open {}", f))?)
+}
+
+fn dump(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let f = arg(args, 0, "output file")?;
+    d.dump_mem(File::create(f).chain_err(|| format!("unable to create {}", f))?)
+}
+
+fn log(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.set_output(args.get(0).cloned(), args.get(1).cloned())
+}
+
+fn strings(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    if args.is_empty() {
+        d.scan_strings()
+    } else {
+        d.decrypt_strings(args)
+    }
+}
+
+fn heatmap(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.heat_map(args.get(0).cloned(), args.get(1).cloned())
+}
+
+fn callgraph(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.call_graph(args.get(0).cloned(), args.get(1).cloned())
+}
+
+fn xref(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.xrefs(args)
+}
+
+fn lockstep(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.lockstep(args)
+}
+
+fn patch(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.patch(args.get(0).cloned(), args.get(1).cloned())
+}
+
+fn symbol(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    d.set_symbol(args)
+}
+
+fn comment(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let addr = arg(args, 0, "address")?;
+    d.set_comment(addr, &args[1..].join(" "))
+}
+
+fn source_(d: &mut Debugger, args: &[&str]) -> Result<()> {
+    let (mut abort, mut echo) = (false, false);
+    let mut i = 0;
+    while i < args.len() {
+        match args[i] {
+            "-e" => abort = true,
+            "-v" => echo = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    source(d, arg(args, i, "script")?, &args[i + 1..], abort, echo)
+}
+
+fn help(_: &mut Debugger, args: &[&str]) -> Result<()> {
+    match args.first() {
+        Some(name) => {
+            let c = match find(name) {
+                Some(c) => c,
+                None => bail!("unknown command '{}'", name),
+            };
+            for u in c.usage {
+                println!("usage: {}", u);
+            }
+            if !c.aliases.is_empty() {
+                println!("aliases: {}", c.aliases.join(", "));
+            }
+            println!("\n{}", c.summary);
+            if !c.help.is_empty() {
+                println!("\n{}", c.help);
+            }
+        }
+        None => {
+            for c in COMMANDS {
+                let name = if c.aliases.is_empty() {
+                    c.name.to_owned()
+                } else {
+                    format!("{} ({})", c.name, c.aliases.join(", "))
+                };
+                println!("{:<20} - {}", name, c.summary);
+            }
+            println!("\n'help <command>' describes a command and the forms it takes\n");
+            println!("{}", GENERAL_HELP);
+        }
+    }
+    Ok(())
+}
+
+fn quit(d: &mut Debugger, _: &[&str]) -> Result<()> {
+    println!("quitting...");
+    d.quit = true;
+    Ok(())
+}
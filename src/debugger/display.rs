@@ -0,0 +1,56 @@
+use std::{fmt, result};
+
+use super::expr::Expr;
+
+/// What an auto-display shows each time execution stops
+#[derive(Debug)]
+pub enum Kind {
+    /// an expression, as it was given and parsed
+    Expr(String, Expr),
+    /// entries from the top of the stack
+    Stack(usize),
+    /// the instruction at the ip
+    Instr,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Kind::Expr(ref e, _) => write!(f, "{}", e),
+            Kind::Stack(n) => write!(f, "/s {}", n),
+            Kind::Instr => write!(f, "/i"),
+        }
+    }
+}
+
+/// An auto-display, and the lines it last showed so that changes can be marked
+#[derive(Debug)]
+pub struct AutoDisplay {
+    pub kind: Kind,
+    last: Option<Vec<String>>,
+}
+
+impl AutoDisplay {
+    pub fn new(kind: Kind) -> AutoDisplay {
+        AutoDisplay {
+            kind: kind,
+            last: None,
+        }
+    }
+
+    /// `lines`, each with whether it differs from when last shown
+    pub fn mark(&mut self, lines: Vec<String>) -> Vec<(bool, String)> {
+        let marked = lines.iter()
+            .enumerate()
+            .map(|(i, l)| {
+                let changed = match self.last {
+                    Some(ref last) => last.get(i) != Some(l),
+                    None => false,
+                };
+                (changed, l.clone())
+            })
+            .collect();
+        self.last = Some(lines);
+        marked
+    }
+}
@@ -0,0 +1,461 @@
+use std::{fmt, result};
+use std::str::FromStr;
+
+use try_from::TryFrom;
+
+use errors::*;
+use memory::{Addr, Register, Target, Value};
+
+/// Arithmetic in expressions wraps the same way the VM's does
+const MODULUS: u32 = 32768;
+
+/// Supplies the machine state and names an expression is evaluated against
+pub trait Env {
+    fn register(&self, reg: Register) -> u16;
+    fn memory(&self, addr: Addr) -> u16;
+    fn symbol(&self, name: &str) -> Option<u16>;
+    fn variable(&self, name: &str) -> Option<u16>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    LogNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Xor,
+    Or,
+    LogAnd,
+    LogOr,
+}
+
+impl BinOp {
+    /// Binding strength; higher binds tighter
+    fn precedence(&self) -> u8 {
+        match *self {
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 10,
+            BinOp::Add | BinOp::Sub => 9,
+            BinOp::Shl | BinOp::Shr => 8,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 7,
+            BinOp::Eq | BinOp::Ne => 6,
+            BinOp::And => 5,
+            BinOp::Xor => 4,
+            BinOp::Or => 3,
+            BinOp::LogAnd => 2,
+            BinOp::LogOr => 1,
+        }
+    }
+
+    fn apply(&self, l: u16, r: u16) -> Result<u16> {
+        let (l32, r32) = (l as u32, r as u32);
+        Ok(match *self {
+            BinOp::Mul => ((l32 * r32) % MODULUS) as u16,
+            BinOp::Div if r == 0 => bail!("division by zero"),
+            BinOp::Div => l / r,
+            BinOp::Mod if r == 0 => bail!("division by zero"),
+            BinOp::Mod => l % r,
+            BinOp::Add => ((l32 + r32) % MODULUS) as u16,
+            BinOp::Sub => ((l32 + MODULUS * 2 - r32) % MODULUS) as u16,
+            BinOp::Shl => ((l32 << (r32 & 0xf)) % MODULUS) as u16,
+            BinOp::Shr => l >> (r & 0xf),
+            BinOp::Lt => (l < r) as u16,
+            BinOp::Le => (l <= r) as u16,
+            BinOp::Gt => (l > r) as u16,
+            BinOp::Ge => (l >= r) as u16,
+            BinOp::Eq => (l == r) as u16,
+            BinOp::Ne => (l != r) as u16,
+            BinOp::And => l & r,
+            BinOp::Xor => l ^ r,
+            BinOp::Or => l | r,
+            BinOp::LogAnd => (l != 0 && r != 0) as u16,
+            BinOp::LogOr => (l != 0 || r != 0) as u16,
+        })
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        let s = match *self {
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::And => "&",
+            BinOp::Xor => "^",
+            BinOp::Or => "|",
+            BinOp::LogAnd => "&&",
+            BinOp::LogOr => "||",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// An expression accepted anywhere the debugger expects an address, value or register
+///
+/// Supports:
+/// * literals: decimal, hex (`0x1f`), binary (`0b101`, or `b101`) and characters (`'a'`)
+/// * registers `r0`..`r7`
+/// * memory dereference: `[addr]`
+/// * arithmetic (`+ - * / % << >>`, wrapping modulo 32768), bitwise (`& | ^ ~`),
+///   comparison (`== != < <= > >=`) and logical (`&& || !`) operators
+/// * symbols, e.g. `print`, and convenience variables, e.g. `$ip`
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Num(u16),
+    Reg(Register),
+    Deref(Box<Expr>),
+    Sym(String),
+    Var(String),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, env: &Env) -> Result<u16> {
+        Ok(match *self {
+            Expr::Num(n) => n,
+            Expr::Reg(r) => env.register(r),
+            Expr::Deref(ref e) => env.memory(eval_addr(e.eval(env)?)?),
+            Expr::Sym(ref s) => {
+                match env.symbol(s) {
+                    Some(v) => v,
+                    None => bail!("unknown symbol '{}'", s),
+                }
+            }
+            Expr::Var(ref v) => {
+                match env.variable(v) {
+                    Some(v) => v,
+                    None => bail!("unknown variable '${}'", v),
+                }
+            }
+            Expr::Unary(op, ref e) => {
+                let v = e.eval(env)? as u32;
+                match op {
+                    UnOp::Neg => ((MODULUS - (v % MODULUS)) % MODULUS) as u16,
+                    UnOp::Not => (!v % MODULUS) as u16,
+                    UnOp::LogNot => (v == 0) as u16,
+                }
+            }
+            Expr::Binary(BinOp::LogAnd, ref l, ref r) => {
+                // short-circuit, so guards like `r1 && [r1] == 4` are safe
+                (l.eval(env)? != 0 && r.eval(env)? != 0) as u16
+            }
+            Expr::Binary(BinOp::LogOr, ref l, ref r) => {
+                (l.eval(env)? != 0 || r.eval(env)? != 0) as u16
+            }
+            Expr::Binary(op, ref l, ref r) => op.apply(l.eval(env)?, r.eval(env)?)?,
+        })
+    }
+
+    pub fn eval_addr(&self, env: &Env) -> Result<Addr> {
+        self.eval(env).and_then(eval_addr)
+    }
+
+    /// Evaluates to a literal `Value`, suitable for storing in a register
+    pub fn eval_value(&self, env: &Env) -> Result<Value> {
+        let v = self.eval(env)?;
+        if v as u32 >= MODULUS {
+            bail!(ErrorKind::InvalidValue(v));
+        }
+        Ok(Value::Literal(v))
+    }
+
+    /// A bare register names itself; anything else must evaluate to a register number
+    pub fn eval_register(&self, env: &Env) -> Result<Register> {
+        match *self {
+            Expr::Reg(r) => Ok(r),
+            _ => {
+                let n = self.eval(env)?;
+                if n > 7 {
+                    bail!(ErrorKind::InvalidRegister(n));
+                }
+                Register::try_from(n as u8)
+            }
+        }
+    }
+
+    /// A bare register names itself; anything else must evaluate to a memory address
+    pub fn eval_target(&self, env: &Env) -> Result<Target> {
+        match *self {
+            Expr::Reg(r) => Ok(Target::Reg(r)),
+            _ => self.eval_addr(env).map(Target::Mem),
+        }
+    }
+}
+
+fn eval_addr(v: u16) -> Result<Addr> {
+    if v as u32 >= MODULUS {
+        bail!(ErrorKind::InvalidAddr(v as usize));
+    }
+    Ok(Addr::from(v))
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Expr::Num(n) if n < 10 => write!(f, "{}", n),
+            Expr::Num(n) => write!(f, "0x{:04x}", n),
+            Expr::Reg(r) => write!(f, "{}", r),
+            Expr::Deref(ref e) => write!(f, "[{}]", e),
+            Expr::Sym(ref s) => write!(f, "{}", s),
+            Expr::Var(ref v) => write!(f, "${}", v),
+            Expr::Unary(UnOp::Neg, ref e) => write!(f, "-{}", e),
+            Expr::Unary(UnOp::Not, ref e) => write!(f, "~{}", e),
+            Expr::Unary(UnOp::LogNot, ref e) => write!(f, "!{}", e),
+            Expr::Binary(op, ref l, ref r) => write!(f, "({} {} {})", l, op, r),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Expr> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let e = parser.expr(0)?;
+        if let Some(t) = parser.peek() {
+            bail!("unexpected '{}' in expression '{}'", t, s);
+        }
+        Ok(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(u16),
+    Ident(String),
+    Var(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(ref s) => write!(f, "{}", s),
+            Token::Var(ref s) => write!(f, "${}", s),
+            Token::Op(o) => write!(f, "{}", o),
+        }
+    }
+}
+
+const OPERATORS: [&'static str; 24] = ["<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*", "/",
+                                       "%", "+", "-", "<", ">", "&", "^", "|", "~", "!", "[",
+                                       "]", "(", ")"];
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// The longest prefix of `s` whose characters all satisfy `f`
+fn take_while<F: Fn(char) -> bool>(s: &str, f: F) -> &str {
+    let end = s.char_indices().find(|&(_, c)| !f(c)).map(|(i, _)| i).unwrap_or(s.len());
+    &s[..end]
+}
+
+fn parse_num(s: &str) -> Result<u16> {
+    let n = if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16)
+    } else if s.starts_with("0b") {
+        u16::from_str_radix(&s[2..], 2)
+    } else {
+        u16::from_str(s)
+    };
+    n.chain_err(|| format!("invalid number '{}'", s))
+}
+
+fn parse_char(s: &str) -> Result<(char, usize)> {
+    let mut chars = s.chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some('\\'), Some('n')) => '\n',
+        (Some('\\'), Some('t')) => '\t',
+        (Some('\\'), Some(c)) => c,
+        (Some(c), _) => {
+            chars = s[c.len_utf8()..].chars();
+            c
+        }
+        _ => bail!("unterminated character literal"),
+    };
+    match chars.next() {
+        Some('\'') => Ok((c, s.len() - chars.as_str().len())),
+        _ => bail!("unterminated character literal"),
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(c) = s[pos..].chars().next() {
+        let rest = &s[pos..];
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+        } else if c.is_digit(10) {
+            let n = take_while(rest, |c| c.is_alphanumeric());
+            tokens.push(Token::Num(parse_num(n)?));
+            pos += n.len();
+        } else if c == '\'' {
+            let (c, len) = parse_char(&rest[1..])?;
+            tokens.push(Token::Num(c as u16));
+            pos += len + 1;
+        } else if c == '$' {
+            let v = take_while(&rest[1..], is_ident_char);
+            tokens.push(Token::Var(v.to_owned()));
+            pos += v.len() + 1;
+        } else if is_ident_char(c) {
+            let ident = take_while(rest, is_ident_char);
+            // the original debugger accepted 'b101' style binary literals
+            if ident.len() > 1 && ident.starts_with("b") &&
+               ident[1..].chars().all(|c| c == '0' || c == '1') {
+                tokens.push(Token::Num(u16::from_str_radix(&ident[1..], 2)?));
+            } else {
+                tokens.push(Token::Ident(ident.to_owned()));
+            }
+            pos += ident.len();
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    pos += op.len();
+                }
+                None => bail!("unexpected character '{}' at offset {}", c, pos),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'t> {
+    tokens: &'t [Token],
+    pos: usize,
+}
+
+impl<'t> Parser<'t> {
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'t Token> {
+        let t = self.tokens.get(self.pos);
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, op: &str) -> Result<()> {
+        match self.next() {
+            Some(&Token::Op(o)) if o == op => Ok(()),
+            Some(t) => bail!("expected '{}', found '{}'", op, t),
+            None => bail!("expected '{}', found end of expression", op),
+        }
+    }
+
+    fn binop(&self) -> Option<BinOp> {
+        match self.peek() {
+            Some(&Token::Op(o)) => {
+                Some(match o {
+                    "*" => BinOp::Mul,
+                    "/" => BinOp::Div,
+                    "%" => BinOp::Mod,
+                    "+" => BinOp::Add,
+                    "-" => BinOp::Sub,
+                    "<<" => BinOp::Shl,
+                    ">>" => BinOp::Shr,
+                    "<" => BinOp::Lt,
+                    "<=" => BinOp::Le,
+                    ">" => BinOp::Gt,
+                    ">=" => BinOp::Ge,
+                    "==" => BinOp::Eq,
+                    "!=" => BinOp::Ne,
+                    "&" => BinOp::And,
+                    "^" => BinOp::Xor,
+                    "|" => BinOp::Or,
+                    "&&" => BinOp::LogAnd,
+                    "||" => BinOp::LogOr,
+                    _ => return None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Precedence climbing over binary operators of at least `min_prec`
+    fn expr(&mut self, min_prec: u8) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.binop() {
+            let prec = op.precedence();
+            if prec < min_prec {
+                break;
+            }
+            self.next();
+            let rhs = self.expr(prec + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let op = match self.peek() {
+            Some(&Token::Op("-")) => UnOp::Neg,
+            Some(&Token::Op("~")) => UnOp::Not,
+            Some(&Token::Op("!")) => UnOp::LogNot,
+            _ => return self.primary(),
+        };
+        self.next();
+        self.unary().map(|e| Expr::Unary(op, Box::new(e)))
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(&Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(&Token::Var(ref v)) => Ok(Expr::Var(v.clone())),
+            Some(&Token::Ident(ref i)) => {
+                if i.len() == 2 && i.starts_with("r") {
+                    if let Ok(r) = Register::from_str(&i[1..]) {
+                        return Ok(Expr::Reg(r));
+                    }
+                }
+                Ok(Expr::Sym(i.clone()))
+            }
+            Some(&Token::Op("(")) => {
+                let e = self.expr(0)?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Some(&Token::Op("[")) => {
+                let e = self.expr(0)?;
+                self.expect("]")?;
+                Ok(Expr::Deref(Box::new(e)))
+            }
+            Some(t) => bail!("unexpected '{}' in expression", t),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+}
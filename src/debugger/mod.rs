@@ -1,24 +1,41 @@
 mod breakpoint;
+mod command;
+mod display;
+mod expr;
 
 use std;
 use std::ascii::AsciiExt;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use byteorder::WriteBytesExt;
 use try_from::TryFrom;
 
+use analysis::call_graph::{CallGraph, Recorder};
+use analysis::cfg::Cfg;
+use analysis::signature::{Signature, Signatures};
+use analysis::strings;
+use analysis::xref;
+use disasm::{self, Disassembly, Mode};
 use errors::*;
+use heat_map::HeatMap;
+use lockstep;
 use machine::*;
-use memory;
+use memory::{self, Addr};
 use op_code;
+use patch::{self, Patch};
+use symbols::{self, Symbols};
+use trace;
 
 enum VmState {
     Stalled(StalledMachine),
     Running(Machine),
     Halted(HaltedMachine),
+    /// only while `step_vm` has the machine
+    Stepping,
 }
 
 impl AsMut<Inspectable + 'static> for VmState {
@@ -27,6 +44,7 @@ impl AsMut<Inspectable + 'static> for VmState {
             &mut VmState::Running(ref mut r) => r,
             &mut VmState::Stalled(ref mut s) => s,
             &mut VmState::Halted(ref mut h) => h,
+            &mut VmState::Stepping => unreachable!(),
         }
     }
 }
@@ -37,6 +55,7 @@ impl AsRef<Inspectable + 'static> for VmState {
             &VmState::Running(ref r) => r,
             &VmState::Stalled(ref s) => s,
             &VmState::Halted(ref h) => h,
+            &VmState::Stepping => unreachable!(),
         }
     }
 }
@@ -44,12 +63,53 @@ impl AsRef<Inspectable + 'static> for VmState {
 enum Sink {
     StdOut,
     File(File),
+    /// structured records, see `trace`
+    Trace(trace::Writer<BufWriter<File>>),
 }
 
 pub struct Debugger {
     state: VmState,
-    breakpoints: Vec<breakpoint::Breakpoint>,
+    breakpoints: BTreeMap<usize, breakpoint::Breakpoint>,
+    /// number for the next breakpoint added, so numbers are never reused
+    next_breakpoint: usize,
     output: Option<Sink>,
+    last_change: Option<breakpoint::Change>,
+    /// the `out` the last instruction run, if it was one
+    last_output: Option<Addr>,
+    /// the most recent output, searched by output breakpoints
+    recent_output: Vec<char>,
+    symbols: Symbols,
+    /// where symbols were last loaded from or saved to
+    symbols_path: Option<PathBuf>,
+    vars: HashMap<String, u16>,
+    calls: Recorder,
+    signatures: Option<SignatureCache>,
+    patches: Vec<LoadedPatch>,
+    /// instructions executed since the debugger started
+    steps: u64,
+    /// trace record of an `in` waiting for input
+    stalled_record: Option<trace::Record>,
+    quit: bool,
+    /// whether a breakpoint's commands are running, from within `run`
+    in_commands: bool,
+    /// scripts being sourced, innermost last
+    script_depth: usize,
+    /// shown whenever execution stops
+    displays: BTreeMap<usize, display::AutoDisplay>,
+    next_display: usize,
+}
+
+/// A patch and, while it is applied, the words it replaced
+struct LoadedPatch {
+    patch: Patch,
+    original: Option<Vec<patch::Edit>>,
+}
+
+/// Signatures inferred from a disassembly of memory, and the entry points it
+/// was made from
+struct SignatureCache {
+    entries: Vec<Addr>,
+    sigs: Signatures,
 }
 
 impl Debugger {
@@ -58,83 +118,366 @@ impl Debugger {
         let _ = std::io::stdout().flush();
         let mut input = String::new();
         std::io::stdin().read_line(&mut input)?;
-        Ok(if "!" == input.trim() {
+        Ok(if input.is_empty() || "!" == input.trim() {
             None
         } else {
             Some(input)
         })
     }
 
-    fn step_vm(mut self) -> Result<Debugger> {
-        if self.output.is_some() {
-            let i = self.curr_instr()?;
-            match self.output {
-                Some(Sink::StdOut) => println!("{}", i),
-                Some(Sink::File(ref mut f)) => writeln!(f, "{}", i)?,
-                None => unreachable!(),
+    /// Describes the store the next instruction will make, along with the value
+    /// currently held at its destination
+    fn pending_store(&mut self) -> Result<Option<(Addr, String, memory::Target, u16)>> {
+        let m = self.state.as_mut();
+        if let Some(ip) = m.ip() {
+            let (op, decoded_op) = m.peek_instr()?;
+            Ok(decoded_op.write_target().map(|tgt| {
+                let old = read_target(m, &tgt);
+                (ip, format!("{}", op), tgt, old)
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The call or return the next instruction will make, for the dynamic call graph
+    fn pending_call(&mut self) -> Result<Option<(Addr, op_code::DecodedOpCode)>> {
+        if let VmState::Running(ref mut m) = self.state {
+            if let Some(ip) = m.ip() {
+                let (_, decoded_op) = m.peek_instr()?;
+                return Ok(match decoded_op {
+                    op_code::DecodedOpCode::Call { .. } |
+                    op_code::DecodedOpCode::Ret { .. } => Some((ip, decoded_op)),
+                    _ => None,
+                });
             }
         }
-        let Debugger { mut state, breakpoints, output } = self;
-        let machine = match state {
-            VmState::Running(m) => m,
-            VmState::Stalled(stalled) => {
-                if let Some(input) = Debugger::get_input()? {
-                    stalled.set_input(input)?
-                } else {
-                    return Ok(Debugger {
-                        state: VmState::Stalled(stalled),
-                        breakpoints: breakpoints,
-                        output: output,
-                    });
-                }
-            }
-            VmState::Halted(_) => {
-                println!("cannot step Halted VM");
-                return Ok(Debugger {
-                    state: state,
-                    breakpoints: breakpoints,
-                    output: output,
+        Ok(None)
+    }
+
+    /// Completes a trace record from the state after its instruction ran
+    fn write_record(output: &mut Option<Sink>,
+                    mut r: trace::Record,
+                    m: &Inspectable,
+                    out: Option<char>)
+                    -> Result<()> {
+        if let Some(Sink::Trace(ref mut w)) = *output {
+            r.finish(m, out);
+            w.write(&r)?;
+        }
+        Ok(())
+    }
+
+    fn step_vm(&mut self) -> Result<()> {
+        let text = match self.output {
+            Some(Sink::StdOut) |
+            Some(Sink::File(_)) => Some(self.curr_instr()?),
+            _ => None,
+        };
+        match (text, &mut self.output) {
+            (Some(i), &mut Some(Sink::StdOut)) => println!("{}", i),
+            (Some(i), &mut Some(Sink::File(ref mut f))) => writeln!(f, "{}", i)?,
+            _ => {}
+        }
+        let store = if self.breakpoints.values().any(|bp| bp.enabled && bp.kind.is_watching()) {
+            self.pending_store()?
+        } else {
+            None
+        };
+        let call = match self.pending_call()? {
+            Some((ip, decoded_op)) => Some((ip, decoded_op, self.current_function())),
+            None => None,
+        };
+        self.last_change = None;
+        self.last_output = None;
+        let input = match self.state {
+            VmState::Running(_) => None,
+            VmState::Stalled(_) => {
+                match Debugger::get_input()? {
+                    Some(input) => Some(input),
+                    None => return Ok(()),
+                }
+            }
+            VmState::Halted(_) |
+            VmState::Stepping => bail!("cannot step Halted VM"),
+        };
+        // nothing below may fail before the state is put back
+        let state = std::mem::replace(&mut self.state, VmState::Stepping);
+        let (state, r) = self.step_state(state, input);
+        self.state = state;
+        r?;
+        if let Some((ip, op_code::DecodedOpCode::Call { addr }, caller)) = call {
+            self.calls.call(caller, ip, addr);
+        }
+        if let Some((ip, instr, target, old)) = store {
+            let new = read_target(self.state.as_ref(), &target);
+            if new != old {
+                self.last_change = Some(breakpoint::Change {
+                    ip: ip,
+                    instr: instr,
+                    target: target,
+                    old: old,
+                    new: new,
                 });
             }
+        }
+        Ok(())
+    }
+
+    /// Runs an instruction of the machine in `state`, first completing the `in`
+    /// it stalled on with `input`. An instruction that faults halts the machine,
+    /// which is returned whatever happens
+    fn step_state(&mut self, state: VmState, input: Option<String>) -> (VmState, Result<()>) {
+        let mut machine = match (state, input) {
+            (VmState::Running(m), _) => m,
+            (VmState::Stalled(stalled), Some(input)) => {
+                let m = stalled.set_input(input);
+                // the `in` that stalled has now run
+                self.steps += 1;
+                if let Some(r) = self.stalled_record.take() {
+                    if let Err(e) = Debugger::write_record(&mut self.output, r, &m, None) {
+                        return (VmState::Running(m), Err(e));
+                    }
+                }
+                m
+            }
+            (state, _) => return (state, Ok(())),
+        };
+        let record = match self.output {
+            Some(Sink::Trace(_)) => {
+                let depth = machine.frames().len() as u16;
+                match trace::Record::start(self.steps, depth, &mut machine) {
+                    Ok(r) => Some(r),
+                    Err(e) => return (VmState::Running(machine), Err(e)),
+                }
+            }
+            _ => None,
         };
-        state = match machine.step()? {
+        let mut out = None;
+        let ip = machine.ip();
+        let (result, fault) = machine.step_or_halt();
+        let state = match result {
             OpResult::Continue(m) => VmState::Running(m),
             OpResult::Output(c, m) => {
                 print!("{}", c);
+                out = Some(c);
+                self.last_output = ip;
+                self.recent_output.push(c);
+                if self.recent_output.len() > 2 * OUTPUT_WINDOW {
+                    let excess = self.recent_output.len() - OUTPUT_WINDOW;
+                    self.recent_output.drain(..excess);
+                }
                 VmState::Running(m)
             }
             OpResult::Input(stalled) => {
-                if let Some(input) = Debugger::get_input()? {
-                    VmState::Running(stalled.set_input(input)?)
-                } else {
-                    VmState::Stalled(stalled)
+                match Debugger::get_input() {
+                    Ok(Some(input)) => VmState::Running(stalled.set_input(input)),
+                    Ok(None) => {
+                        self.stalled_record = record;
+                        return (VmState::Stalled(stalled), Ok(()));
+                    }
+                    Err(e) => return (VmState::Stalled(stalled), Err(e)),
                 }
             }
             OpResult::Halted(halted) => VmState::Halted(halted),
         };
+        if let Some(e) = fault {
+            return (state, Err(e).chain_err(|| "vm fault; the machine has halted"));
+        }
+        self.steps += 1;
+        let r = match record {
+            Some(r) => Debugger::write_record(&mut self.output, r, state.as_ref(), out),
+            None => Ok(()),
+        };
+        (state, r)
+    }
 
-        Ok(Debugger {
-            state: state,
-            breakpoints: breakpoints,
-            output: output,
-        })
+    /// Runs the machine until `until`, if given, or a breakpoint triggers, returning
+    /// whether it got to `until`
+    fn run(&mut self, until: Option<Until>) -> Result<bool> {
+        if self.in_commands {
+            bail!("cannot resume from breakpoint commands; end them with 'continue' instead");
+        }
+        let mut n = 0;
+        loop {
+            let done = match until {
+                Some(Until::Steps(steps)) => n >= steps,
+                Some(Until::Addr(addr)) => n > 0 && self.state.as_ref().ip() == Some(addr),
+                Some(Until::Depth(depth, ip)) => {
+                    let d = self.depth();
+                    let at = ip.is_none() || self.state.as_ref().ip() == ip;
+                    n > 0 && (d < depth || (d == depth && at))
+                }
+                None => false,
+            };
+            if done {
+                return Ok(true);
+            }
+            self.step_vm()?;
+            n += 1;
+            let stop = match self.triggered_breakpoint() {
+                Ok(Some(r)) => {
+                    println!("breaking: {}", r);
+                    let printed_by = match r {
+                        breakpoint::Reason::Printed(_, _, ip) => Some(ip),
+                        _ => None,
+                    };
+                    Some((r.number(), r.temporary().is_some(), printed_by))
+                }
+                Err(e) => {
+                    println!("error testing breakpoint: {}", e);
+                    None
+                }
+                _ => None,
+            };
+            if let Some((number, temporary, printed_by)) = stop {
+                if let Some(ip) = printed_by {
+                    self.backtrace(ip);
+                }
+                let continues = match number {
+                    Some(n) => {
+                        let continues = self.breakpoint_commands(n);
+                        if temporary {
+                            self.breakpoints.remove(&n);
+                        }
+                        continues
+                    }
+                    None => false,
+                };
+                let running = match self.state {
+                    VmState::Running(_) => true,
+                    _ => false,
+                };
+                if !continues || !running || self.quit {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    /// Runs the commands attached to breakpoint `n`, returning whether execution
+    /// is to carry on
+    fn breakpoint_commands(&mut self, n: usize) -> bool {
+        let (commands, continues) = match self.breakpoints.get(&n) {
+            Some(bp) => (bp.commands.clone(), bp.continues),
+            None => return false,
+        };
+        self.in_commands = true;
+        let mut ok = true;
+        for c in &commands {
+            if let Err(e) = command::execute(self, c) {
+                command::report(&c[0], &e);
+                ok = false;
+                break;
+            }
+        }
+        self.in_commands = false;
+        ok && continues
+    }
+
+    /// Runs to the instruction after the next one, running any call it makes until
+    /// it returns
+    fn step_over(&mut self) -> Result<bool> {
+        let call = match self.pending_call()? {
+            Some((ip, op_code::DecodedOpCode::Call { .. })) => Some(Addr::from(u16::from(ip) + 2)),
+            _ => None,
+        };
+        match call {
+            Some(ret) => {
+                let depth = self.depth();
+                self.run(Some(Until::Depth(depth, Some(ret))))
+            }
+            None => self.run(Some(Until::Steps(1))),
+        }
+    }
+
+    /// Runs until the function executing returns, showing what it returned
+    fn finish(&mut self) -> Result<()> {
+        let depth = self.depth();
+        if depth == 0 {
+            bail!("not in a called function");
+        }
+        let f = self.current_function();
+        if self.run(Some(Until::Depth(depth - 1, None)))? {
+            let returns = match self.signature(f) {
+                Some(ref sig) if !sig.returns.is_empty() => self.reg_values(&sig.returns.regs()),
+                _ => self.reg_values(&[0]),
+            };
+            println!("{} returned {}", self.symbols.describe(f), returns);
+        }
+        Ok(())
     }
 
     fn triggered_breakpoint(&mut self) -> Result<Option<breakpoint::Reason>> {
-        Ok(match &mut self.state {
-            &mut VmState::Running(ref mut m) => {
-                let (op, decoded_op) = m.peek_instr()?;
-                if let Some(ip) = m.ip() {
-                    self.breakpoints
-                        .iter()
-                        .find(|bp| bp.is_triggered(&ip, &op, &decoded_op))
-                        .map(breakpoint::Reason::Triggered)
-                } else {
-                    None
+        let mut reached = Vec::new();
+        if let Some(ref change) = self.last_change {
+            reached.extend(self.breakpoints
+                .iter()
+                .filter(|&(_, bp)| bp.enabled && bp.kind.is_changed(change))
+                .map(|(&n, _)| n));
+        }
+        if self.last_output.is_some() {
+            let start = self.recent_output.len().saturating_sub(OUTPUT_WINDOW);
+            let output = &self.recent_output[start..];
+            reached.extend(self.breakpoints
+                .iter()
+                .filter(|&(_, bp)| bp.enabled && bp.kind.is_printed(output))
+                .map(|(&n, _)| n));
+        }
+        let function = self.current_function();
+        if let VmState::Running(ref mut m) = self.state {
+            let (op, decoded_op) = m.peek_instr()?;
+            if let Some(ip) = m.ip() {
+                let next = breakpoint::Next {
+                    ip: ip,
+                    op: &op,
+                    decoded: &decoded_op,
+                    function: function,
+                    has_input: m.has_input(),
+                };
+                reached.extend(self.breakpoints
+                    .iter()
+                    .filter(|&(_, bp)| bp.enabled && bp.kind.is_triggered(&next))
+                    .map(|(&n, _)| n));
+            }
+        }
+        let mut stop = None;
+        for n in reached {
+            let holds = match self.breakpoints[&n].condition {
+                Some(ref c) => {
+                    c.eval(&self.context()).unwrap_or_else(|e| {
+                        println!("error evaluating condition of breakpoint {}: {}", n, e);
+                        1
+                    }) != 0
                 }
+                None => true,
+            };
+            let bp = self.breakpoints.get_mut(&n).unwrap();
+            if !holds {
+                continue;
             }
-            &mut VmState::Stalled(_) => Some(breakpoint::Reason::Stalled),
-            &mut VmState::Halted(_) => Some(breakpoint::Reason::Halted),
+            bp.hits += 1;
+            if bp.ignore > 0 {
+                bp.ignore -= 1;
+            } else if stop.is_none() {
+                stop = Some(n);
+            }
+        }
+        if let Some(n) = stop {
+            let bp = &self.breakpoints[&n];
+            return Ok(Some(match (&bp.kind, self.last_change.as_ref(), self.last_output) {
+                (&breakpoint::Kind::Change(..), Some(change), _) => {
+                    breakpoint::Reason::Changed(n, bp, change)
+                }
+                (&breakpoint::Kind::Output(_), _, Some(ip)) => breakpoint::Reason::Printed(n, bp, ip),
+                _ => breakpoint::Reason::Triggered(n, bp),
+            }));
+        }
+        Ok(match self.state {
+            VmState::Running(_) => None,
+            VmState::Stalled(_) => Some(breakpoint::Reason::Stalled),
+            VmState::Halted(_) |
+            VmState::Stepping => Some(breakpoint::Reason::Halted),
         })
     }
 
@@ -177,44 +520,307 @@ impl Debugger {
         Ok(())
     }
 
-    fn add_breakpoint(&mut self, op: &str, loc: &str) -> Result<()> {
-        let bp = match op {
-            "r" => breakpoint::Breakpoint::read(loc),
-            "w" => breakpoint::Breakpoint::write(loc),
-            "a" => breakpoint::Breakpoint::access(loc),
-            "@" => breakpoint::Breakpoint::at(loc),
+    /// Runs the print routines at `routines` in a fork of the VM for every
+    /// constant string they are called with
+    fn decrypt_strings(&self, routines: &[&str]) -> Result<()> {
+        let mut entries = vec![Addr::from(0u16)];
+        entries.extend(self.calls.callees());
+        let disasm = self.disassemble(&entries);
+        let cfg = Cfg::new(&disasm);
+        let sigs = Signatures::new(&disasm, &cfg);
+        let mut addrs = Vec::new();
+        for r in routines {
+            addrs.push(self.eval_addr(r)?);
+        }
+        for s in strings::decrypt(self.state.as_ref(), &disasm, &cfg, &sigs, &addrs) {
+            println!("{}", s);
+        }
+        Ok(())
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            vm: self.state.as_ref(),
+            symbols: &self.symbols,
+            vars: &self.vars,
+        }
+    }
+
+    fn eval(&self, e: &str) -> Result<u16> {
+        expr::Expr::from_str(e).and_then(|e| e.eval(&self.context()))
+    }
+
+    fn eval_addr(&self, e: &str) -> Result<Addr> {
+        expr::Expr::from_str(e).and_then(|e| e.eval_addr(&self.context()))
+    }
+
+    fn eval_target(&self, e: &str) -> Result<memory::Target> {
+        expr::Expr::from_str(e).and_then(|e| e.eval_target(&self.context()))
+    }
+
+    /// Parses `start..end`, where either bound is an expression and may be omitted
+    fn eval_range(&self, r: &str) -> Result<memory::AddrRange> {
+        let bound = |b: &str| if b.trim().is_empty() {
+            Ok(None)
+        } else {
+            self.eval_addr(b).map(Some)
+        };
+        if let Some(i) = r.find("..") {
+            Ok(memory::AddrRange::new(bound(&r[..i])?, bound(&r[(i + 2)..])?))
+        } else {
+            let a = self.eval_addr(r)?;
+            Ok(memory::AddrRange::new(Some(a), Some(a)))
+        }
+    }
+
+    /// Parses the locations for a watchpoint; either a comma separated list of
+    /// registers ('r' for all of them), or an address range
+    fn eval_watched(&self, loc: &str) -> Result<breakpoint::Watched> {
+        if "r" == loc {
+            return (0..8u8)
+                .map(memory::Register::try_from)
+                .collect::<Result<Vec<_>>>()
+                .map(breakpoint::Watched::Regs);
+        }
+        let regs = loc.split(',').map(expr::Expr::from_str).collect::<Result<Vec<_>>>();
+        match regs {
+            Ok(ref regs) if regs.iter().all(|r| match *r {
+                expr::Expr::Reg(_) => true,
+                _ => false,
+            }) => {
+                regs.iter()
+                    .map(|r| r.eval_register(&self.context()))
+                    .collect::<Result<Vec<_>>>()
+                    .map(breakpoint::Watched::Regs)
+            }
+            _ => self.eval_range(loc).map(breakpoint::Watched::Mem),
+        }
+    }
+
+    /// Adds a breakpoint, returning its number; any `if expr` at the end of `args`
+    /// makes it conditional
+    fn add_breakpoint(&mut self, op: &str, args: &[&str], temporary: bool) -> Result<usize> {
+        let (args, condition) = match args.iter().position(|&a| a == "if") {
+            Some(i) => (&args[..i], Some(expr::Expr::from_str(&args[i + 1..].join(" "))?)),
+            None => (args, None),
+        };
+        if op == "input" {
+            if !args.is_empty() {
+                bail!("unexpected '{}'; conditions follow 'if'", args.join(" "));
+            }
+            return Ok(self.insert_breakpoint(breakpoint::Kind::Input, condition, temporary));
+        }
+        let (loc, args) = match args.split_first() {
+            Some((loc, args)) => (*loc, args),
+            None => bail!("must specify loc"),
+        };
+        let kind = match op {
+            "r" => breakpoint::Kind::Read(self.eval_target(loc)?),
+            "w" => breakpoint::Kind::Write(self.eval_target(loc)?),
+            "a" => breakpoint::Kind::Access(self.eval_target(loc)?),
+            "@" => breakpoint::Kind::at(self.eval_target(loc)?)?,
+            "op" => breakpoint::Kind::Op(breakpoint::OpClass::new(loc)?),
+            "call" => breakpoint::Kind::Call(self.eval_addr(loc)?),
+            "ret" => breakpoint::Kind::Return(self.eval_addr(loc)?),
+            "o" => {
+                let text = std::iter::once(loc).chain(args.iter().cloned()).collect::<Vec<_>>();
+                breakpoint::Kind::Output(breakpoint::Pattern::new(&text.join(" "))?)
+            }
+            "c" => {
+                let cond = match args.len() {
+                    0 => None,
+                    2 => Some(breakpoint::Condition::new(args[0], self.eval(args[1])?)?),
+                    _ => bail!("watch condition must be one of 'to <val>' or 'crosses <val>'"),
+                };
+                breakpoint::Kind::Change(self.eval_watched(loc)?, cond)
+            }
             o => bail!("unknown breakpoint op {}", o),
+        };
+        if op != "c" && op != "o" && !args.is_empty() {
+            bail!("unexpected '{}'; conditions follow 'if'", args.join(" "));
+        }
+        Ok(self.insert_breakpoint(kind, condition, temporary))
+    }
+
+    fn insert_breakpoint(&mut self,
+                         kind: breakpoint::Kind,
+                         condition: Option<expr::Expr>,
+                         temporary: bool)
+                         -> usize {
+        let mut bp = breakpoint::Breakpoint::new(kind);
+        bp.condition = condition;
+        bp.temporary = temporary;
+        let n = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.breakpoints.insert(n, bp);
+        n
+    }
+
+    /// Calls made and not yet returned from
+    fn depth(&self) -> usize {
+        self.state.as_ref().frames().len()
+    }
+
+    /// Entry of the function executing
+    fn current_function(&self) -> Addr {
+        self.state.as_ref().frames().last().map(|f| f.target).unwrap_or(self.calls.root())
+    }
+
+    /// Prints the calls leading to `ip`, innermost first, with where on the stack
+    /// each call's return address is
+    fn backtrace(&self, ip: Addr) {
+        // each frame is where execution is, the function it is in, and for calls
+        // out of that function, where the return address is on the stack
+        let mut trace = Vec::new();
+        let mut at = (ip, None);
+        for f in self.state.as_ref().frames().iter().rev() {
+            trace.push((at.0, f.target, at.1));
+            at = (f.site, Some(f.depth));
+        }
+        trace.push((at.0, self.calls.root(), at.1));
+        for (i, &(at, function, depth)) in trace.iter().enumerate() {
+            let function = self.symbols.locate(function).unwrap_or_else(|| format!("{:?}", function));
+            let mut line = format!("#{:<2} {} in {}", i, self.symbols.describe(at), function);
+            if let Some(d) = depth {
+                line.push_str(&format!(" (return address at stack depth {})", d));
+            }
+            println!("{}", line);
+        }
+    }
 
-        }?;
-        self.breakpoints.push(bp);
+    /// Prints the backtrace from the ip, and any returns not matching the calls made
+    fn show_backtrace(&self) -> Result<()> {
+        let ip = match self.state.as_ref().ip() {
+            Some(ip) => ip,
+            None => bail!("machine halted"),
+        };
+        self.backtrace(ip);
+        let anomalies = self.state.as_ref().anomalies();
+        if !anomalies.is_empty() {
+            println!("anomalous returns, most recent last:");
+            for a in anomalies {
+                println!("  {}", a);
+            }
+        }
         Ok(())
     }
 
     fn list_breakpoints(&self) {
-        for i in 0..self.breakpoints.len() {
-            println!("{}: {}", i, self.breakpoints[i])
+        if self.breakpoints.is_empty() {
+            println!("no breakpoints");
+        }
+        for (n, bp) in &self.breakpoints {
+            let mut line = format!("{}: {}", n, bp);
+            if let Some(l) = bp.kind.addr().and_then(|a| self.symbols.locate(a)) {
+                line.push_str(&format!(" <{}>", l));
+            }
+            if bp.hits > 0 {
+                line.push_str(&format!(", hit {} time{}", bp.hits, if bp.hits == 1 { "" } else { "s" }));
+            }
+            if bp.ignore > 0 {
+                line.push_str(&format!(", ignoring the next {}", bp.ignore));
+            }
+            if bp.temporary {
+                line.push_str(", temporary");
+            }
+            if !bp.enabled {
+                line.push_str(", disabled");
+            }
+            println!("{}", line);
+            if !bp.commands.is_empty() || bp.continues {
+                let mut commands = bp.commands.iter().map(|c| command::quote(c)).collect::<Vec<_>>();
+                if bp.continues {
+                    commands.push("continue".to_owned());
+                }
+                println!("    then {}", commands.join("; "));
+            }
+        }
+    }
+
+    /// The breakpoints numbered in `ns`, where `*` means all of them
+    fn breakpoint_numbers(&self, ns: &[&str]) -> Result<Vec<usize>> {
+        if ns.is_empty() {
+            bail!("must specify breakpoints (\"*\" for all)");
+        }
+        if ns.contains(&"*") {
+            return Ok(self.breakpoints.keys().cloned().collect());
+        }
+        ns.iter()
+            .map(|n| {
+                let n = usize::from_str(n.trim()).chain_err(|| format!("invalid breakpoint {}", n))?;
+                if !self.breakpoints.contains_key(&n) {
+                    bail!("no such breakpoint {}", n);
+                }
+                Ok(n)
+            })
+            .collect()
+    }
+
+    fn delete_breakpoints(&mut self, ns: &[&str]) -> Result<()> {
+        for n in self.breakpoint_numbers(ns)? {
+            self.breakpoints.remove(&n);
+        }
+        Ok(())
+    }
+
+    fn enable_breakpoints(&mut self, ns: &[&str], enabled: bool) -> Result<()> {
+        for n in self.breakpoint_numbers(ns)? {
+            self.breakpoints.get_mut(&n).unwrap().enabled = enabled;
         }
+        Ok(())
     }
 
-    fn delete_breakpoint(&mut self, n: &str) -> Result<()> {
-        let n = n.trim();
-        if "*" == n {
-            self.breakpoints.clear();
+    /// Makes breakpoint `n` conditional on `e`, or unconditional if `e` is empty
+    fn set_condition(&mut self, n: &str, e: &str) -> Result<()> {
+        let n = self.breakpoint_numbers(&[n])?[0];
+        let condition = if e.trim().is_empty() {
+            None
         } else {
-            let n = usize::try_from(n)?;
-            if n >= self.breakpoints.len() {
-                bail!("no such breakpoint {}", n);
-            } else {
-                self.breakpoints.remove(n);
+            Some(expr::Expr::from_str(e)?)
+        };
+        self.breakpoints.get_mut(&n).unwrap().condition = condition;
+        Ok(())
+    }
+
+    /// Attaches `commands`, `;` separated, to breakpoint `n`, replacing any it
+    /// had; a final `continue` carries on running after the others have run, and
+    /// no other command may resume execution
+    fn set_commands(&mut self, n: &str, commands: &str) -> Result<()> {
+        let mut commands = command::parse(commands)?;
+        let continues = match commands.last() {
+            Some(c) => command::find(&c[0]).map(|c| c.name == "continue").unwrap_or(false),
+            None => false,
+        };
+        if continues {
+            commands.pop();
+        }
+        for c in &commands {
+            match command::find(&c[0]) {
+                Some(cmd) if command::RESUMING.contains(&cmd.name) => {
+                    bail!("'{}' would resume execution; only a final 'continue' can", c[0])
+                }
+                Some(_) => {}
+                None => bail!("unrecognized command '{}'", c[0]),
             }
         }
+        let n = self.breakpoint_numbers(&[n])?[0];
+        let bp = self.breakpoints.get_mut(&n).unwrap();
+        bp.commands = commands;
+        bp.continues = continues;
+        Ok(())
+    }
+
+    fn set_ignore(&mut self, n: &str, count: &str) -> Result<()> {
+        let n = self.breakpoint_numbers(&[n])?[0];
+        let count = u32::from_str(count).chain_err(|| format!("invalid count {}", count))?;
+        self.breakpoints.get_mut(&n).unwrap().ignore = count;
         Ok(())
     }
 
     fn examine_mem(&self, addrs: &str) -> Result<()> {
         const WIDTH: usize = 16;
+        let range = self.eval_range(addrs)?;
         let mem = self.state.as_ref().memory();
-        let range = memory::AddrRange::try_from(addrs)?;
         let mem = mem.get_range(&range).chunks(WIDTH);
         let mut s = range.start();
         for row in mem {
@@ -246,31 +852,73 @@ impl Debugger {
             .into_iter()
             .map(|r| format!("{}", r))
             .collect::<Vec<_>>();
-        let m = self.state.as_mut();
-        if let Some(ip) = m.ip() {
-            m.peek_instr().map(|(r, d)| match r {
-                op_code::OpCode::Call { addr } => {
-                    let regs = regs.join(",");
-                    format!("{:?}: {} | {} <- {}({})", ip, r, d, addr, regs)
+        let ip = match self.state.as_ref().ip() {
+            Some(ip) => ip,
+            None => return Ok(format!("MACHINE HALTED")),
+        };
+        let (r, d) = self.state.as_mut().peek_instr()?;
+        let mut line = format!("{}: {} | {}", self.symbols.describe(ip), self.instr_text(&r), d);
+        match (r, d) {
+            (op_code::OpCode::Call { .. }, op_code::DecodedOpCode::Call { addr }) => {
+                let target = self.symbols.locate(addr).unwrap_or_else(|| format!("{:?}", addr));
+                let args = match self.signature(addr) {
+                    Some(sig) => self.reg_values(&sig.args.regs()),
+                    None => regs.join(","),
+                };
+                line.push_str(&format!(" <- {}({})", target, args));
+            }
+            (op_code::OpCode::Ret { .. }, _) => {
+                let f = self.current_function();
+                match self.signature(f) {
+                    Some(ref sig) if !sig.returns.is_empty() => {
+                        let returns = self.reg_values(&sig.returns.regs());
+                        line.push_str(&format!(" -> {}", returns))
+                    }
+                    Some(_) => {}
+                    None => line.push_str(&format!(" -> {}", regs[0])),
                 }
-                op_code::OpCode::Ret { .. } => format!("{:?}: {} | {} -> {}", ip, r, d, regs[0]),
-                _ => format!("{:?}: {} | {}", ip, r, d),
-            })
-        } else {
-            Ok(format!("MACHINE HALTED"))
+            }
+            _ => {}
+        }
+        if let Some(c) = self.symbols.comment(ip) {
+            line.push_str(&format!("  ; {}", c));
         }
+        Ok(line)
+    }
+    /// `r0=0x0001, r1=0x05fb` for the given registers
+    fn reg_values(&self, regs: &[usize]) -> String {
+        let values = self.state.as_ref().registers().into_iter().collect::<Vec<_>>();
+        regs.iter()
+            .map(|&r| format!("r{}=0x{:04x}", r, values[r]))
+            .collect::<Vec<_>>()
+            .join(", ")
     }
-    fn set_output(&mut self, sink: Option<&str>) -> Result<()> {
-        self.output = match sink {
-            Some("-") => {
+
+    /// The instruction as `OpCode` displays it, with named addresses replaced by
+    /// their names
+    fn instr_text(&self, op: &op_code::OpCode) -> String {
+        disasm::instr_text(op, |_, a| self.symbols.name(a).map(|n| n.to_owned()))
+    }
+
+    fn set_output(&mut self, sink: Option<&str>, file: Option<&str>) -> Result<()> {
+        self.output = match (sink, file) {
+            (Some(f @ "bin"), Some(file)) |
+            (Some(f @ "json"), Some(file)) => {
+                println!("tracing instructions to {}", file);
+                let w = BufWriter::new(File::create(file)?);
+                Some(Sink::Trace(trace::Writer::new(w, trace::Format::from_str(f)?)?))
+            }
+            (Some("bin"), None) |
+            (Some("json"), None) => bail!("must specify trace file"),
+            (Some("-"), _) => {
                 println!("logging instructions to stdout");
                 Some(Sink::StdOut)
             }
-            Some(f) => {
+            (Some(f), _) => {
                 println!("logging instructions to {}", f);
                 Some(Sink::File(File::create(f)?))
             }
-            None => {
+            (None, _) => {
                 println!("instruction logging disabled");
                 None
             }
@@ -289,7 +937,8 @@ impl Debugger {
         let (vm_state, reg) = match self.state {
             VmState::Stalled(ref m) => (0, m.reg_u8()),
             VmState::Running(_) => (1, 0),
-            VmState::Halted(_) => (2, 0),
+            VmState::Halted(_) |
+            VmState::Stepping => (2, 0),
         };
         debug!("vm_state: {}, reg: {}", vm_state, reg);
         file.write_u8(vm_state)?;
@@ -307,6 +956,7 @@ impl Debugger {
         debug!("read {} save file bytes", bytes_read);
         debug!("vm_state: {}, reg: {}", data[0], data[1]);
         let machine = Machine::try_from(&data[2..])?;
+        let heat_map = self.state.as_mut().set_heat_map(None);
         self.state = match data[0] {
             0 => {
                 VmState::Stalled(StalledMachine::new(machine, memory::Register::try_from(data[1])?))
@@ -315,245 +965,657 @@ impl Debugger {
             2 => VmState::Halted(HaltedMachine::new(machine)),
             v => bail!("unknown VmState {}", v),
         };
+        self.state.as_mut().set_heat_map(heat_map);
+        let ip = self.state.as_ref().ip().unwrap_or(Addr::from(0u16));
+        self.calls.reset(ip);
+        self.stalled_record = None;
+        self.recent_output.clear();
+        self.signatures = None;
+        self.reapply_patches();
+        Ok(())
+    }
+
+    /// Applies the patches in `file`, keeping them to list, revert and apply to
+    /// any save file loaded later
+    fn load_patches<P: AsRef<Path>>(&mut self, file: P) -> Result<()> {
+        for p in patch::load(file)? {
+            let original = match p.apply(self.state.as_mut().memory_mut()) {
+                Ok(o) => {
+                    println!("applied patch {}", p.name);
+                    Some(o)
+                }
+                Err(e) => {
+                    println!("not applying {}", e);
+                    None
+                }
+            };
+            self.patches.push(LoadedPatch {
+                patch: p,
+                original: original,
+            });
+        }
+        self.signatures = None;
+        Ok(())
+    }
+
+    /// Applies the patches applied before a save file replaced memory
+    fn reapply_patches(&mut self) {
+        for p in &mut self.patches {
+            if p.original.is_some() {
+                p.original = match p.patch.apply(self.state.as_mut().memory_mut()) {
+                    Ok(o) => Some(o),
+                    Err(e) => {
+                        println!("not reapplying {}", e);
+                        None
+                    }
+                };
+            }
+        }
+    }
+
+    fn patch(&mut self, cmd: Option<&str>, arg: Option<&str>) -> Result<()> {
+        match (cmd, arg) {
+            (None, _) => {
+                for (i, p) in self.patches.iter().enumerate() {
+                    let state = if p.original.is_some() {
+                        "applied"
+                    } else {
+                        "not applied"
+                    };
+                    println!("{}: {} ({})", i, p.patch.name, state);
+                    for e in &p.patch.expect {
+                        println!("    expect {}", e);
+                    }
+                    for e in &p.patch.edits {
+                        println!("    {}", e);
+                    }
+                }
+            }
+            (Some("load"), Some(file)) => self.load_patches(file)?,
+            (Some("load"), None) => bail!("must specify patch file"),
+            (Some(cmd), Some(n)) if cmd == "apply" || cmd == "revert" => {
+                let which = if "*" == n {
+                    (0..self.patches.len()).collect()
+                } else {
+                    let n = usize::from_str(n)?;
+                    if n >= self.patches.len() {
+                        bail!("no such patch {}", n);
+                    }
+                    vec![n]
+                };
+                for i in which {
+                    let p = &mut self.patches[i];
+                    let mem = self.state.as_mut().memory_mut();
+                    match (cmd, p.original.take()) {
+                        ("apply", None) => p.original = Some(p.patch.apply(mem)?),
+                        ("revert", Some(o)) => patch::revert(mem, &o),
+                        (_, o) => p.original = o,
+                    }
+                }
+                self.signatures = None;
+            }
+            (Some(cmd), None) if cmd == "apply" || cmd == "revert" => {
+                bail!("must specify patch to {} (\"*\" for all)", cmd)
+            }
+            (Some(c), _) => bail!("unknown patch command {}", c),
+        }
+        Ok(())
+    }
+
+    /// The inferred signature of the function at `f`; memory is disassembled
+    /// again, also from `f`, when `f` has not been seen before
+    fn signature(&mut self, f: Addr) -> Option<Signature> {
+        let stale = match self.signatures {
+            Some(ref c) => c.sigs.get(f).is_none() && !c.entries.contains(&f),
+            None => true,
+        };
+        if stale {
+            let mut entries = vec![Addr::from(0u16), f];
+            entries.extend(self.calls.callees());
+            if let Some(ref c) = self.signatures {
+                entries.extend(c.entries.iter().cloned());
+            }
+            let disasm = self.disassemble(&entries);
+            let sigs = Signatures::new(&disasm, &Cfg::new(&disasm));
+            self.signatures = Some(SignatureCache {
+                entries: entries,
+                sigs: sigs,
+            });
+        }
+        self.signatures.as_ref().and_then(|c| c.sigs.get(f).cloned())
+    }
+
+    /// Disassembles the current memory from `entries` and any functions named in
+    /// the symbols
+    fn disassemble(&self, entries: &[Addr]) -> Disassembly {
+        let mut entries = entries.to_vec();
+        entries.extend(self.symbols
+            .iter()
+            .filter(|&(_, s)| s.kind == symbols::Kind::Function)
+            .map(|(a, _)| a));
+        let mut disasm = Disassembly::new(self.state.as_ref().memory(), Mode::Recursive, &entries);
+        disasm.set_symbols(self.symbols.clone());
+        disasm
+    }
+
+    /// Runs a copy of the current machine alongside a copy of itself or of a save
+    /// file, with any pokes applied to the latter, until the two diverge
+    fn lockstep(&self, args: &[&str]) -> Result<()> {
+        let (mut b, pokes) = match args.first() {
+            Some(&"-s") => {
+                match args.get(1) {
+                    Some(f) => (Machine::from_save(f)?, &args[2..]),
+                    None => bail!("must specify save file"),
+                }
+            }
+            _ => (self.state.as_ref().fork(), args),
+        };
+        for p in pokes {
+            lockstep::Poke::from_str(p)?.apply(&mut b);
+        }
+        let report = lockstep::run(self.state.as_ref().fork(), b, "", LOCKSTEP_STEPS, 8)?;
+        println!("{}", report);
+        Ok(())
+    }
+
+    /// Lists the instructions referring to an address or register, searching the
+    /// code reachable from the entry point, the functions called so far and the
+    /// current ip, or with `-l`, every word decoded in turn
+    fn xrefs(&self, args: &[&str]) -> Result<()> {
+        let (linear, e) = match args.first() {
+            Some(&"-l") => (true, args[1..].join(" ")),
+            _ => (false, args.join(" ")),
+        };
+        if e.is_empty() {
+            bail!("must specify address or register");
+        }
+        let tgt = self.eval_target(&e)?;
+        let disasm = if linear {
+            let mut disasm = Disassembly::new(self.state.as_ref().memory(), Mode::Linear, &[]);
+            disasm.set_symbols(self.symbols.clone());
+            disasm
+        } else {
+            let mut entries = vec![Addr::from(0u16)];
+            entries.extend(self.state.as_ref().ip());
+            entries.extend(self.calls.callees());
+            self.disassemble(&entries)
+        };
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        xref::write_xrefs(&disasm, &Cfg::new(&disasm), &xref::to_target(&disasm, tgt), &mut out)
+    }
+
+    /// Shows the call graph as a tree from root (by default the program entry), or
+    /// writes it to a DOT file; calls observed while running are included
+    fn call_graph(&self, cmd: Option<&str>, file: Option<&str>) -> Result<()> {
+        let mut entries = vec![Addr::from(0u16)];
+        entries.extend(self.calls.callees());
+        let disasm = self.disassemble(&entries);
+        let graph = CallGraph::new(&Cfg::new(&disasm), Some(&self.calls));
+        match (cmd, file) {
+            (Some("dot"), Some(file)) => {
+                graph.write_dot(&disasm, &mut BufWriter::new(File::create(file)?))
+            }
+            (Some("dot"), None) => bail!("must specify output file"),
+            (root, _) => {
+                let root = match root {
+                    Some(r) => self.eval_addr(r)?,
+                    None => Addr::from(0u16),
+                };
+                let stdout = std::io::stdout();
+                let mut out = stdout.lock();
+                graph.write_tree(&disasm, root, &mut out)
+            }
+        }
+    }
+
+    fn heat_map(&mut self, cmd: Option<&str>, file: Option<&str>) -> Result<()> {
+        match (cmd, file) {
+            (Some("on"), _) => {
+                if self.state.as_ref().heat_map().is_none() {
+                    self.state.as_mut().set_heat_map(Some(HeatMap::new()));
+                }
+                println!("recording memory access heat map");
+            }
+            (Some("off"), _) => {
+                self.state.as_mut().set_heat_map(None);
+                println!("memory access heat map disabled");
+            }
+            (Some("reset"), _) => {
+                self.state.as_mut().set_heat_map(Some(HeatMap::new()));
+                println!("memory access heat map reset");
+            }
+            (Some(fmt), Some(file)) => {
+                let heat_map = self.state
+                    .as_ref()
+                    .heat_map()
+                    .ok_or("heat map is not being recorded, see 'hm on'")?;
+                let mut f = BufWriter::new(File::create(file)?);
+                match fmt {
+                    "csv" => heat_map.write_csv(&mut f)?,
+                    "ppm" => heat_map.write_ppm(&mut f)?,
+                    "png" => heat_map.write_png(&mut f)?,
+                    f => bail!("unknown heat map format {}", f),
+                }
+            }
+            (Some(_), None) => bail!("must specify output file"),
+            (None, _) => {
+                if self.state.as_ref().heat_map().is_some() {
+                    println!("recording memory access heat map");
+                } else {
+                    println!("memory access heat map disabled");
+                }
+            }
+        }
         Ok(())
     }
 
     fn write_reg(&mut self, n: &str, v: &str) -> Result<()> {
-        let r = memory::Register::from_str(n)?;
-        let v = memory::Value::from_str(v)?;
+        let (r, v) = {
+            let ctx = self.context();
+            let r = expr::Expr::from_str(n)?.eval_register(&ctx)?;
+            let v = expr::Expr::from_str(v)?.eval_value(&ctx)?;
+            (r, v)
+        };
         self.state.as_mut().write_reg(r, v);
         Ok(())
     }
 
+    fn print_expr(&mut self, e: &str) -> Result<()> {
+        let v = self.eval(e)?;
+        println!("{} = 0x{:04x} {} {:?}", e, v, v, memory::Value::try_from(v));
+        self.vars.insert("_".to_owned(), v);
+        Ok(())
+    }
+
+    fn set_var(&mut self, name: &str, e: &str) -> Result<()> {
+        if !name.starts_with("$") || name.len() < 2 {
+            bail!("convenience variable names must begin with '$'");
+        }
+        let name = &name[1..];
+        if BUILTIN_VARS.contains(&name) || name.starts_with("s") && usize::from_str(&name[1..]).is_ok() {
+            bail!("${} is read-only", name);
+        }
+        let v = self.eval(e)?;
+        self.vars.insert(name.to_owned(), v);
+        Ok(())
+    }
+
+    fn set_symbol(&mut self, args: &[&str]) -> Result<()> {
+        match args.first().cloned() {
+            None => {
+                for (addr, sym) in self.symbols.iter() {
+                    println!("{:<5} {}: {}", sym.kind, sym.name, addr);
+                }
+                for (addr, c) in self.symbols.comments() {
+                    println!("{}: ; {}", self.symbols.describe(addr), c);
+                }
+            }
+            Some(cmd) if cmd == "load" || cmd == "save" => {
+                let path = match args.get(1) {
+                    Some(f) => PathBuf::from(f),
+                    None => {
+                        match self.symbols_path.clone() {
+                            Some(p) => p,
+                            None => bail!("must specify symbol file"),
+                        }
+                    }
+                };
+                if cmd == "load" {
+                    self.symbols = Symbols::load(&path)?;
+                    println!("loaded symbols from {}", path.display());
+                } else {
+                    self.symbols.save(&path)?;
+                    println!("saved symbols to {}", path.display());
+                }
+                self.symbols_path = Some(path);
+            }
+            Some("rm") => {
+                if args.len() < 2 {
+                    bail!("must specify symbol to remove");
+                }
+                for name in &args[1..] {
+                    if self.symbols.remove(name).is_none() {
+                        bail!("unknown symbol '{}'", name);
+                    }
+                }
+            }
+            Some(kind) if kind == "fn" || kind == "label" || kind == "var" => {
+                if args.len() < 3 {
+                    bail!("must specify name and address");
+                }
+                let addr = self.eval_addr(&args[2..].join(" "))?;
+                self.symbols.define(args[1], addr, symbols::Kind::from_str(kind)?)?;
+            }
+            Some(name) if args.len() == 1 => {
+                match self.symbols.get_by_name(name) {
+                    Some((addr, sym)) => println!("{:<5} {}: {}", sym.kind, name, addr),
+                    None => bail!("unknown symbol '{}'", name),
+                }
+            }
+            Some(name) => {
+                let addr = self.eval_addr(&args[1..].join(" "))?;
+                self.symbols.define(name, addr, symbols::Kind::Label)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_comment(&mut self, addr: &str, text: &str) -> Result<()> {
+        let addr = self.eval_addr(addr)?;
+        self.symbols.set_comment(addr, text);
+        Ok(())
+    }
+
     fn show_stack(&self, n: Option<&str>) -> Result<()> {
+        let n = match n {
+            Some(n) => Some(usize::from_str(n)?),
+            None => None,
+        };
+        for line in self.stack_lines(n)? {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    /// The top `n` stack entries, or all of them
+    fn stack_lines(&self, n: Option<usize>) -> Result<Vec<String>> {
         let stack = self.state.as_ref().stack();
         let stack_len = stack.len();
         let n = match n {
-            Some(n) => {
-                let n = usize::from_str(n)?;
-                if n > stack_len { stack_len } else { n }
-            }
-            None => stack_len,
+            Some(n) if n < stack_len => n,
+            _ => stack_len,
         };
+        let frames = self.state.as_ref().frames();
+        let mut lines = Vec::new();
         for (i, v) in stack.iter().rev().take(n).enumerate() {
+            let loc = match memory::Value::try_from(*v)? {
+                memory::Value::Literal(a) => self.symbols.locate(Addr::from(a)),
+                memory::Value::FromRegister(_) => None,
+            };
             let v = memory::Value::try_from(*v)?;
-            println!("{:04}: {} {:?}", i, v, v);
+            let mut line = match loc {
+                Some(l) => format!("{:04}: {} {:?} <{}>", i, v, v, l),
+                None => format!("{:04}: {} {:?}", i, v, v),
+            };
+            if let Some(f) = frames.iter().find(|f| f.depth == stack_len - i) {
+                line.push_str(&format!("  (return address of the call at {:?})", f.site));
+            }
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    /// Adds an auto-display: `/i` for the instruction at the ip, `/s [n]` for the
+    /// top n (4 if unspecified) stack entries, or else an expression
+    fn add_display(&mut self, args: &[&str]) -> Result<usize> {
+        let kind = match args.first().cloned() {
+            Some("/i") if args.len() == 1 => display::Kind::Instr,
+            Some("/s") if args.len() <= 2 => {
+                let n = match args.get(1) {
+                    Some(n) => usize::from_str(n).chain_err(|| format!("invalid stack entry count: {}", n))?,
+                    None => 4,
+                };
+                display::Kind::Stack(n)
+            }
+            Some(_) => {
+                let e = args.join(" ");
+                let parsed = expr::Expr::from_str(&e)?;
+                display::Kind::Expr(e, parsed)
+            }
+            None => bail!("must specify what to display"),
+        };
+        let n = self.next_display;
+        self.next_display += 1;
+        self.displays.insert(n, display::AutoDisplay::new(kind));
+        Ok(n)
+    }
+
+    fn delete_displays(&mut self, ns: &[&str]) -> Result<()> {
+        if ns.is_empty() {
+            bail!("must specify displays (\"*\" for all)");
+        }
+        if ns.contains(&"*") {
+            self.displays.clear();
+            return Ok(());
+        }
+        for n in ns {
+            let n = usize::from_str(n).chain_err(|| format!("invalid display {}", n))?;
+            if self.displays.remove(&n).is_none() {
+                bail!("no such display {}", n);
+            }
         }
         Ok(())
     }
 
-    fn show_registers(&self, r: &str) -> Result<()> {
+    /// Shows every auto-display, marking what changed since they were last shown
+    fn show_displays(&mut self) {
+        let ns = self.displays.keys().cloned().collect::<Vec<_>>();
+        for n in ns {
+            let lines = match self.display_lines(n) {
+                Ok(lines) => lines,
+                Err(e) => vec![format!("<{}>", e)],
+            };
+            let d = self.displays.get_mut(&n).unwrap();
+            let marked = d.mark(lines);
+            let mark = |changed| if changed { "*" } else { " " };
+            match d.kind {
+                display::Kind::Expr(ref e, _) => {
+                    for (changed, l) in marked {
+                        println!("{} {}: {} = {}", mark(changed), n, e, l);
+                    }
+                }
+                _ => {
+                    println!("  {}: {}", n, d.kind);
+                    for (changed, l) in marked {
+                        println!("    {} {}", mark(changed), l);
+                    }
+                }
+            }
+        }
+    }
+
+    fn display_lines(&mut self, n: usize) -> Result<Vec<String>> {
+        let count = match self.displays[&n].kind {
+            display::Kind::Expr(_, ref e) => {
+                let v = e.eval(&self.context())?;
+                return Ok(vec![format!("0x{:04x} {}", v, v)]);
+            }
+            display::Kind::Stack(count) => Some(count),
+            display::Kind::Instr => None,
+        };
+        match count {
+            Some(count) => {
+                let lines = self.stack_lines(Some(count))?;
+                Ok(if lines.is_empty() { vec!["stack empty".to_owned()] } else { lines })
+            }
+            None => Ok(vec![self.curr_instr()?]),
+        }
+    }
+
+    fn show_registers(&self, r: Option<memory::Register>) -> Result<()> {
         let mut regs = self.state.as_ref().registers().into_iter();
-        if "r" == r {
-            let mut i = 0;
-            for r in regs {
+        if let Some(r) = r {
+            let i = usize::from(r);
+            if let Some(r) = regs.nth(i) {
                 print!("r{}: 0x{:04x} {} {:?}\n",
                        i,
                        r,
                        r,
                        memory::Value::try_from(r));
-                i += 1;
             }
         } else {
-            let i = usize::from(memory::Register::from_str(&r[1..])?);
-            if let Some(r) = regs.nth(i) {
+            let mut i = 0;
+            for r in regs {
                 print!("r{}: 0x{:04x} {} {:?}\n",
                        i,
                        r,
                        r,
                        memory::Value::try_from(r));
+                i += 1;
             }
         }
         Ok(())
     }
 }
 
-pub fn debug<P: AsRef<Path>>(rom_path: P) -> Result<()> {
+/// Where `Debugger::run` stops, other than at a breakpoint
+#[derive(Clone, Copy)]
+enum Until {
+    /// after the number of instructions
+    Steps(u64),
+    /// on reaching the address
+    Addr(Addr),
+    /// once returned to the given call depth, at the address if given, or
+    /// beyond; calls are tracked as they are made rather than from the stack,
+    /// which the ROM also uses for data
+    Depth(usize, Option<Addr>),
+}
+
+/// Characters of output kept for output breakpoints to match against
+const OUTPUT_WINDOW: usize = 1024;
+
+/// Instructions `lockstep` runs before giving up on finding a divergence
+const LOCKSTEP_STEPS: u64 = 10_000_000;
+
+/// Read-only variables derived from the VM state; `$s<n>` additionally refers to
+/// the n-th stack entry from the top
+const BUILTIN_VARS: [&'static str; 2] = ["ip", "sp"];
+
+/// Evaluation environment for expressions, over the current VM state
+struct Context<'a> {
+    vm: &'a Inspectable,
+    symbols: &'a Symbols,
+    vars: &'a HashMap<String, u16>,
+}
+
+impl<'a> expr::Env for Context<'a> {
+    fn register(&self, reg: memory::Register) -> u16 {
+        self.vm.registers().read(memory::Value::FromRegister(reg))
+    }
+
+    fn memory(&self, addr: Addr) -> u16 {
+        self.vm.memory().peek(addr)
+    }
+
+    fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.addr(name).map(u16::from)
+    }
+
+    fn variable(&self, name: &str) -> Option<u16> {
+        match name {
+            "ip" => self.vm.ip().map(u16::from),
+            "sp" => Some(self.vm.stack().len() as u16),
+            n if n.starts_with("s") && usize::from_str(&n[1..]).is_ok() => {
+                let i = usize::from_str(&n[1..]).unwrap_or(0);
+                self.vm.stack().iter().rev().nth(i).cloned()
+            }
+            n => self.vars.get(n).cloned(),
+        }
+    }
+}
+
+fn read_target(vm: &Inspectable, tgt: &memory::Target) -> u16 {
+    match *tgt {
+        memory::Target::Mem(addr) => vm.memory().peek(addr),
+        memory::Target::Reg(reg) => vm.registers().read(memory::Value::FromRegister(reg)),
+    }
+}
+
+/// Runs the debugger on the ROM at `rom_path`, along with the symbol file at
+/// `symbols_path` or, if none is given, any alongside the ROM with a `.sym` extension,
+/// applying the patches in `patch_paths` and then running the `startup` script
+pub fn debug<P: AsRef<Path>>(rom_path: P,
+                             symbols_path: Option<P>,
+                             patch_paths: &[P],
+                             startup: Option<P>)
+                             -> Result<()> {
+    let (symbols, symbols_path) = match symbols_path {
+        Some(p) => {
+            let symbols = Symbols::load(&p)
+                .chain_err(|| format!("unable to load symbols from {}", p.as_ref().display()))?;
+            (symbols, Some(p.as_ref().to_path_buf()))
+        }
+        None => {
+            let p = rom_path.as_ref().with_extension("sym");
+            if p.exists() {
+                let symbols = Symbols::load(&p)
+                    .chain_err(|| format!("unable to load symbols from {}", p.display()))?;
+                (symbols, Some(p))
+            } else {
+                (Symbols::new(), None)
+            }
+        }
+    };
     let mut input = String::new();
     let mut debugger = Debugger {
         state: VmState::Running(Machine::new(rom_path)?),
-        breakpoints: Vec::new(),
+        breakpoints: BTreeMap::new(),
+        next_breakpoint: 1,
         output: None,
+        last_change: None,
+        last_output: None,
+        recent_output: Vec::new(),
+        symbols: symbols,
+        symbols_path: symbols_path,
+        vars: HashMap::new(),
+        calls: Recorder::new(Addr::from(0u16)),
+        signatures: None,
+        patches: Vec::new(),
+        steps: 0,
+        stalled_record: None,
+        quit: false,
+        in_commands: false,
+        script_depth: 0,
+        displays: BTreeMap::new(),
+        next_display: 1,
     };
-    loop {
+    for p in patch_paths {
+        debugger.load_patches(p)
+            .chain_err(|| format!("unable to load patches from {}", p.as_ref().display()))?;
+    }
+    if let Some(p) = startup {
+        let path = p.as_ref().to_string_lossy().into_owned();
+        if let Err(e) = command::source(&mut debugger, &path, &[], false, false) {
+            command::report("source", &e);
+        }
+    }
+    // the last command, if an empty line should run it again
+    let mut last: Option<Vec<String>> = None;
+    // instructions run when displays were last shown
+    let mut steps = debugger.steps;
+    while !debugger.quit {
+        if debugger.steps != steps {
+            debugger.show_displays();
+        }
+        steps = debugger.steps;
         debugger.prompt();
         input.clear();
-        std::io::stdin().read_line(&mut input)?;
-        let mut parts = input.split_whitespace();
-        if let Some(cmd) = parts.next() {
-            match cmd {
-                "c" => {
-                    loop {
-                        debugger = debugger.step_vm()?;
-                        match debugger.triggered_breakpoint() {
-                            Ok(Some(r)) => {
-                                println!("breaking: {}", r);
-                                break;
-                            }
-                            Err(e) => println!("error testing breakpoint: {}", e),
-                            _ => {}
-                        }
-                    }
-                }
-                "s" => {
-                    let steps = if let Some(steps) = parts.next() {
-                        if let Ok(steps) = u64::from_str(steps) {
-                            steps
-                        } else {
-                            println!("invalid step count: {}", steps);
-                            continue;
-                        }
-                    } else {
-                        1
-                    };
-
-                    for _ in 0..steps {
-                        debugger = debugger.step_vm()?;
-                        match debugger.triggered_breakpoint() {
-                            Ok(Some(r)) => {
-                                println!("breaking: {}", r);
-                                break;
-                            }
-                            Err(e) => println!("error testing breakpoint: {}", e),
-                            _ => {}
-                        }
-                    }
-                }
-                "i" => {
-                    match debugger.curr_instr() {
-                        Ok(i) => println!("{}", i),
-                        Err(e) => println!("error displaying current instruction: {} ", e),
-                    }
-                }
-                "x" => {
-                    if let Some(loc) = parts.next() {
-                        let r = if "s" == loc {
-                            debugger.show_stack(parts.next())
-                        } else if loc.starts_with("r") {
-                            debugger.show_registers(loc)
-                        } else {
-                            debugger.examine_mem(loc)
-                        };
-                        if let Err(e) = r {
-                            println!("error examining memory: {}", e);
-                        }
-                    } else {
-                        println!("must specify location to examine");
-                    }
-                }
-                "d" => {
-                    if let Some(file) = parts.next() {
-                        if let Err(e) = File::create(file)
-                            .map_err(Error::from)
-                            .and_then(|f| debugger.dump_mem(f)) {
-                            println!("unable to create memory dump file: {}", e);
-                        }
-                    } else {
-                        println!("must specify output file");
-                    }
-                }
-                "f" => {
-                    if let Err(e) = debugger.scan_strings() {
-                        println!("unable to scan memory for strings: {}", e)
-                    }
-                }
-                "b" => {
-                    match (parts.next(), parts.next()) {
-                        (Some(o), Some(l)) => {
-                            if let Err(e) = debugger.add_breakpoint(o, l) {
-                                println!("error adding breakpoint: {}", e);
-                            }
-                        }
-                        _ => println!("must specify op and loc"),
-                    }
-                }
-                "bl" => debugger.list_breakpoints(),
-                "bx" => {
-                    if let Some(n) = parts.next() {
-                        if let Err(e) = debugger.delete_breakpoint(n) {
-                            println!("error deleting breakpoint: {}", e);
-                        }
-                    } else {
-                        println!("must specify breakpoint to delete (\"*\" for all)");
-                    }
-                }
-                "v" => {
-                    if let Some(file) = parts.next() {
-                        if let Err(e) = File::create(file)
-                            .map_err(Error::from)
-                            .and_then(|f| debugger.save_vm(f)) {
-                            println!("unable to create VM save file: {}", e);
-                        }
-                    } else {
-                        println!("must specify output file");
-                    }
-                }
-                "w" => {
-                    match (parts.next(), parts.next()) {
-                        (Some(n), Some(v)) => {
-                            if let Err(e) = debugger.write_reg(n, v) {
-                                println!("could not write register: {}", e);
-                            }
-                        }
-                        _ => println!("must specify register and value"),
-                    }
-                }
-                ">" => {
-                    if let Err(e) = debugger.set_output(parts.next()) {
-                        println!("error setting instruction logging: {}", e);
-                    }
-                }
-                "l" => {
-                    if let Some(file) = parts.next() {
-                        if let Err(e) = File::open(file)
-                            .map_err(Error::from)
-                            .and_then(|f| debugger.load_vm(f)) {
-                            println!("unable to load VM save file: {}", e);
-                        }
-                    } else {
-                        println!("must specify input file");
-                    }
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            break;
+        }
+        let commands = if input.trim().is_empty() {
+            last.iter().cloned().collect()
+        } else {
+            match command::parse(&input) {
+                Ok(commands) => commands,
+                Err(e) => {
+                    println!("error: {}", e);
+                    continue;
                 }
-                "q" => {
-                    println!("quitting...");
-                    break;
-                }
-                "h" => {
-                    println!(r#"
-v file  - save vm state to <file>
-l file  - load vm state from <file>
-> [<file|->]
-        - log instructions to <file>. if '-' is specified, instructions will be printed to STDOUT
-          logging is turned off if no argument specified.
-c       - continue execution
-i       - show current instruction
-s [n]   - step execution n times (once if unspecified)
-w n val - write val (0..32767) to register n
-x addr[..addr]
-        - examine memory contents at addr. a range can be specified, e.g. 0x000f..0x00f0
-         when specifying a range, omitting the first, second, or both addresses will
-         extend the range from the start or to the end
-x s [n] - show stack contents. If <n> is specified, at most <n> entries will be shown (top down)
-x r[0-7]
-        - show register contents ('r' shows all registers)
-d file  - dump the memory contents to file
-f       - scan memory for strings and output them
-b op loc
-        - add a conditional breakpoint
-          op: one of:
-            @ (at)     - break when instruction pointer hits given address
-            r (read)   - break when an instruction reads from given address or register
-            w (write)  - break when an instruction writes to given address or register
-            a (access) - break when an instruction reads or writes given address or register
-          loc: location to watch, either one of r[0...7] for registers,
-               or 0x<addr> for memory location.
-               NB: @ op requires a memory address
-bl      - list breakpoints
-bx n    - delete breakpoint n ("*" for all breakpoints)
-q       - quit
-"#);
-                }
-                c => println!("unrecognized command '{}', try 'h' for help", c),
+            }
+        };
+        for words in commands {
+            last = match command::find(&words[0]) {
+                Some(c) if c.repeats => Some(words.clone()),
+                _ => None,
+            };
+            if let Err(e) = command::execute(&mut debugger, &words) {
+                command::report(&words[0], &e);
+                break;
+            }
+            if debugger.quit {
+                break;
             }
         }
     }
@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, btree_map};
+use std::io::Write;
+
+use errors::*;
+use memory::{Addr, Memory, Value};
+use op_code::{Flow, OpCode, Role};
+use analysis::signature::{Signature, Signatures};
+use symbols::{self, Symbols};
+
+/// Shortest run of printable words reported as a string rather than data
+const MIN_STRING: usize = 4;
+/// Data words listed per `.data` line
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// decode every word in turn, skipping over anything that looks like text
+    Linear,
+    /// follow control flow from the entry points; everything unreached is data
+    Recursive,
+}
+
+#[derive(Debug)]
+pub enum Item {
+    /// a decoded instruction, along with the number of words it occupies
+    Instr(OpCode, u16),
+    Data(u16),
+    /// a run of words holding one printable character each
+    Str(String),
+}
+
+impl Item {
+    pub fn len(&self) -> u16 {
+        match *self {
+            Item::Instr(_, len) => len,
+            Item::Data(_) => 1,
+            Item::Str(ref s) => s.chars().count() as u16,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LabelKind {
+    /// target of a `call`, or an entry point
+    Sub,
+    /// target of a jump
+    Loc,
+}
+
+pub struct Disassembly {
+    mode: Mode,
+    items: BTreeMap<u16, Item>,
+    labels: BTreeMap<u16, LabelKind>,
+    symbols: Symbols,
+    signatures: BTreeMap<u16, Signature>,
+}
+
+impl Disassembly {
+    pub fn new(mem: &Memory, mode: Mode, entries: &[Addr]) -> Disassembly {
+        match mode {
+            Mode::Linear => linear(mem),
+            Mode::Recursive => recursive(mem, entries),
+        }
+    }
+
+    /// Names and comments to use in place of the generated labels
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Signatures to note at the start of each function in the listing
+    pub fn set_signatures(&mut self, sigs: &Signatures) {
+        self.signatures = sigs.iter().map(|(f, s)| (u16::from(f), s.clone())).collect();
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn items(&self) -> btree_map::Iter<u16, Item> {
+        self.items.iter()
+    }
+
+    pub fn item(&self, addr: Addr) -> Option<&Item> {
+        self.items.get(&u16::from(addr))
+    }
+
+    /// The label for `addr`; only addresses at the start of an item are labelled
+    pub fn label(&self, addr: Addr) -> Option<String> {
+        let a = u16::from(addr);
+        if !self.items.contains_key(&a) {
+            return None;
+        }
+        if let Some(name) = self.symbols.name(addr) {
+            return Some(name.to_owned());
+        }
+        self.labels.get(&a).map(|k| match *k {
+            LabelKind::Sub => format!("sub_{:04x}", a),
+            LabelKind::Loc => format!("loc_{:04x}", a),
+        })
+    }
+
+    /// Entry points and call targets
+    pub fn subroutines(&self) -> Vec<Addr> {
+        self.labels
+            .iter()
+            .filter(|&(a, k)| *k == LabelKind::Sub && self.items.contains_key(a))
+            .map(|(a, _)| Addr::from(*a))
+            .collect()
+    }
+
+    /// The instruction as `OpCode` displays it, with jump and call targets labelled
+    /// and named memory operands replaced by their names
+    pub fn instr_text(&self, op: &OpCode) -> String {
+        instr_text(op, |role, a| match role {
+            Role::Dest => self.label(a),
+            _ => self.symbols.name(a).map(|n| n.to_owned()),
+        })
+    }
+
+    /// Whether a blank line goes before `addr` in the listing
+    fn starts_routine(&self, addr: u16) -> bool {
+        match self.symbols.get(Addr::from(addr)) {
+            Some(s) => s.kind == symbols::Kind::Function,
+            None => self.labels.get(&addr) == Some(&LabelKind::Sub),
+        }
+    }
+
+    /// `; addr`, followed by any comment for it
+    fn annotation(&self, addr: u16) -> String {
+        match self.symbols.comment(Addr::from(addr)) {
+            Some(c) => format!("; {:?} {}", Addr::from(addr), c),
+            None => format!("; {:?}", Addr::from(addr)),
+        }
+    }
+
+    /// Writes the listing in a form the assembler accepts
+    pub fn write_listing<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut data: Vec<u16> = Vec::new();
+        let mut data_start = 0;
+        for (&a, item) in &self.items {
+            let label = self.label(Addr::from(a));
+            let data_break = label.is_some() || data.len() == DATA_PER_LINE ||
+                             self.symbols.comment(Addr::from(a)).is_some();
+            if !data.is_empty() && (data_break || !is_data(item)) {
+                write_data(w, &self.annotation(data_start), &data)?;
+                data.clear();
+            }
+            if let Some(label) = label {
+                if self.starts_routine(a) {
+                    writeln!(w, "")?;
+                }
+                writeln!(w, "{}:", label)?;
+                if let Some(sig) = self.signatures.get(&a) {
+                    writeln!(w, "    ; {}", sig.summary())?;
+                }
+            }
+            match *item {
+                Item::Instr(ref op, _) => {
+                    writeln!(w, "    {:<40} {}", self.instr_text(op), self.annotation(a))?
+                }
+                Item::Str(ref s) => {
+                    let s = format!(".string \"{}\"", escape(s));
+                    writeln!(w, "    {:<40} {}", s, self.annotation(a))?
+                }
+                Item::Data(d) => {
+                    if data.is_empty() {
+                        data_start = a;
+                    }
+                    data.push(d);
+                }
+            }
+        }
+        if !data.is_empty() {
+            write_data(w, &self.annotation(data_start), &data)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_data(item: &Item) -> bool {
+    match *item {
+        Item::Data(_) => true,
+        _ => false,
+    }
+}
+
+fn write_data<W: Write>(w: &mut W, annotation: &str, data: &[u16]) -> Result<()> {
+    let words = data.iter().map(|d| format!("0x{:04x}", d)).collect::<Vec<_>>();
+    let line = format!(".data {}", words.join(" "));
+    writeln!(w, "    {:<40} {}", line, annotation)?;
+    Ok(())
+}
+
+/// The instruction as `OpCode` displays it, with literal jump, call and memory
+/// addresses replaced by whatever `name` gives for them
+pub fn instr_text<F>(op: &OpCode, name: F) -> String
+    where F: Fn(Role, Addr) -> Option<String>
+{
+    let mut text = op.instruction().mnemonic.to_owned();
+    for (role, v) in op.roles() {
+        let name = match (role, v) {
+            (Role::Dest, Value::Literal(a)) |
+            (Role::Load, Value::Literal(a)) |
+            (Role::Store, Value::Literal(a)) => name(role, Addr::from(a)),
+            _ => None,
+        };
+        match name {
+            Some(n) => text.push_str(&format!(" {}", n)),
+            None => text.push_str(&format!(" {}", v)),
+        }
+    }
+    text
+}
+
+pub fn escape(s: &str) -> String {
+    let mut e = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' => e.push_str("\\n"),
+            '\t' => e.push_str("\\t"),
+            '"' => e.push_str("\\\""),
+            '\\' => e.push_str("\\\\"),
+            c => e.push(c),
+        }
+    }
+    e
+}
+
+fn decode_at(mem: &mut Memory, addr: u16) -> Option<(OpCode, u16)> {
+    mem.set_ip(Addr::from(addr));
+    mem.fetch_op().ok().map(|op| (op, u16::from(mem.ip()) - addr))
+}
+
+fn is_text(w: u16) -> bool {
+    (w >= 32 && w < 127) || w == '\n' as u16 || w == '\t' as u16
+}
+
+/// Finds runs of printable words in `start..end`, keyed by their start address
+fn find_strings(mem: &Memory, start: u16, end: u16) -> BTreeMap<u16, String> {
+    let mut strings = BTreeMap::new();
+    let mut a = start;
+    while a < end {
+        let first = mem.peek(Addr::from(a));
+        // a run of bare newlines is more likely data than text
+        if first < 32 || !is_text(first) {
+            a += 1;
+            continue;
+        }
+        let mut s = String::new();
+        let mut e = a;
+        while e < end && is_text(mem.peek(Addr::from(e))) {
+            s.push(mem.peek(Addr::from(e)) as u8 as char);
+            e += 1;
+        }
+        if s.len() >= MIN_STRING {
+            strings.insert(a, s);
+        }
+        a = e;
+    }
+    strings
+}
+
+fn linear(mem: &Memory) -> Disassembly {
+    let mut mem = mem.clone();
+    let end = mem.used_words() as u16;
+    let strings = find_strings(&mem, 0, end);
+    let mut items = BTreeMap::new();
+    let mut labels = BTreeMap::new();
+    let mut a = 0;
+    while a < end {
+        if let Some(s) = strings.get(&a) {
+            let len = s.len() as u16;
+            items.insert(a, Item::Str(s.clone()));
+            a += len;
+            continue;
+        }
+        let next_string = strings.range((a + 1)..).next().map(|(s, _)| *s).unwrap_or(end);
+        match decode_at(&mut mem, a) {
+            Some((op, len)) if a + len <= next_string => {
+                if let Some(t) = op.dest() {
+                    add_label(&mut labels, t, kind(&op));
+                }
+                items.insert(a, Item::Instr(op, len));
+                a += len;
+            }
+            _ => {
+                items.insert(a, Item::Data(mem.peek(Addr::from(a))));
+                a += 1;
+            }
+        }
+    }
+    Disassembly {
+        mode: Mode::Linear,
+        items: items,
+        labels: labels,
+        symbols: Symbols::new(),
+        signatures: BTreeMap::new(),
+    }
+}
+
+fn recursive(mem: &Memory, entries: &[Addr]) -> Disassembly {
+    let mut mem = mem.clone();
+    let end = mem.used_words() as u16;
+    let mut covered = vec![false; end as usize];
+    let mut items = BTreeMap::new();
+    let mut labels = BTreeMap::new();
+    let mut work = Vec::new();
+    for e in entries {
+        add_label(&mut labels, u16::from(*e), LabelKind::Sub);
+        work.push(u16::from(*e));
+    }
+    while let Some(a) = work.pop() {
+        if a >= end || covered[a as usize] {
+            continue;
+        }
+        let (op, len) = match decode_at(&mut mem, a) {
+            Some((op, len)) if a + len <= end &&
+                               (a..(a + len)).all(|i| !covered[i as usize]) => (op, len),
+            _ => continue,
+        };
+        for i in a..(a + len) {
+            covered[i as usize] = true;
+        }
+        if let Some(t) = op.dest() {
+            add_label(&mut labels, t, kind(&op));
+            work.push(t);
+        }
+        if falls_through(&op) {
+            work.push(a + len);
+        }
+        items.insert(a, Item::Instr(op, len));
+    }
+
+    // everything not reached is data; pick out the text amongst it
+    let mut a = 0;
+    while a < end {
+        if covered[a as usize] {
+            a += 1;
+            continue;
+        }
+        let mut e = a;
+        while e < end && !covered[e as usize] {
+            e += 1;
+        }
+        let strings = find_strings(&mem, a, e);
+        while a < e {
+            if let Some(s) = strings.get(&a) {
+                let len = s.len() as u16;
+                items.insert(a, Item::Str(s.clone()));
+                a += len;
+            } else {
+                items.insert(a, Item::Data(mem.peek(Addr::from(a))));
+                a += 1;
+            }
+        }
+    }
+    Disassembly {
+        mode: Mode::Recursive,
+        items: items,
+        labels: labels,
+        symbols: Symbols::new(),
+        signatures: BTreeMap::new(),
+    }
+}
+
+fn add_label(labels: &mut BTreeMap<u16, LabelKind>, addr: u16, kind: LabelKind) {
+    let k = labels.entry(addr).or_insert(kind);
+    if kind == LabelKind::Sub {
+        *k = kind;
+    }
+}
+
+fn kind(op: &OpCode) -> LabelKind {
+    match op.instruction().flow {
+        Flow::Call => LabelKind::Sub,
+        _ => LabelKind::Loc,
+    }
+}
+
+fn falls_through(op: &OpCode) -> bool {
+    op.instruction().falls_through()
+}
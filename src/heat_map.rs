@@ -0,0 +1,178 @@
+use std::io::Write;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use errors::*;
+use memory::Addr;
+
+const WORDS: usize = 32768;
+/// Image layout; one pixel per word, rows of 128 words
+const WIDTH: usize = 128;
+const HEIGHT: usize = WORDS / WIDTH;
+
+/// Per-word read, write and execute counts accumulated over a run of the VM
+pub struct HeatMap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    execs: Vec<u32>,
+}
+
+impl HeatMap {
+    pub fn new() -> HeatMap {
+        HeatMap {
+            reads: vec![0; WORDS],
+            writes: vec![0; WORDS],
+            execs: vec![0; WORDS],
+        }
+    }
+
+    pub fn read(&mut self, addr: Addr) {
+        bump(&mut self.reads, addr);
+    }
+
+    pub fn write(&mut self, addr: Addr) {
+        bump(&mut self.writes, addr);
+    }
+
+    /// Records execution of the instruction occupying `start` up to (excluding) `end`
+    pub fn exec(&mut self, start: Addr, end: Addr) {
+        for a in usize::from(start)..usize::from(end) {
+            bump(&mut self.execs, Addr::from(a as u16));
+        }
+    }
+
+    /// One `addr,reads,writes,execs` row per word that was accessed at all
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> Result<()> {
+        writeln!(w, "addr,reads,writes,execs")?;
+        for a in 0..WORDS {
+            let (r, wr, x) = (self.reads[a], self.writes[a], self.execs[a]);
+            if r != 0 || wr != 0 || x != 0 {
+                writeln!(w, "0x{:04x},{},{},{}", a, r, wr, x)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Binary (P6) PPM image
+    pub fn write_ppm<W: Write>(&self, w: &mut W) -> Result<()> {
+        write!(w, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        w.write_all(&self.pixels())?;
+        Ok(())
+    }
+
+    /// Truecolour PNG image; the image data is stored uncompressed so no deflate
+    /// implementation is required
+    pub fn write_png<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.write_u32::<BigEndian>(WIDTH as u32)?;
+        ihdr.write_u32::<BigEndian>(HEIGHT as u32)?;
+        // bit depth 8, colour type 2 (RGB), default compression, filter & interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(w, b"IHDR", &ihdr)?;
+
+        // each scanline is preceded by its filter type; 0 is "none"
+        let mut raw = Vec::with_capacity(HEIGHT * (1 + WIDTH * 3));
+        for row in self.pixels().chunks(WIDTH * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        write_chunk(w, b"IDAT", &zlib_stored(&raw)?)?;
+
+        write_chunk(w, b"IEND", &[])
+    }
+
+    /// RGB pixels: red for writes, green for execution, blue for reads; intensity is
+    /// log-scaled against the busiest word for each kind of access
+    fn pixels(&self) -> Vec<u8> {
+        let scales = [scale(&self.writes), scale(&self.execs), scale(&self.reads)];
+        let mut px = Vec::with_capacity(WORDS * 3);
+        for a in 0..WORDS {
+            for &(counts, max) in &scales {
+                px.push(intensity(counts[a], max));
+            }
+        }
+        px
+    }
+}
+
+fn bump(counts: &mut [u32], addr: Addr) {
+    let c = &mut counts[usize::from(addr)];
+    *c = c.saturating_add(1);
+}
+
+fn scale(counts: &[u32]) -> (&[u32], f64) {
+    let max = counts.iter().cloned().max().unwrap_or(0);
+    (counts, (max as f64).ln_1p())
+}
+
+fn intensity(count: u32, max: f64) -> u8 {
+    if count == 0 {
+        0
+    } else if max <= (1f64).ln_1p() {
+        255
+    } else {
+        // anything touched at all stays visibly distinct from untouched memory
+        (64.0 + 191.0 * (count as f64).ln_1p() / max) as u8
+    }
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8], data: &[u8]) -> Result<()> {
+    w.write_u32::<BigEndian>(data.len() as u32)?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    w.write_u32::<BigEndian>(crc.finish())?;
+    Ok(())
+}
+
+/// Wraps `data` in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Result<Vec<u8>> {
+    const MAX_BLOCK: usize = 65535;
+    let mut z = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    // CM 8 (deflate), 32K window, no preset dictionary, check bits
+    z.extend_from_slice(&[0x78, 0x01]);
+    let blocks = data.chunks(MAX_BLOCK).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i + 1 == blocks.len();
+        z.push(if last { 1 } else { 0 });
+        let len = block.len() as u16;
+        z.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        z.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &d in data {
+        a = (a + d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    z.write_u32::<BigEndian>((b << 16) | a)?;
+    Ok(z)
+}
+
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        Crc32(0xffffffff)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &d in data {
+            self.0 ^= d as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 {
+                    0xedb88320 ^ (self.0 >> 1)
+                } else {
+                    self.0 >> 1
+                };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
@@ -0,0 +1,22 @@
+#![recursion_limit = "1024"]
+
+extern crate byteorder;
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate log;
+extern crate try_from;
+
+pub mod analysis;
+pub mod asm;
+pub mod debugger;
+pub mod disasm;
+pub mod errors;
+pub mod heat_map;
+pub mod lockstep;
+pub mod machine;
+pub mod memory;
+pub mod op_code;
+pub mod patch;
+pub mod symbols;
+pub mod trace;
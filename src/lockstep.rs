@@ -0,0 +1,392 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use errors::*;
+use machine::{HaltedMachine, Inspectable, Machine, OpResult, StalledMachine};
+use memory::{Addr, Register, Value};
+use op_code::DecodedOpCode;
+use trace::{Effect, Record};
+
+/// A value written to a register or memory word before running, e.g. `r7=5`
+/// or `0x1571=21`
+#[derive(Clone, Copy, Debug)]
+pub enum Poke {
+    Reg(Register, u16),
+    Mem(Addr, u16),
+}
+
+impl Poke {
+    pub fn apply(&self, m: &mut Inspectable) {
+        match *self {
+            Poke::Reg(reg, v) => m.write_reg(reg, Value::Literal(v)),
+            Poke::Mem(addr, v) => m.memory_mut().write(addr, v),
+        }
+    }
+}
+
+impl FromStr for Poke {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Poke> {
+        let mut parts = s.splitn(2, '=');
+        let (loc, v) = match (parts.next(), parts.next()) {
+            (Some(loc), Some(v)) => (loc.trim(), v.trim()),
+            _ => bail!("expected 'rN=VALUE' or 'ADDR=VALUE', found '{}'", s),
+        };
+        let v = if v.starts_with("0x") {
+            u16::from_str_radix(&v[2..], 16)
+        } else {
+            u16::from_str(v)
+        };
+        let v = v.chain_err(|| format!("invalid value in '{}'", s))?;
+        if loc.starts_with('r') {
+            Ok(Poke::Reg(Register::from_str(&loc[1..])?, v))
+        } else {
+            Ok(Poke::Mem(Addr::from_str(loc)?, v))
+        }
+    }
+}
+
+/// One of the machines being compared
+enum Side {
+    Running(Machine),
+    Waiting(StalledMachine),
+    Halted(HaltedMachine),
+}
+
+impl Side {
+    fn as_ref(&self) -> &Inspectable {
+        match *self {
+            Side::Running(ref m) => m,
+            Side::Waiting(ref s) => s,
+            Side::Halted(ref h) => h,
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match *self {
+            Side::Running(_) => "running",
+            Side::Waiting(_) => "waiting for input",
+            Side::Halted(_) => "halted",
+        }
+    }
+}
+
+/// A machine and what it has been given to run with
+struct Runner {
+    side: Side,
+    input: VecDeque<String>,
+    /// calls made and not yet returned from
+    depth: u16,
+}
+
+impl Runner {
+    /// Runs one instruction, returning its trace record, or `None` if the machine
+    /// cannot run any further
+    fn step(mut self, step: u64) -> Result<(Runner, Option<Record>)> {
+        let mut m = match self.side {
+            Side::Running(m) => m,
+            s => {
+                self.side = s;
+                return Ok((self, None));
+            }
+        };
+        let mut r = Record::start(step, self.depth, &mut m)?;
+        let mut out = None;
+        self.side = match m.step()? {
+            OpResult::Continue(m) => Side::Running(m),
+            OpResult::Output(c, m) => {
+                out = Some(c);
+                Side::Running(m)
+            }
+            OpResult::Input(stalled) => {
+                match self.input.pop_front() {
+                    Some(line) => Side::Running(stalled.set_input(line)),
+                    None => {
+                        self.side = Side::Waiting(stalled);
+                        return Ok((self, None));
+                    }
+                }
+            }
+            OpResult::Halted(halted) => Side::Halted(halted),
+        };
+        r.finish(self.side.as_ref(), out);
+        match r.decoded() {
+            Ok(DecodedOpCode::Call { .. }) => self.depth += 1,
+            Ok(DecodedOpCode::Ret { addr: Some(_) }) => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+        Ok((self, Some(r)))
+    }
+}
+
+/// Something that differs between the two machines
+#[derive(Debug)]
+pub enum Difference {
+    /// the machines ran different instructions at the same address
+    Code { ip: u16, a: Vec<u16>, b: Vec<u16> },
+    Status(&'static str, &'static str),
+    Ip(u16, u16),
+    Reg { reg: usize, a: u16, b: u16 },
+    /// the stacks differ at the given depth from the bottom
+    Stack { depth: usize, a: Option<u16>, b: Option<u16> },
+    /// a word one of the machines just wrote
+    Mem { addr: u16, a: u16, b: u16 },
+    Output(Option<char>, Option<char>),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let word = |v: Option<u16>| v.map(|v| format!("0x{:04x}", v)).unwrap_or("-".to_owned());
+        match *self {
+            Difference::Code { ip, ref a, ref b } => {
+                write!(f, "code at 0x{:04x}: {:?} vs {:?}", ip, a, b)
+            }
+            Difference::Status(a, b) => write!(f, "status: {} vs {}", a, b),
+            Difference::Ip(a, b) => write!(f, "ip: 0x{:04x} vs 0x{:04x}", a, b),
+            Difference::Reg { reg, a, b } => write!(f, "r{}: 0x{:04x} vs 0x{:04x}", reg, a, b),
+            Difference::Stack { depth, a, b } => {
+                write!(f, "stack[{}]: {} vs {}", depth, word(a), word(b))
+            }
+            Difference::Mem { addr, a, b } => {
+                write!(f, "[0x{:04x}]: 0x{:04x} vs 0x{:04x}", addr, a, b)
+            }
+            Difference::Output(a, b) => write!(f, "output: {:?} vs {:?}", a, b),
+        }
+    }
+}
+
+/// A machine's registers, stack and where it is
+pub struct State {
+    pub status: &'static str,
+    pub ip: Option<u16>,
+    pub regs: [u16; 8],
+    pub stack: Vec<u16>,
+}
+
+impl State {
+    fn new(side: &Side) -> State {
+        let m = side.as_ref();
+        let mut regs = [0; 8];
+        for (i, r) in m.registers().into_iter().enumerate() {
+            regs[i] = r;
+        }
+        State {
+            status: side.status(),
+            ip: m.ip().map(u16::from),
+            regs: regs,
+            stack: m.stack().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "ip 0x{:04x} ({})", ip, self.status)?,
+            None => write!(f, "{}", self.status)?,
+        }
+        for (i, r) in self.regs.iter().enumerate() {
+            write!(f, " r{}=0x{:04x}", i, r)?;
+        }
+        let top = self.stack.iter().rev().take(8).map(|v| format!("0x{:04x}", v)).collect::<Vec<_>>();
+        write!(f, "\n    stack ({})", self.stack.len())?;
+        if !top.is_empty() {
+            write!(f, ": {}", top.join(" "))?;
+        }
+        if self.stack.len() > 8 {
+            write!(f, " ...")?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Outcome {
+    Diverged(Vec<Difference>),
+    Halted,
+    /// both machines need input beyond what they were given
+    Input,
+    /// no divergence within the step limit
+    Limit,
+}
+
+pub struct Report {
+    pub outcome: Outcome,
+    /// instructions each machine ran
+    pub steps: u64,
+    /// the last instructions run by each machine, oldest first
+    pub history: Vec<(Record, Record)>,
+    pub a: State,
+    pub b: State,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.outcome {
+            Outcome::Diverged(ref diffs) => {
+                writeln!(f, "diverged after {} steps:", self.steps)?;
+                for d in diffs {
+                    writeln!(f, "  {}", d)?;
+                }
+            }
+            Outcome::Halted => writeln!(f, "both halted after {} steps without diverging", self.steps)?,
+            Outcome::Input => {
+                writeln!(f, "both waiting for input after {} steps without diverging", self.steps)?
+            }
+            Outcome::Limit => writeln!(f, "no divergence in {} steps", self.steps)?,
+        }
+        if !self.history.is_empty() {
+            writeln!(f, "last instructions:")?;
+        }
+        for &(ref a, ref b) in &self.history {
+            let (a, b) = (format!("{}", a), format!("{}", b));
+            if a == b {
+                writeln!(f, "   {}", a)?;
+            } else {
+                writeln!(f, " A {}\n B {}", a, b)?;
+            }
+        }
+        write!(f, "A: {}\nB: {}", self.a, self.b)
+    }
+}
+
+/// Runs `a` and `b` an instruction at a time until they stop agreeing on the
+/// ip, a register, the stack, a word just written, or output. Registers which
+/// differ at the start are only compared once they have come to agree. Each
+/// machine is given the same lines of `input`, and the last `context`
+/// instructions are kept for the report.
+pub fn run(a: Machine, b: Machine, input: &str, max_steps: u64, context: usize) -> Result<Report> {
+    let lines = input.lines().map(|l| format!("{}\n", l)).collect::<VecDeque<_>>();
+    let mut a = Runner {
+        side: Side::Running(a),
+        input: lines.clone(),
+        depth: 0,
+    };
+    let mut b = Runner {
+        side: Side::Running(b),
+        input: lines,
+        depth: 0,
+    };
+    let mut masked = [false; 8];
+    for (i, (ra, rb)) in a.side
+        .as_ref()
+        .registers()
+        .into_iter()
+        .zip(b.side.as_ref().registers().into_iter())
+        .enumerate() {
+        masked[i] = ra != rb;
+    }
+    let mut history = VecDeque::new();
+    let mut steps = 0;
+    let mut diffs = compare(&a.side, &b.side, &mut masked, None);
+    while diffs.is_empty() && steps < max_steps {
+        let (next_a, ra) = a.step(steps)?;
+        let (next_b, rb) = b.step(steps)?;
+        a = next_a;
+        b = next_b;
+        diffs = compare(&a.side, &b.side, &mut masked, ra.as_ref().and_then(|ra| {
+            rb.as_ref().map(|rb| (ra, rb))
+        }));
+        match (ra, rb) {
+            (Some(ra), Some(rb)) => {
+                steps += 1;
+                history.push_back((ra, rb));
+                if history.len() > context {
+                    history.pop_front();
+                }
+            }
+            _ => break,
+        }
+    }
+    let outcome = if !diffs.is_empty() {
+        Outcome::Diverged(diffs)
+    } else {
+        match (&a.side, &b.side) {
+            (&Side::Halted(_), &Side::Halted(_)) => Outcome::Halted,
+            (&Side::Waiting(_), &Side::Waiting(_)) => Outcome::Input,
+            _ => Outcome::Limit,
+        }
+    };
+    Ok(Report {
+        outcome: outcome,
+        steps: steps,
+        history: history.into_iter().collect(),
+        a: State::new(&a.side),
+        b: State::new(&b.side),
+    })
+}
+
+/// How the machines differ, having just run the instructions recorded in `last`
+fn compare(a: &Side, b: &Side, masked: &mut [bool; 8], last: Option<(&Record, &Record)>) -> Vec<Difference> {
+    let mut diffs = Vec::new();
+    if let Some((ra, rb)) = last {
+        if ra.raw != rb.raw {
+            diffs.push(Difference::Code {
+                ip: ra.ip,
+                a: ra.raw.clone(),
+                b: rb.raw.clone(),
+            });
+        }
+        let (oa, ob) = (output(ra), output(rb));
+        if oa != ob {
+            diffs.push(Difference::Output(oa, ob));
+        }
+        for addr in written(ra).into_iter().chain(written(rb)) {
+            let (va, vb) = (a.as_ref().memory().peek(Addr::from(addr)),
+                            b.as_ref().memory().peek(Addr::from(addr)));
+            if va != vb && !diffs.iter().any(|d| match *d {
+                Difference::Mem { addr: a, .. } => a == addr,
+                _ => false,
+            }) {
+                diffs.push(Difference::Mem {
+                    addr: addr,
+                    a: va,
+                    b: vb,
+                });
+            }
+        }
+    }
+    if a.status() != b.status() {
+        diffs.push(Difference::Status(a.status(), b.status()));
+    }
+    let (a, b) = (a.as_ref(), b.as_ref());
+    match (a.ip(), b.ip()) {
+        (Some(ia), Some(ib)) if ia != ib => diffs.push(Difference::Ip(u16::from(ia), u16::from(ib))),
+        _ => {}
+    }
+    for (i, (ra, rb)) in a.registers().into_iter().zip(b.registers().into_iter()).enumerate() {
+        if ra == rb {
+            masked[i] = false;
+        } else if !masked[i] {
+            diffs.push(Difference::Reg {
+                reg: i,
+                a: ra,
+                b: rb,
+            });
+        }
+    }
+    let (sa, sb) = (a.stack(), b.stack());
+    if sa != sb {
+        let depth = sa.iter().zip(sb).take_while(|&(x, y)| x == y).count();
+        diffs.push(Difference::Stack {
+            depth: depth,
+            a: sa.get(depth).cloned(),
+            b: sb.get(depth).cloned(),
+        });
+    }
+    diffs
+}
+
+fn output(r: &Record) -> Option<char> {
+    r.effects.iter().filter_map(|e| match *e {
+        Effect::Out(c) => Some(c),
+        _ => None,
+    }).next()
+}
+
+fn written(r: &Record) -> Vec<u16> {
+    r.effects.iter().filter_map(|e| match *e {
+        Effect::Mem { addr, .. } => Some(addr),
+        _ => None,
+    }).collect()
+}
@@ -7,6 +7,7 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use try_from::TryFrom;
 
 use errors::*;
+use heat_map::HeatMap;
 use memory::*;
 use op_code::{OpCode, DecodedOpCode};
 
@@ -14,10 +15,58 @@ pub trait Inspectable {
     fn ip(&self) -> Option<Addr>;
     fn registers(&self) -> &RegisterSet;
     fn memory(&self) -> &Memory;
+    fn memory_mut(&mut self) -> &mut Memory;
     fn stack(&self) -> &[u16];
     fn as_bytes(&self) -> Result<Vec<u8>>;
     fn write_reg(&mut self, Register, Value);
     fn peek_instr(&mut self) -> Result<(OpCode, DecodedOpCode)>;
+    fn heat_map(&self) -> Option<&HeatMap>;
+    fn set_heat_map(&mut self, Option<HeatMap>) -> Option<HeatMap>;
+    /// An independent, running copy of the machine, e.g. for running code
+    /// speculatively; the heat map and any buffered input are not carried over
+    fn fork(&self) -> Machine;
+    /// Calls made and not yet returned from, outermost first
+    fn frames(&self) -> &[Frame];
+    /// The most recent returns that did not match the calls made
+    fn anomalies(&self) -> &[Anomaly];
+}
+
+/// A call not yet returned from. The stack holds data as well as return
+/// addresses, so calls are tracked alongside it as they are made
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// the `call`
+    pub site: Addr,
+    pub target: Addr,
+    /// where the call pushed its return address to, the instruction after it
+    pub ret: Addr,
+    /// the stack depth with the return address pushed
+    pub depth: usize,
+}
+
+/// A return that did not match the calls made; as the stack can only be
+/// changed at the top, a return address being replaced shows up as a `Popped`
+/// followed by an `Unmatched`
+#[derive(Clone, Debug)]
+pub enum Anomaly {
+    /// a `ret` to an address pushed as data rather than by a call
+    Unmatched { ip: Addr, to: Addr },
+    /// a `pop` of a return address, abandoning the call
+    Popped { ip: Addr, frame: Frame },
+}
+
+/// How many anomalies a machine keeps
+const MAX_ANOMALIES: usize = 64;
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Anomaly::Unmatched { ip, to } => write!(f, "{:?}: ret to {:?}, which no call pushed", ip, to),
+            Anomaly::Popped { ip, ref frame } => {
+                write!(f, "{:?}: pop of the return address of the call at {:?}", ip, frame.site)
+            }
+        }
+    }
 }
 
 pub struct Machine {
@@ -25,6 +74,12 @@ pub struct Machine {
     registers: RegisterSet,
     stack: Vec<u16>,
     input_buffer: String,
+    heat_map: Option<HeatMap>,
+    frames: Vec<Frame>,
+    /// stack depth up to which calls were made before they were tracked, as
+    /// in a machine loaded from a save file
+    untracked: usize,
+    anomalies: Vec<Anomaly>,
 }
 
 impl<'a> TryFrom<&'a [u8]> for Machine {
@@ -70,6 +125,10 @@ impl<'a> TryFrom<&'a [u8]> for Machine {
             registers: registers,
             stack: stack,
             input_buffer: String::new(),
+            heat_map: None,
+            frames: Vec::new(),
+            untracked: stack_len,
+            anomalies: Vec::new(),
         })
     }
 }
@@ -89,17 +148,74 @@ impl Machine {
             registers: RegisterSet::new(),
             stack: Vec::new(),
             input_buffer: String::new(),
+            heat_map: None,
+            frames: Vec::new(),
+            untracked: 0,
+            anomalies: Vec::new(),
         })
     }
 
+    /// Loads a machine from a save file written by the debugger, ignoring the
+    /// leading debugger state bytes
+    pub fn from_save<P: AsRef<Path>>(save_path: P) -> Result<Machine> {
+        let mut data = Vec::with_capacity(::memory::MAX_BYTES);
+        std::fs::File::open(save_path)?.read_to_end(&mut data)?;
+        if data.len() < 2 {
+            bail!("truncated save file");
+        }
+        Machine::try_from(&data[2..])
+    }
+
+    /// Calls the routine at `addr` as a `call` at the current ip would
+    pub fn enter(&mut self, addr: Addr) {
+        let ip = self.memory.ip();
+        self.stack.push(u16::from(ip));
+        self.frames.push(Frame {
+            site: ip,
+            target: addr,
+            ret: ip,
+            depth: self.stack.len(),
+        });
+        self.memory.set_ip(addr);
+    }
+
     pub fn step(mut self) -> Result<OpResult> {
+        let event = self.exec()?;
+        Ok(self.after(event))
+    }
+
+    /// Like `step`, but an instruction that faults, e.g. by popping an empty
+    /// stack, leaves the machine halted rather than losing it, so that it can
+    /// still be inspected
+    pub fn step_or_halt(mut self) -> (OpResult, Option<Error>) {
+        match self.exec() {
+            Ok(event) => (self.after(event), None),
+            Err(e) => (OpResult::Halted(HaltedMachine(self)), Some(e)),
+        }
+    }
+
+    fn after(self, event: Event) -> OpResult {
+        match event {
+            Event::Continue => OpResult::Continue(self),
+            Event::Output(c) => OpResult::Output(c, self),
+            Event::Input(reg) => OpResult::Input(StalledMachine(self, reg)),
+            Event::Halted => OpResult::Halted(HaltedMachine(self)),
+        }
+    }
+
+    /// Runs the instruction at the ip
+    fn exec(&mut self) -> Result<Event> {
+        let ip = self.memory.ip();
         let op_code = self.memory.fetch_op()?;
+        if let Some(ref mut heat_map) = self.heat_map {
+            heat_map.exec(ip, self.memory.ip());
+        }
         match op_code.decode(&self.registers, self.stack.last().map(|h| *h))? {
-            DecodedOpCode::Halt => return Ok(OpResult::Halted(HaltedMachine(self))),
+            DecodedOpCode::Halt {} => return Ok(Event::Halted),
             DecodedOpCode::Out { c } => {
-                return Ok(OpResult::Output(c, self));
+                return Ok(Event::Output(c));
             }
-            DecodedOpCode::Noop => {}
+            DecodedOpCode::Noop {} => {}
             DecodedOpCode::Jmp { addr } => {
                 self.memory.set_ip(addr);
             }
@@ -134,6 +250,7 @@ impl Machine {
             DecodedOpCode::Pop { reg } => {
                 if let Some(v) = self.stack.pop() {
                     self.registers.write_u16(reg, v);
+                    self.popped(ip);
                 } else {
                     bail!(ErrorKind::EmptyStack);
                 }
@@ -151,38 +268,93 @@ impl Machine {
                 self.registers.write_u16(reg, !val & 0b111111111111111);
             }
             DecodedOpCode::Call { addr } => {
-                self.stack.push(usize::from(self.memory.ip()) as u16);
+                let ret = self.memory.ip();
+                self.stack.push(usize::from(ret) as u16);
+                self.frames.push(Frame {
+                    site: ip,
+                    target: addr,
+                    ret: ret,
+                    depth: self.stack.len(),
+                });
                 self.memory.set_ip(addr);
             }
             DecodedOpCode::Rmem { reg, addr } => {
+                if let Some(ref mut heat_map) = self.heat_map {
+                    heat_map.read(addr);
+                }
                 let v = self.memory.read(addr).and_then(Value::try_from)?;
                 self.registers.write_val(reg, v);
             }
             DecodedOpCode::Wmem { addr, val } => {
+                if let Some(ref mut heat_map) = self.heat_map {
+                    heat_map.write(addr);
+                }
                 self.memory.write(addr, val);
             }
             DecodedOpCode::Ret { addr } => {
                 if let Some(a) = addr {
+                    self.returned(ip, a);
                     self.stack.pop();
                     self.memory.set_ip(a);
                 } else {
-                    return Ok(OpResult::Halted(HaltedMachine(self)));
+                    return Ok(Event::Halted);
                 }
             }
             DecodedOpCode::In { reg } => {
                 if 0 == self.input_buffer.len() {
-                    return Ok(OpResult::Input(StalledMachine(self, reg)));
+                    return Ok(Event::Input(reg));
                 } else {
-                    self.write_reg_from_buffer(reg)?;
+                    self.write_reg_from_buffer(reg);
                 }
             }
         }
-        Ok(OpResult::Continue(self))
+        Ok(Event::Continue)
+    }
+
+    /// Follows a `ret` at `ip` to `to` on the shadow call stack, before the
+    /// return address is popped
+    fn returned(&mut self, ip: Addr, to: Addr) {
+        let depth = self.stack.len();
+        match self.frames.last().map(|f| f.depth) {
+            Some(d) if d == depth => {
+                self.frames.pop();
+            }
+            _ if depth <= self.untracked => {}
+            _ => self.anomaly(Anomaly::Unmatched { ip: ip, to: to }),
+        }
+        self.untracked = std::cmp::min(self.untracked, depth - 1);
+    }
+
+    /// Drops calls whose return address a `pop` at `ip` took off the stack
+    fn popped(&mut self, ip: Addr) {
+        let depth = self.stack.len();
+        self.untracked = std::cmp::min(self.untracked, depth);
+        while self.frames.last().map(|f| f.depth > depth).unwrap_or(false) {
+            let frame = self.frames.pop().unwrap();
+            self.anomaly(Anomaly::Popped {
+                ip: ip,
+                frame: frame,
+            });
+        }
+    }
+
+    fn anomaly(&mut self, a: Anomaly) {
+        if self.anomalies.len() == MAX_ANOMALIES {
+            self.anomalies.remove(0);
+        }
+        self.anomalies.push(a);
+    }
+
+    /// Whether an `in` would read input already given rather than ask for more
+    pub fn has_input(&self) -> bool {
+        !self.input_buffer.is_empty()
     }
 
-    fn write_reg_from_buffer(&mut self, reg: Register) -> Result<()> {
-        let v = Value::try_from(self.input_buffer.remove(0) as u16)?;
-        Ok(self.registers.write_val(reg, v))
+    /// Reads the next input character into `reg`; any beyond the 15-bit range
+    /// are read as '?'
+    fn write_reg_from_buffer(&mut self, reg: Register) {
+        let c = self.input_buffer.remove(0) as u32;
+        self.registers.write_u16(reg, if c < 32768 { c as u16 } else { b'?' as u16 });
     }
 }
 
@@ -199,6 +371,10 @@ impl Inspectable for Machine {
         &self.memory
     }
 
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
     fn stack(&self) -> &[u16] {
         &self.stack
     }
@@ -252,6 +428,35 @@ impl Inspectable for Machine {
         self.memory.set_ip(ip);
         Ok((op_code, decoded))
     }
+
+    fn heat_map(&self) -> Option<&HeatMap> {
+        self.heat_map.as_ref()
+    }
+
+    fn set_heat_map(&mut self, heat_map: Option<HeatMap>) -> Option<HeatMap> {
+        std::mem::replace(&mut self.heat_map, heat_map)
+    }
+
+    fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    fn anomalies(&self) -> &[Anomaly] {
+        &self.anomalies
+    }
+
+    fn fork(&self) -> Machine {
+        Machine {
+            memory: self.memory.clone(),
+            registers: self.registers.clone(),
+            stack: self.stack.clone(),
+            input_buffer: String::new(),
+            heat_map: None,
+            frames: self.frames.clone(),
+            untracked: self.untracked,
+            anomalies: Vec::new(),
+        }
+    }
 }
 
 pub struct StalledMachine(Machine, Register);
@@ -264,10 +469,12 @@ impl StalledMachine {
         self.1.into()
     }
 
-    pub fn set_input(mut self, input: String) -> Result<Machine> {
+    /// Completes the `in` the machine stalled on with the first character of
+    /// `input`, which must not be empty, buffering the rest
+    pub fn set_input(mut self, input: String) -> Machine {
         self.0.input_buffer = input;
-        self.0.write_reg_from_buffer(self.1)?;
-        Ok(self.0)
+        self.0.write_reg_from_buffer(self.1);
+        self.0
     }
 }
 
@@ -284,6 +491,10 @@ impl Inspectable for StalledMachine {
         &self.0.memory
     }
 
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.0.memory
+    }
+
     fn stack(&self) -> &[u16] {
         &self.0.stack
     }
@@ -299,6 +510,26 @@ impl Inspectable for StalledMachine {
     fn peek_instr(&mut self) -> Result<(OpCode, DecodedOpCode)> {
         self.0.peek_instr()
     }
+
+    fn heat_map(&self) -> Option<&HeatMap> {
+        self.0.heat_map()
+    }
+
+    fn set_heat_map(&mut self, heat_map: Option<HeatMap>) -> Option<HeatMap> {
+        self.0.set_heat_map(heat_map)
+    }
+
+    fn fork(&self) -> Machine {
+        self.0.fork()
+    }
+
+    fn frames(&self) -> &[Frame] {
+        &self.0.frames
+    }
+
+    fn anomalies(&self) -> &[Anomaly] {
+        &self.0.anomalies
+    }
 }
 
 pub struct HaltedMachine(Machine);
@@ -322,6 +553,10 @@ impl Inspectable for HaltedMachine {
         &self.0.memory
     }
 
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.0.memory
+    }
+
     fn stack(&self) -> &[u16] {
         &self.0.stack
     }
@@ -337,6 +572,34 @@ impl Inspectable for HaltedMachine {
     fn peek_instr(&mut self) -> Result<(OpCode, DecodedOpCode)> {
         self.0.peek_instr()
     }
+
+    fn heat_map(&self) -> Option<&HeatMap> {
+        self.0.heat_map()
+    }
+
+    fn set_heat_map(&mut self, heat_map: Option<HeatMap>) -> Option<HeatMap> {
+        self.0.set_heat_map(heat_map)
+    }
+
+    fn fork(&self) -> Machine {
+        self.0.fork()
+    }
+
+    fn frames(&self) -> &[Frame] {
+        &self.0.frames
+    }
+
+    fn anomalies(&self) -> &[Anomaly] {
+        &self.0.anomalies
+    }
+}
+
+/// What running an instruction led to, for `Machine::after` to wrap the machine in
+enum Event {
+    Continue,
+    Output(char),
+    Input(Register),
+    Halted,
 }
 
 pub enum OpResult {
@@ -1,23 +1,38 @@
-#![recursion_limit = "1024"]
-
-extern crate byteorder;
 extern crate env_logger;
-#[macro_use]
-extern crate error_chain;
-#[macro_use]
-extern crate log;
-extern crate try_from;
+extern crate synacor;
+
+use std::path::Path;
+
+use synacor::debugger;
 
-mod debugger;
-mod errors;
-mod machine;
-mod memory;
-mod op_code;
+/// Script sourced at startup, unless another is given with -x or none with -n
+const STARTUP: &'static str = ".synacorrc";
 
 fn main() {
     env_logger::init().expect("unable to initialize logging");
-    let rom_path = std::env::args().nth(1).expect("must specify ROM file");
-    if let Err(e) = debugger::debug(rom_path) {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut patches = Vec::new();
+    let mut startup = if Path::new(STARTUP).exists() {
+        Some(STARTUP.to_owned())
+    } else {
+        None
+    };
+    while let Some(arg) = args.next() {
+        if "-p" == arg || "--patch" == arg {
+            patches.push(args.next().expect("must specify patch file"));
+        } else if "-x" == arg || "--startup" == arg {
+            startup = Some(args.next().expect("must specify startup script"));
+        } else if "-n" == arg || "--no-startup" == arg {
+            startup = None;
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut positional = positional.into_iter();
+    let rom_path = positional.next().expect("must specify ROM file");
+    let symbols_path = positional.next();
+    if let Err(e) = debugger::debug(rom_path, symbols_path, &patches, startup) {
         println!("error: {}", e);
 
         for e in e.iter().skip(1) {
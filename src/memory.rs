@@ -2,13 +2,13 @@ use std::fmt;
 use std::io::{Cursor, Seek, SeekFrom};
 use std::str::FromStr;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use try_from::TryFrom;
 
 use errors::*;
 use op_code::OpCode;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Target {
     Mem(Addr),
     Reg(Register),
@@ -137,12 +137,45 @@ impl<'a> From<&'a Addr> for usize {
     }
 }
 
+#[derive(Debug)]
 pub struct AddrRange(Option<Addr>, Option<Addr>);
 
 impl AddrRange {
+    /// A range between the given bounds (in either order); omitted bounds are open
+    pub fn new(s: Option<Addr>, e: Option<Addr>) -> AddrRange {
+        match (s, e) {
+            (Some(ref sv), Some(ref ev)) if sv.0 > ev.0 => AddrRange(e, s),
+            _ => AddrRange(s, e),
+        }
+    }
+
     pub fn start(&self) -> usize {
         self.0.map(usize::from).unwrap_or(0)
     }
+
+    /// The lower bound, unless the range is open at the start
+    pub fn first(&self) -> Option<Addr> {
+        self.0
+    }
+
+    /// Whether `addr` lies within the range; open ends extend to the limits of
+    /// the address space, irrespective of how much memory is in use
+    pub fn contains(&self, addr: Addr) -> bool {
+        self.0.map(|s| s.0 <= addr.0).unwrap_or(true) &&
+        self.1.map(|e| addr.0 <= e.0).unwrap_or(true)
+    }
+}
+
+impl fmt::Display for AddrRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.0, self.1) {
+            (Some(s), Some(e)) if s == e => write!(f, "{}", s),
+            (Some(s), Some(e)) => write!(f, "{}..{}", s, e),
+            (Some(s), None) => write!(f, "{}..", s),
+            (None, Some(e)) => write!(f, "..{}", e),
+            (None, None) => write!(f, ".."),
+        }
+    }
 }
 
 impl FromStr for AddrRange {
@@ -163,13 +196,11 @@ impl FromStr for AddrRange {
             let s = Some(Addr::from_str(s)?);
             (s, s)
         };
-        match (s, e) {
-            (Some(ref sv), Some(ref ev)) if sv.0 > ev.0 => Ok(AddrRange(e, s)),
-            _ => Ok(AddrRange(s, e)),
-        }
+        Ok(AddrRange::new(s, e))
     }
 }
 
+#[derive(Clone)]
 pub struct RegisterSet([u16; 8]);
 
 pub struct RegisterSetIterator<'s> {
@@ -330,6 +361,15 @@ impl TryFrom<u16> for Value {
     }
 }
 
+impl From<Value> for u16 {
+    fn from(v: Value) -> u16 {
+        match v {
+            Value::Literal(l) => l,
+            Value::FromRegister(r) => 32768 + r.0 as u16,
+        }
+    }
+}
+
 impl FromStr for Value {
     type Err = Error;
     fn from_str(v: &str) -> Result<Value> {
@@ -344,6 +384,7 @@ impl FromStr for Value {
     }
 }
 
+#[derive(Clone)]
 pub struct Memory {
     ip: Cursor<Vec<u8>>,
     max_used_addr: Addr,
@@ -367,6 +408,11 @@ impl Memory {
         (self.max_used_addr.0 + 1) * 2
     }
 
+    /// Number of words from the start of memory up to the highest one in use
+    pub fn used_words(&self) -> usize {
+        self.max_used_addr.0 as usize + 1
+    }
+
     pub fn set_ip(&mut self, addr: Addr) {
         let _ = self.ip.seek(addr.into());
     }
@@ -383,6 +429,12 @@ impl Memory {
         r
     }
 
+    /// Read the word at `addr` without disturbing the instruction pointer
+    pub fn peek(&self, addr: Addr) -> u16 {
+        let i = usize::from(addr) * 2;
+        LittleEndian::read_u16(&self.ip.get_ref()[i..(i + 2)])
+    }
+
     pub fn write(&mut self, addr: Addr, val: u16) {
         let ip = self.ip();
         self.set_ip(addr);
@@ -426,104 +478,6 @@ impl Memory {
     }
 
     pub fn fetch_op(&mut self) -> Result<OpCode> {
-        let instr = self.next_instr()?;
-        let op_code = match instr {
-            0u16 => OpCode::Halt,
-            1u16 => {
-                OpCode::Set {
-                    reg: self.next_reg()?,
-                    val: self.next_val()?,
-                }
-            }
-            2u16 => OpCode::Push { val: self.next_val()? },
-            3u16 => OpCode::Pop { reg: self.next_reg()? },
-            4u16 => {
-                OpCode::Eq {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            5u16 => {
-                OpCode::Gt {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            6u16 => OpCode::Jmp { addr: self.next_val()? },
-            7u16 => {
-                OpCode::Jt {
-                    cond: self.next_val()?,
-                    addr: self.next_val()?,
-                }
-            }
-            8u16 => {
-                OpCode::Jf {
-                    cond: self.next_val()?,
-                    addr: self.next_val()?,
-                }
-            }
-            9u16 => {
-                OpCode::Add {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            10u16 => {
-                OpCode::Mult {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            11u16 => {
-                OpCode::Mod {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            12u16 => {
-                OpCode::And {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            13u16 => {
-                OpCode::Or {
-                    reg: self.next_reg()?,
-                    val1: self.next_val()?,
-                    val2: self.next_val()?,
-                }
-            }
-            14u16 => {
-                OpCode::Not {
-                    reg: self.next_reg()?,
-                    val: self.next_val()?,
-                }
-            }
-            15u16 => {
-                OpCode::Rmem {
-                    reg: self.next_reg()?,
-                    addr: self.next_val()?,
-                }
-            }
-            16u16 => {
-                OpCode::Wmem {
-                    addr: self.next_val()?,
-                    val: self.next_val()?,
-                }
-            }
-            17u16 => OpCode::Call { addr: self.next_val()? },
-            18u16 => OpCode::Ret,
-            19u16 => OpCode::Out { c: self.next_val()? },
-            20u16 => OpCode::In { reg: self.next_reg()? },
-            21u16 => OpCode::Noop,
-            u => bail!(ErrorKind::InvalidOpCode(u)),
-        };
-        Ok(op_code)
+        OpCode::fetch(|| self.next_u16())
     }
 }
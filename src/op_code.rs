@@ -1,5 +1,7 @@
 use std::{fmt, result};
 
+use try_from::TryFrom;
+
 use errors::*;
 use memory::{Addr, Register, RegisterSet, Target, Value};
 
@@ -11,374 +13,372 @@ pub trait OpAccess {
     }
 }
 
-#[derive(Debug)]
-pub enum OpCode {
-    Halt,
-    Set { reg: Register, val: Value },
-    Push { val: Value },
-    Pop { reg: Register },
-    Eq {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    Gt {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    Jmp { addr: Value },
-    Jt { cond: Value, addr: Value },
-    Jf { cond: Value, addr: Value },
-    Add {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    Mult {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    Mod {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    And {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    Or {
-        reg: Register,
-        val1: Value,
-        val2: Value,
-    },
-    Not { reg: Register, val: Value },
-    Rmem { reg: Register, addr: Value },
-    Wmem { addr: Value, val: Value },
-    Call { addr: Value },
-    Ret,
-    Out { c: Value },
-    In { reg: Register },
-    Noop,
+/// How an instruction uses one of its operands
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// register the result is stored to
+    Write,
+    /// value consumed by the instruction
+    Read,
+    /// address read from (`rmem`)
+    Load,
+    /// address written to (`wmem`)
+    Store,
+    /// jump or call destination
+    Dest,
 }
 
-impl fmt::Display for OpCode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+impl Role {
+    /// Whether the operand must name a register rather than hold a literal
+    pub fn is_register(&self) -> bool {
+        *self == Role::Write
+    }
+
+    /// Whether a register operand in this role is read; the register holding a
+    /// `wmem` address is read, and the memory it points to written
+    fn reads(&self) -> bool {
         match *self {
-            OpCode::Halt => write!(f, "halt"),
-            OpCode::Set { reg, val } => write!(f, "set {} {}", reg, val),
-            OpCode::Push { val } => write!(f, "push {}", val),
-            OpCode::Pop { reg } => write!(f, "pop {}", reg),
-            OpCode::Eq { reg, val1, val2 } => write!(f, "eq {} {} {}", reg, val1, val2),
-            OpCode::Gt { reg, val1, val2 } => write!(f, "gt {} {} {}", reg, val1, val2),
-            OpCode::Jmp { addr } => write!(f, "jmp {}", addr),
-            OpCode::Jt { cond, addr } => write!(f, "jt {} {}", cond, addr),
-            OpCode::Jf { cond, addr } => write!(f, "jf {} {}", cond, addr),
-            OpCode::Add { reg, val1, val2 } => write!(f, "add {} {} {}", reg, val1, val2),
-            OpCode::Mult { reg, val1, val2 } => write!(f, "mult {} {} {}", reg, val1, val2),
-            OpCode::Mod { reg, val1, val2 } => write!(f, "mod {} {} {}", reg, val1, val2),
-            OpCode::And { reg, val1, val2 } => write!(f, "and {} {} {}", reg, val1, val2),
-            OpCode::Or { reg, val1, val2 } => write!(f, "or {} {} {}", reg, val1, val2),
-            OpCode::Not { reg, val } => write!(f, "not {} {}", reg, val),
-            OpCode::Rmem { reg, addr } => write!(f, "rmem {} {}", reg, addr),
-            OpCode::Wmem { addr, val } => write!(f, "wmem {} {}", addr, val),
-            OpCode::Call { addr } => write!(f, "call {}", addr),
-            OpCode::Ret => write!(f, "ret"),
-            OpCode::Out { c } => write!(f, "out {}", c),
-            OpCode::In { reg } => write!(f, "in {}", reg),
-            OpCode::Noop => write!(f, "noop"),
+            Role::Read | Role::Load | Role::Store | Role::Dest => true,
+            Role::Write => false,
         }
     }
+
+    fn writes(&self) -> bool {
+        !self.reads()
+    }
 }
 
-impl OpAccess for OpCode {
-    fn reads(&self, tgt: &Target) -> bool {
-        match *self {
-            OpCode::Halt => false,
-            OpCode::Set { ref val, .. } => tgt == val,
-            OpCode::Push { ref val } => tgt == val,
-            OpCode::Pop { .. } => false,
-            OpCode::Eq { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::Gt { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::Jmp { ref addr } => tgt == addr,
-            OpCode::Jt { ref cond, ref addr } => tgt == cond || tgt == addr,
-            OpCode::Jf { ref cond, ref addr } => tgt == cond || tgt == addr,
-            OpCode::Add { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::Mult { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::Mod { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::And { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::Or { ref val1, ref val2, .. } => tgt == val1 || tgt == val2,
-            OpCode::Not { ref val, .. } => tgt == val,
-            OpCode::Rmem { ref addr, .. } => tgt == addr,
-            OpCode::Wmem { ref val, .. } => tgt == val,
-            OpCode::Call { ref addr } => tgt == addr,
-            OpCode::Ret => false,
-            OpCode::Out { ref c } => tgt == c,
-            OpCode::In { .. } => false,
-            OpCode::Noop => false,
-        }
+/// Where execution goes after an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    /// falls through to the next instruction
+    Normal,
+    /// unconditional jump
+    Jump,
+    /// conditional jump; falls through otherwise
+    Branch,
+    /// subroutine call; returns to the next instruction
+    Call,
+    Return,
+    Halt,
+}
+
+#[derive(Debug)]
+pub struct Instruction {
+    pub num: u16,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Role],
+    pub flow: Flow,
+}
+
+impl Instruction {
+    pub fn by_num(num: u16) -> Option<&'static Instruction> {
+        INSTRUCTIONS.get(num as usize)
     }
 
-    fn writes(&self, tgt: &Target) -> bool {
-        match *self {
-            OpCode::Halt => false,
-            OpCode::Set { ref reg, .. } => tgt == reg,
-            OpCode::Push { .. } => false,
-            OpCode::Pop { ref reg } => tgt == reg,
-            OpCode::Eq { ref reg, .. } => tgt == reg,
-            OpCode::Gt { ref reg, .. } => tgt == reg,
-            OpCode::Jmp { .. } => false,
-            OpCode::Jt { .. } => false,
-            OpCode::Jf { .. } => false,
-            OpCode::Add { ref reg, .. } => tgt == reg,
-            OpCode::Mult { ref reg, .. } => tgt == reg,
-            OpCode::Mod { ref reg, .. } => tgt == reg,
-            OpCode::And { ref reg, .. } => tgt == reg,
-            OpCode::Or { ref reg, .. } => tgt == reg,
-            OpCode::Not { ref reg, .. } => tgt == reg,
-            OpCode::Rmem { ref reg, .. } => tgt == reg,
-            OpCode::Wmem { ref addr, .. } => tgt == addr,
-            OpCode::Call { .. } => false,
-            OpCode::Ret => false,
-            OpCode::Out { .. } => false,
-            OpCode::In { ref reg } => tgt == reg,
-            OpCode::Noop => false,
+    pub fn by_mnemonic(mnemonic: &str) -> Option<&'static Instruction> {
+        INSTRUCTIONS.iter().find(|i| i.mnemonic == mnemonic)
+    }
+
+    /// Number of words the instruction occupies, including the op code
+    pub fn len(&self) -> u16 {
+        1 + self.operands.len() as u16
+    }
+
+    /// Whether execution may continue with the next instruction
+    pub fn falls_through(&self) -> bool {
+        match self.flow {
+            Flow::Jump | Flow::Return | Flow::Halt => false,
+            Flow::Normal | Flow::Branch | Flow::Call => true,
         }
     }
 }
 
-impl OpCode {
-    pub fn decode(&self, registers: &RegisterSet, ret: Option<u16>) -> Result<DecodedOpCode> {
-        Ok(match *self {
-            OpCode::Halt => DecodedOpCode::Halt,
-            OpCode::Out { c } => {
-                let c = registers.read(c) as u8 as char;
-                DecodedOpCode::Out { c: c }
-            }
-            OpCode::Noop => DecodedOpCode::Noop,
-            OpCode::Jmp { addr } => {
-                let addr = registers.read(addr).into();
-                DecodedOpCode::Jmp { addr: addr }
-            }
-            OpCode::Jt { cond, addr } => {
-                let cond = registers.read(cond);
-                let addr = registers.read(addr).into();
-                DecodedOpCode::Jt {
-                    cond: cond,
-                    addr: addr,
-                }
-            }
-            OpCode::Jf { cond, addr } => {
-                let cond = registers.read(cond);
-                let addr = registers.read(addr).into();
-                DecodedOpCode::Jf {
-                    cond: cond,
-                    addr: addr,
-                }
-            }
-            OpCode::Set { reg, val } => {
-                let val = registers.read(val);
-                DecodedOpCode::Set {
-                    reg: reg,
-                    val: val,
-                }
-            }
-            OpCode::Add { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::Add {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
-                }
-            }
-            OpCode::Mult { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::Mult {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
-                }
-            }
-            OpCode::Mod { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::Mod {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
-                }
+/// Operand types an `OpCode` field may have
+trait Operand: Sized {
+    fn from_word(w: u16) -> Result<Self>;
+    fn value(&self) -> Value;
+}
+
+impl Operand for Register {
+    fn from_word(w: u16) -> Result<Register> {
+        Register::try_from(w)
+    }
+
+    fn value(&self) -> Value {
+        Value::FromRegister(*self)
+    }
+}
+
+impl Operand for Value {
+    fn from_word(w: u16) -> Result<Value> {
+        Value::try_from(w)
+    }
+
+    fn value(&self) -> Value {
+        *self
+    }
+}
+
+/// Resolves an `OpCode` operand to the type of the matching `DecodedOpCode` field
+trait Decode<T> {
+    fn decode(&self, registers: &RegisterSet) -> T;
+}
+
+impl Decode<Register> for Register {
+    fn decode(&self, _: &RegisterSet) -> Register {
+        *self
+    }
+}
+
+impl Decode<u16> for Value {
+    fn decode(&self, registers: &RegisterSet) -> u16 {
+        registers.read(*self)
+    }
+}
+
+impl Decode<Addr> for Value {
+    fn decode(&self, registers: &RegisterSet) -> Addr {
+        registers.read(*self).into()
+    }
+}
+
+impl Decode<char> for Value {
+    fn decode(&self, registers: &RegisterSet) -> char {
+        registers.read(*self) as u8 as char
+    }
+}
+
+/// Field types a `DecodedOpCode` may have
+trait Decoded {
+    /// The register or memory location the field names, if any
+    fn target(&self) -> Option<Target> {
+        None
+    }
+}
+
+impl Decoded for Register {
+    fn target(&self) -> Option<Target> {
+        Some(Target::Reg(*self))
+    }
+}
+
+impl Decoded for Addr {
+    fn target(&self) -> Option<Target> {
+        Some(Target::Mem(*self))
+    }
+}
+
+impl Decoded for u16 {}
+
+impl Decoded for char {}
+
+fn is_target(field: &Decoded, tgt: &Target) -> bool {
+    match (field.target(), *tgt) {
+        (Some(Target::Reg(a)), Target::Reg(b)) => a == b,
+        (Some(Target::Mem(a)), Target::Mem(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Defines `OpCode`, the `INSTRUCTIONS` table and everything that only depends on
+/// an instruction's layout: fetching, encoding, display and access analysis, and
+/// the same again for `DecodedOpCode`, whose fields hold each operand resolved
+/// against the registers; `ret` also decodes the address it pops
+macro_rules! instructions {
+    ($($num:tt $mnemonic:tt $flow:ident $variant:ident
+       { $($field:ident: $ty:ident = $role:ident => $decoded:ident),* }
+       [$($popped:ident)*])*) => {
+        #[derive(Debug)]
+        pub enum OpCode {
+            $($variant { $($field: $ty),* },)*
+        }
+
+        #[derive(Debug)]
+        pub enum DecodedOpCode {
+            $($variant { $($field: $decoded,)* $($popped: Option<Addr>)* },)*
+        }
+
+        /// Every instruction, indexed by its number
+        pub static INSTRUCTIONS: &'static [Instruction] = &[
+            $(Instruction {
+                num: $num,
+                mnemonic: $mnemonic,
+                operands: &[$(Role::$role),*],
+                flow: Flow::$flow,
+            },)*
+        ];
+
+        impl OpCode {
+            /// Reads an instruction a word at a time from `next`
+            pub fn fetch<F>(mut next: F) -> Result<OpCode>
+                where F: FnMut() -> Result<u16>
+            {
+                let num = match Value::try_from(next()?)? {
+                    Value::Literal(i) => i,
+                    Value::FromRegister(r) => bail!(ErrorKind::NonLiteralOpCode(r)),
+                };
+                Ok(match num {
+                    $($num => OpCode::$variant { $($field: Operand::from_word(next()?)?),* },)*
+                    u => bail!(ErrorKind::InvalidOpCode(u)),
+                })
             }
-            OpCode::Eq { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::Eq {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
+
+            pub fn num(&self) -> u16 {
+                match *self {
+                    $(OpCode::$variant { .. } => $num,)*
                 }
             }
-            OpCode::Push { val } => {
-                let val = registers.read(val);
-                DecodedOpCode::Push { val: val }
-            }
-            OpCode::Pop { reg } => DecodedOpCode::Pop { reg: reg },
-            OpCode::Gt { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::Gt {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
+
+            /// Operands in encoding order; the roles are given by `instruction()`
+            pub fn operands(&self) -> Vec<Value> {
+                match *self {
+                    $(OpCode::$variant { $($field),* } => vec![$($field.value()),*],)*
                 }
             }
-            OpCode::And { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::And {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
-                }
+
+            /// Resolves the operands against `registers`; `ret` is the top of the
+            /// stack, which a `ret` instruction returns to
+            pub fn decode(&self,
+                          registers: &RegisterSet,
+                          ret: Option<u16>)
+                          -> Result<DecodedOpCode> {
+                Ok(match *self {
+                    $(OpCode::$variant { $($field),* } => DecodedOpCode::$variant {
+                        $($field: Decode::<$decoded>::decode(&$field, registers),)*
+                        $($popped: ret.map(Addr::from))*
+                    },)*
+                })
             }
-            OpCode::Or { reg, val1, val2 } => {
-                let val1 = registers.read(val1);
-                let val2 = registers.read(val2);
-                DecodedOpCode::Or {
-                    reg: reg,
-                    val1: val1,
-                    val2: val2,
+        }
+
+        impl DecodedOpCode {
+            /// The register or memory location this instruction will store to, if any
+            pub fn write_target(&self) -> Option<Target> {
+                match *self {
+                    $(DecodedOpCode::$variant { $(ref $field,)* .. } => {
+                        $(if Role::$role == Role::Write || Role::$role == Role::Store {
+                            return $field.target();
+                        })*
+                        None
+                    })*
                 }
             }
-            OpCode::Not { reg, val } => {
-                let val = registers.read(val);
-                DecodedOpCode::Not {
-                    reg: reg,
-                    val: val,
+        }
+
+        impl fmt::Display for DecodedOpCode {
+            fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+                match *self {
+                    $(DecodedOpCode::$variant { $(ref $field,)* $(ref $popped)* } => {
+                        write!(f, "{}", $mnemonic)?;
+                        $(write!(f, " {}", $field)?;)*
+                        $(if let Some(a) = *$popped {
+                            write!(f, " {}", a)?;
+                        })*
+                    })*
                 }
+                Ok(())
             }
-            OpCode::Call { addr } => {
-                let addr = registers.read(addr).into();
-                DecodedOpCode::Call { addr: addr }
-            }
-            OpCode::Rmem { reg, addr } => {
-                let addr = registers.read(addr).into();
-                DecodedOpCode::Rmem {
-                    reg: reg,
-                    addr: addr,
+        }
+
+        impl OpAccess for DecodedOpCode {
+            // operands have already been resolved, so what remains read is the
+            // memory `rmem` loads from
+            fn reads(&self, tgt: &Target) -> bool {
+                match *self {
+                    $(DecodedOpCode::$variant { $(ref $field,)* .. } => {
+                        false $(|| (Role::$role == Role::Load && is_target($field, tgt)))*
+                    })*
                 }
             }
-            OpCode::Wmem { addr, val } => {
-                let addr = registers.read(addr).into();
-                let val = registers.read(val);
-                DecodedOpCode::Wmem {
-                    addr: addr,
-                    val: val,
+
+            fn writes(&self, tgt: &Target) -> bool {
+                match *self {
+                    $(DecodedOpCode::$variant { $(ref $field,)* .. } => {
+                        false $(|| ((Role::$role == Role::Write || Role::$role == Role::Store) &&
+                                    is_target($field, tgt)))*
+                    })*
                 }
             }
-            OpCode::Ret => {
-                let addr = ret.map(Addr::from);
-                DecodedOpCode::Ret { addr: addr }
-            }
-            OpCode::In { reg } => DecodedOpCode::In { reg: reg },
-        })
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum DecodedOpCode {
-    Halt,
-    Set { reg: Register, val: u16 },
-    Push { val: u16 },
-    Pop { reg: Register },
-    Eq { reg: Register, val1: u16, val2: u16 },
-    Gt { reg: Register, val1: u16, val2: u16 },
-    Jmp { addr: Addr },
-    Jt { cond: u16, addr: Addr },
-    Jf { cond: u16, addr: Addr },
-    Add { reg: Register, val1: u16, val2: u16 },
-    Mult { reg: Register, val1: u16, val2: u16 },
-    Mod { reg: Register, val1: u16, val2: u16 },
-    And { reg: Register, val1: u16, val2: u16 },
-    Or { reg: Register, val1: u16, val2: u16 },
-    Not { reg: Register, val: u16 },
-    Rmem { reg: Register, addr: Addr },
-    Wmem { addr: Addr, val: u16 },
-    Call { addr: Addr },
-    Ret { addr: Option<Addr> },
-    Out { c: char },
-    In { reg: Register },
-    Noop,
+instructions! {
+    0 "halt" Halt Halt {} []
+    1 "set" Normal Set { reg: Register = Write => Register, val: Value = Read => u16 } []
+    2 "push" Normal Push { val: Value = Read => u16 } []
+    3 "pop" Normal Pop { reg: Register = Write => Register } []
+    4 "eq" Normal Eq { reg: Register = Write => Register,
+                       val1: Value = Read => u16, val2: Value = Read => u16 } []
+    5 "gt" Normal Gt { reg: Register = Write => Register,
+                       val1: Value = Read => u16, val2: Value = Read => u16 } []
+    6 "jmp" Jump Jmp { addr: Value = Dest => Addr } []
+    7 "jt" Branch Jt { cond: Value = Read => u16, addr: Value = Dest => Addr } []
+    8 "jf" Branch Jf { cond: Value = Read => u16, addr: Value = Dest => Addr } []
+    9 "add" Normal Add { reg: Register = Write => Register,
+                         val1: Value = Read => u16, val2: Value = Read => u16 } []
+    10 "mult" Normal Mult { reg: Register = Write => Register,
+                            val1: Value = Read => u16, val2: Value = Read => u16 } []
+    11 "mod" Normal Mod { reg: Register = Write => Register,
+                          val1: Value = Read => u16, val2: Value = Read => u16 } []
+    12 "and" Normal And { reg: Register = Write => Register,
+                          val1: Value = Read => u16, val2: Value = Read => u16 } []
+    13 "or" Normal Or { reg: Register = Write => Register,
+                        val1: Value = Read => u16, val2: Value = Read => u16 } []
+    14 "not" Normal Not { reg: Register = Write => Register, val: Value = Read => u16 } []
+    15 "rmem" Normal Rmem { reg: Register = Write => Register, addr: Value = Load => Addr } []
+    16 "wmem" Normal Wmem { addr: Value = Store => Addr, val: Value = Read => u16 } []
+    17 "call" Call Call { addr: Value = Dest => Addr } []
+    18 "ret" Return Ret {} [addr]
+    19 "out" Normal Out { c: Value = Read => char } []
+    20 "in" Normal In { reg: Register = Write => Register } []
+    21 "noop" Normal Noop {} []
+}
+
+impl OpCode {
+    pub fn instruction(&self) -> &'static Instruction {
+        &INSTRUCTIONS[self.num() as usize]
+    }
+
+    /// Operands paired with the way the instruction uses them
+    pub fn roles(&self) -> Vec<(Role, Value)> {
+        self.instruction().operands.iter().cloned().zip(self.operands()).collect()
+    }
+
+    /// Jump or call destination, when it is a literal
+    pub fn dest(&self) -> Option<u16> {
+        for (role, v) in self.roles() {
+            if let (Role::Dest, Value::Literal(t)) = (role, v) {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    pub fn encode(&self) -> Vec<u16> {
+        let mut words = vec![self.num()];
+        words.extend(self.operands().into_iter().map(u16::from));
+        words
+    }
 }
 
-impl fmt::Display for DecodedOpCode {
+impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
-        match *self {
-            DecodedOpCode::Halt => write!(f, "halt"),
-            DecodedOpCode::Set { reg, val } => write!(f, "set {} {}", reg, val),
-            DecodedOpCode::Push { val } => write!(f, "push {}", val),
-            DecodedOpCode::Pop { reg } => write!(f, "pop {}", reg),
-            DecodedOpCode::Eq { reg, val1, val2 } => write!(f, "eq {} {} {}", reg, val1, val2),
-            DecodedOpCode::Gt { reg, val1, val2 } => write!(f, "gt {} {} {}", reg, val1, val2),
-            DecodedOpCode::Jmp { addr } => write!(f, "jmp {}", addr),
-            DecodedOpCode::Jt { cond, addr } => write!(f, "jt {} {}", cond, addr),
-            DecodedOpCode::Jf { cond, addr } => write!(f, "jf {} {}", cond, addr),
-            DecodedOpCode::Add { reg, val1, val2 } => write!(f, "add {} {} {}", reg, val1, val2),
-            DecodedOpCode::Mult { reg, val1, val2 } => write!(f, "mult {} {} {}", reg, val1, val2),
-            DecodedOpCode::Mod { reg, val1, val2 } => write!(f, "mod {} {} {}", reg, val1, val2),
-            DecodedOpCode::And { reg, val1, val2 } => write!(f, "and {} {} {}", reg, val1, val2),
-            DecodedOpCode::Or { reg, val1, val2 } => write!(f, "or {} {} {}", reg, val1, val2),
-            DecodedOpCode::Not { reg, val } => write!(f, "not {} {}", reg, val),
-            DecodedOpCode::Rmem { reg, addr } => write!(f, "rmem {} {}", reg, addr),
-            DecodedOpCode::Wmem { addr, val } => write!(f, "wmem {} {}", addr, val),
-            DecodedOpCode::Call { addr } => write!(f, "call {}", addr),
-            DecodedOpCode::Ret { addr } if addr.is_some() => write!(f, "ret {}", addr.unwrap()),
-            DecodedOpCode::Ret { .. } => write!(f, "ret"),
-            DecodedOpCode::Out { c } => write!(f, "out {}", c),
-            DecodedOpCode::In { reg } => write!(f, "in {}", reg),
-            DecodedOpCode::Noop => write!(f, "noop"),
+        write!(f, "{}", self.instruction().mnemonic)?;
+        for v in self.operands() {
+            write!(f, " {}", v)?;
         }
+        Ok(())
     }
 }
 
-impl OpAccess for DecodedOpCode {
+impl OpAccess for OpCode {
     fn reads(&self, tgt: &Target) -> bool {
-        // pretty much all args have already been resolved in DecodeOpCode; the only
-        // possible read is from the rmem instruction
-        match *self {
-            DecodedOpCode::Rmem { ref addr, .. } => tgt == addr,
-            _ => false,
-        }
+        self.roles().iter().any(|&(role, ref v)| role.reads() && tgt == v)
     }
 
     fn writes(&self, tgt: &Target) -> bool {
-        match *self {
-            DecodedOpCode::Halt => false,
-            DecodedOpCode::Set { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Push { .. } => false,
-            DecodedOpCode::Pop { ref reg } => tgt == reg,
-            DecodedOpCode::Eq { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Gt { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Jmp { .. } => false,
-            DecodedOpCode::Jt { .. } => false,
-            DecodedOpCode::Jf { .. } => false,
-            DecodedOpCode::Add { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Mult { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Mod { ref reg, .. } => tgt == reg,
-            DecodedOpCode::And { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Or { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Not { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Rmem { ref reg, .. } => tgt == reg,
-            DecodedOpCode::Wmem { ref addr, .. } => tgt == addr,
-            DecodedOpCode::Call { .. } => false,
-            DecodedOpCode::Ret { .. } => false,
-            DecodedOpCode::Out { .. } => false,
-            DecodedOpCode::In { ref reg } => tgt == reg,
-            DecodedOpCode::Noop => false,
-        }
+        self.roles().iter().any(|&(role, ref v)| role.writes() && tgt == v)
     }
 }
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use asm;
+use errors::*;
+use memory::{Addr, Memory};
+use symbols::split_word;
+
+/// Words written to, or expected at, consecutive addresses
+#[derive(Clone, Debug)]
+pub struct Edit {
+    pub addr: Addr,
+    pub words: Vec<u16>,
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words = self.words.iter().map(|w| format!("0x{:04x}", w)).collect::<Vec<_>>();
+        write!(f, "{:?}: {}", self.addr, words.join(" "))
+    }
+}
+
+/// A named set of edits to memory, applied only if memory holds the expected
+/// words. Patch files are made up of lines such as
+///
+/// ```text
+/// # lines starting with '#' are ignored
+/// patch teleporter
+/// expect 0x1571 0x0007 0x8000 0x15cb
+/// words 0x1571 0x0015 0x0015 0x0015
+/// asm 0x156b set r0 6
+/// asm 0x1587
+///     set r1 5
+///     ret
+/// end
+/// ```
+///
+/// `words` takes anything `.data` does, and `asm` assembles either the rest of
+/// the line or the lines up to `end` at the address given. Edits before the
+/// first `patch` line belong to a patch named after the file.
+#[derive(Clone, Debug)]
+pub struct Patch {
+    pub name: String,
+    /// words memory must hold for the patch to apply
+    pub expect: Vec<Edit>,
+    pub edits: Vec<Edit>,
+}
+
+impl Patch {
+    fn new(name: &str) -> Patch {
+        Patch {
+            name: name.to_owned(),
+            expect: Vec::new(),
+            edits: Vec::new(),
+        }
+    }
+
+    /// Checks the expected words, then writes the edits, returning the words
+    /// they replaced
+    pub fn apply(&self, mem: &mut Memory) -> Result<Vec<Edit>> {
+        for e in &self.expect {
+            for (i, &w) in e.words.iter().enumerate() {
+                let addr = Addr::from(u16::from(e.addr) + i as u16);
+                let found = mem.peek(addr);
+                if found != w {
+                    bail!("patch {}: expected 0x{:04x} at {:?}, found 0x{:04x}",
+                          self.name,
+                          w,
+                          addr,
+                          found);
+                }
+            }
+        }
+        let original = self.edits.iter().map(|e| read(mem, e.addr, e.words.len())).collect();
+        for e in &self.edits {
+            write(mem, e);
+        }
+        Ok(original)
+    }
+}
+
+/// Restores the words `Patch::apply` returned, undoing the patch
+pub fn revert(mem: &mut Memory, original: &[Edit]) {
+    for e in original.iter().rev() {
+        write(mem, e);
+    }
+}
+
+fn read(mem: &Memory, addr: Addr, n: usize) -> Edit {
+    Edit {
+        addr: addr,
+        words: (0..n).map(|i| mem.peek(Addr::from(u16::from(addr) + i as u16))).collect(),
+    }
+}
+
+fn write(mem: &mut Memory, e: &Edit) {
+    for (i, &w) in e.words.iter().enumerate() {
+        mem.write(Addr::from(u16::from(e.addr) + i as u16), w);
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Patch>> {
+    let mut src = String::new();
+    File::open(&path)?.read_to_string(&mut src)?;
+    let name = path.as_ref()
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "patch".to_owned());
+    parse(&src, &name)
+}
+
+/// Parses a patch file; `name` names any edits before the first `patch` line
+pub fn parse(src: &str, name: &str) -> Result<Vec<Patch>> {
+    let mut patches = vec![Patch::new(name)];
+    let mut lines = src.lines().enumerate();
+    while let Some((n, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (cmd, rest) = split_word(line);
+        let (addr, rest) = split_word(rest);
+        let at = || format!("line {}", n + 1);
+        if cmd == "patch" {
+            if addr.is_empty() || !rest.is_empty() {
+                bail!("{}: expected 'patch name'", at());
+            }
+            patches.push(Patch::new(addr));
+            continue;
+        }
+        let addr = Addr::from_str(addr).chain_err(&at)?;
+        let edit = match cmd {
+            "words" | "expect" => assemble(&format!(".data {}", rest), addr).chain_err(&at)?,
+            "asm" if !rest.is_empty() => assemble(rest, addr).chain_err(&at)?,
+            "asm" => {
+                let mut src = String::new();
+                loop {
+                    match lines.next() {
+                        Some((_, l)) if l.trim() == "end" => break,
+                        Some((_, l)) => {
+                            src.push_str(l);
+                            src.push('\n');
+                        }
+                        None => bail!("{}: 'asm' block without 'end'", at()),
+                    }
+                }
+                assemble(&src, addr).chain_err(&at)?
+            }
+            c => bail!("{}: unknown patch command '{}'", at(), c),
+        };
+        let patch = patches.last_mut().unwrap();
+        if cmd == "expect" {
+            patch.expect.push(edit);
+        } else {
+            patch.edits.push(edit);
+        }
+    }
+    Ok(patches.into_iter().filter(|p| !p.edits.is_empty() || !p.expect.is_empty()).collect())
+}
+
+fn assemble(src: &str, addr: Addr) -> Result<Edit> {
+    let a = asm::assemble_at(src, addr)?;
+    Ok(Edit {
+        addr: a.origin,
+        words: a.words,
+    })
+}
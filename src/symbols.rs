@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use asm;
+use errors::*;
+use memory::Addr;
+
+/// Furthest past a function or label an address is still described relative to it
+const MAX_OFFSET: u16 = 0x200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// entry point of a routine
+    Function,
+    /// any other code address
+    Label,
+    /// a memory word holding data
+    Var,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Kind::Function => "fn",
+            Kind::Label => "label",
+            Kind::Var => "var",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Kind> {
+        match s {
+            "fn" => Ok(Kind::Function),
+            "label" => Ok(Kind::Label),
+            "var" => Ok(Kind::Var),
+            k => bail!("unknown symbol kind '{}'; expected fn, label or var", k),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: Kind,
+}
+
+/// Names and comments for addresses, kept in a text file of lines such as
+///
+/// ```text
+/// # lines starting with '#' are ignored
+/// fn print 0x05fb
+/// label r7_test 0x154b
+/// var room 0x0aac
+/// comment 0x154b r0 is the return value
+/// ```
+///
+/// There is at most one name per address and one address per name.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<u16, Symbol>,
+    by_name: BTreeMap<String, u16>,
+    comments: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Symbols> {
+        let mut src = String::new();
+        File::open(path)?.read_to_string(&mut src)?;
+        Symbols::parse(&src)
+    }
+
+    pub fn parse(src: &str) -> Result<Symbols> {
+        let mut symbols = Symbols::new();
+        for (n, line) in src.lines().enumerate() {
+            symbols.parse_line(line).chain_err(|| format!("line {}", n + 1))?;
+        }
+        Ok(symbols)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (kind, rest) = split_word(line);
+        let (first, rest) = split_word(rest);
+        if kind == "comment" {
+            self.set_comment(Addr::from_str(first)?, rest);
+            return Ok(());
+        }
+        if first.is_empty() || rest.is_empty() {
+            bail!("expected '<fn|label|var> name addr' or 'comment addr text'");
+        }
+        self.define(first, Addr::from_str(rest)?, Kind::from_str(kind)?)
+    }
+
+    /// Writes the symbols back out in the form `parse` reads
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        for (&a, s) in &self.by_addr {
+            writeln!(w, "{} {} {:?}", s.kind, s.name, Addr::from(a))?;
+        }
+        for (&a, c) in &self.comments {
+            writeln!(w, "comment {:?} {}", Addr::from(a), c)?;
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Names `addr`, replacing any other name for it and any other address
+    /// with this name
+    pub fn define(&mut self, name: &str, addr: Addr, kind: Kind) -> Result<()> {
+        if !asm::is_label(name) || (name.len() == 2 && name.starts_with('r')) {
+            bail!("invalid symbol name '{}'", name);
+        }
+        self.remove(name);
+        let a = u16::from(addr);
+        if let Some(old) = self.by_addr.insert(a,
+                                               Symbol {
+                                                   name: name.to_owned(),
+                                                   kind: kind,
+                                               }) {
+            self.by_name.remove(&old.name);
+        }
+        self.by_name.insert(name.to_owned(), a);
+        Ok(())
+    }
+
+    /// Forgets `name`, returning the address it named
+    pub fn remove(&mut self, name: &str) -> Option<Addr> {
+        self.by_name.remove(name).map(|a| {
+            self.by_addr.remove(&a);
+            Addr::from(a)
+        })
+    }
+
+    /// Attaches `text` to `addr`; empty text removes the comment
+    pub fn set_comment(&mut self, addr: Addr, text: &str) {
+        if text.is_empty() {
+            self.comments.remove(&u16::from(addr));
+        } else {
+            self.comments.insert(u16::from(addr), text.to_owned());
+        }
+    }
+
+    pub fn addr(&self, name: &str) -> Option<Addr> {
+        self.by_name.get(name).map(|&a| Addr::from(a))
+    }
+
+    /// Either a symbol name or a literal address
+    pub fn parse_addr(&self, s: &str) -> Result<Addr> {
+        match self.addr(s) {
+            Some(a) => Ok(a),
+            None => Addr::from_str(s).chain_err(|| format!("unknown symbol or invalid address '{}'", s)),
+        }
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<(Addr, &Symbol)> {
+        self.by_name.get(name).map(|a| (Addr::from(*a), &self.by_addr[a]))
+    }
+
+    pub fn get(&self, addr: Addr) -> Option<&Symbol> {
+        self.by_addr.get(&u16::from(addr))
+    }
+
+    pub fn name(&self, addr: Addr) -> Option<&str> {
+        self.get(addr).map(|s| &s.name[..])
+    }
+
+    pub fn comment(&self, addr: Addr) -> Option<&str> {
+        self.comments.get(&u16::from(addr)).map(|c| &c[..])
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = (Addr, &'a Symbol)> + 'a> {
+        Box::new(self.by_addr.iter().map(|(&a, s)| (Addr::from(a), s)))
+    }
+
+    pub fn comments<'a>(&'a self) -> Box<Iterator<Item = (Addr, &'a str)> + 'a> {
+        Box::new(self.comments.iter().map(|(&a, c)| (Addr::from(a), &c[..])))
+    }
+
+    /// `addr` as a symbol, e.g. `print`, or as an offset from the nearest
+    /// function or label before it, e.g. `print+0x3`
+    pub fn locate(&self, addr: Addr) -> Option<String> {
+        let a = u16::from(addr);
+        if let Some(s) = self.by_addr.get(&a) {
+            return Some(s.name.clone());
+        }
+        self.by_addr
+            .range(..a)
+            .rev()
+            .find(|&(_, s)| s.kind != Kind::Var)
+            .and_then(|(&start, s)| if a - start <= MAX_OFFSET {
+                Some(format!("{}+0x{:x}", s.name, a - start))
+            } else {
+                None
+            })
+    }
+
+    /// `addr` followed by its symbolic location, if it has one, e.g. `0x05fe <print+0x3>`
+    pub fn describe(&self, addr: Addr) -> String {
+        match self.locate(addr) {
+            Some(l) => format!("{:?} <{}>", addr, l),
+            None => format!("{:?}", addr),
+        }
+    }
+}
+
+/// The first word of `s` and the rest, both trimmed
+pub fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    }
+}
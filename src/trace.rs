@@ -0,0 +1,684 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::*;
+use machine::Inspectable;
+use memory::{Addr, AddrRange, Register, RegisterSet};
+use op_code::{DecodedOpCode, Instruction, OpCode};
+
+/// Start of a binary trace, followed by a format version
+const MAGIC: &'static [u8] = b"SYNT";
+const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// fixed layout little-endian records, after a short header
+    Binary,
+    /// one JSON object per line
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "bin" => Ok(Format::Binary),
+            "json" => Ok(Format::Json),
+            f => bail!("unknown trace format '{}'; expected bin or json", f),
+        }
+    }
+}
+
+/// A change made by executing an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    Reg { reg: u8, value: u16 },
+    Mem { addr: u16, old: u16, new: u16 },
+    Push(u16),
+    Pop(u16),
+    Out(char),
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Effect::Reg { reg, value } => write!(f, "r{}=0x{:04x}", reg, value),
+            Effect::Mem { addr, old, new } => {
+                write!(f, "[0x{:04x}]=0x{:04x} (was 0x{:04x})", addr, new, old)
+            }
+            Effect::Push(v) => write!(f, "push 0x{:04x}", v),
+            Effect::Pop(v) => write!(f, "pop 0x{:04x}", v),
+            Effect::Out(c) => write!(f, "out {:?}", c),
+        }
+    }
+}
+
+/// One executed instruction: the machine state before it ran, and what it changed
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub step: u64,
+    pub ip: u16,
+    /// the instruction's words
+    pub raw: Vec<u16>,
+    pub regs: [u16; 8],
+    /// stack length
+    pub sp: u16,
+    /// calls made and not yet returned from
+    pub depth: u16,
+    pub effects: Vec<Effect>,
+    /// top of the stack, and the address and old value of any `wmem`, until `finish`
+    top: Option<u16>,
+    store: Option<(u16, u16)>,
+}
+
+impl Record {
+    /// Captures the state before the instruction at the machine's ip runs
+    pub fn start(step: u64, depth: u16, m: &mut Inspectable) -> Result<Record> {
+        let ip = match m.ip() {
+            Some(ip) => ip,
+            None => bail!("machine halted"),
+        };
+        let (op, decoded) = m.peek_instr()?;
+        let a = u16::from(ip);
+        let mem = m.memory();
+        let mut regs = [0; 8];
+        for (i, r) in m.registers().into_iter().enumerate() {
+            regs[i] = r;
+        }
+        Ok(Record {
+            step: step,
+            ip: a,
+            raw: (0..op.instruction().len()).map(|i| mem.peek(Addr::from(a + i))).collect(),
+            regs: regs,
+            sp: m.stack().len() as u16,
+            depth: depth,
+            effects: Vec::new(),
+            top: m.stack().last().cloned(),
+            store: match decoded {
+                DecodedOpCode::Wmem { addr, .. } => Some((u16::from(addr), mem.peek(addr))),
+                _ => None,
+            },
+        })
+    }
+
+    /// Records the effects of the instruction from the state after it ran, and
+    /// the character it output, if any
+    pub fn finish(&mut self, m: &Inspectable, out: Option<char>) {
+        for (i, r) in m.registers().into_iter().enumerate() {
+            if r != self.regs[i] {
+                self.effects.push(Effect::Reg {
+                    reg: i as u8,
+                    value: r,
+                });
+            }
+        }
+        if let Some((addr, old)) = self.store.take() {
+            self.effects.push(Effect::Mem {
+                addr: addr,
+                old: old,
+                new: m.memory().peek(Addr::from(addr)),
+            });
+        }
+        let sp = m.stack().len() as u16;
+        if sp > self.sp {
+            self.effects.push(Effect::Push(m.stack().last().cloned().unwrap_or(0)));
+        } else if sp < self.sp {
+            self.effects.push(Effect::Pop(self.top.unwrap_or(0)));
+        }
+        if let Some(c) = out {
+            self.effects.push(Effect::Out(c));
+        }
+    }
+
+    pub fn op(&self) -> Result<OpCode> {
+        let mut words = self.raw.iter();
+        OpCode::fetch(|| words.next().cloned().ok_or_else(|| "truncated instruction".into()))
+    }
+
+    /// The instruction with its operands resolved against the recorded registers
+    pub fn decoded(&self) -> Result<DecodedOpCode> {
+        let popped = self.effects.iter().filter_map(|e| match *e {
+            Effect::Pop(v) => Some(v),
+            _ => None,
+        });
+        self.op()?.decode(&RegisterSet::load(self.regs), popped.last())
+    }
+
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_u64::<LittleEndian>(self.step)?;
+        w.write_u16::<LittleEndian>(self.ip)?;
+        w.write_u8(self.raw.len() as u8)?;
+        for &v in self.raw.iter().chain(self.regs.iter()) {
+            w.write_u16::<LittleEndian>(v)?;
+        }
+        w.write_u16::<LittleEndian>(self.sp)?;
+        w.write_u16::<LittleEndian>(self.depth)?;
+        w.write_u8(self.effects.len() as u8)?;
+        for e in &self.effects {
+            match *e {
+                Effect::Reg { reg, value } => {
+                    w.write_u8(0)?;
+                    w.write_u8(reg)?;
+                    w.write_u16::<LittleEndian>(value)?;
+                }
+                Effect::Mem { addr, old, new } => {
+                    w.write_u8(1)?;
+                    w.write_u16::<LittleEndian>(addr)?;
+                    w.write_u16::<LittleEndian>(old)?;
+                    w.write_u16::<LittleEndian>(new)?;
+                }
+                Effect::Push(v) => {
+                    w.write_u8(2)?;
+                    w.write_u16::<LittleEndian>(v)?;
+                }
+                Effect::Pop(v) => {
+                    w.write_u8(3)?;
+                    w.write_u16::<LittleEndian>(v)?;
+                }
+                Effect::Out(c) => {
+                    w.write_u8(4)?;
+                    w.write_u8(c as u8)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a record written by `write_binary`, or `None` at the end of the trace
+    pub fn read_binary<R: BufRead>(r: &mut R) -> Result<Option<Record>> {
+        if r.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let step = r.read_u64::<LittleEndian>()?;
+        let ip = r.read_u16::<LittleEndian>()?;
+        let n = r.read_u8()?;
+        let mut raw = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            raw.push(r.read_u16::<LittleEndian>()?);
+        }
+        let mut regs = [0; 8];
+        for reg in &mut regs {
+            *reg = r.read_u16::<LittleEndian>()?;
+        }
+        let sp = r.read_u16::<LittleEndian>()?;
+        let depth = r.read_u16::<LittleEndian>()?;
+        let n = r.read_u8()?;
+        let mut effects = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            effects.push(match r.read_u8()? {
+                0 => {
+                    Effect::Reg {
+                        reg: r.read_u8()?,
+                        value: r.read_u16::<LittleEndian>()?,
+                    }
+                }
+                1 => {
+                    Effect::Mem {
+                        addr: r.read_u16::<LittleEndian>()?,
+                        old: r.read_u16::<LittleEndian>()?,
+                        new: r.read_u16::<LittleEndian>()?,
+                    }
+                }
+                2 => Effect::Push(r.read_u16::<LittleEndian>()?),
+                3 => Effect::Pop(r.read_u16::<LittleEndian>()?),
+                4 => Effect::Out(r.read_u8()? as char),
+                t => bail!("unknown trace effect {} at step {}", t, step),
+            });
+        }
+        Ok(Some(Record {
+            step: step,
+            ip: ip,
+            raw: raw,
+            regs: regs,
+            sp: sp,
+            depth: depth,
+            effects: effects,
+            top: None,
+            store: None,
+        }))
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> Result<()> {
+        let list = |vs: &[u16]| vs.iter().map(|v| format!("{}", v)).collect::<Vec<_>>().join(",");
+        let (op, decoded) = match (self.op(), self.decoded()) {
+            (Ok(op), Ok(d)) => (format!("{}", op), format!("{}", d)),
+            _ => (String::new(), String::new()),
+        };
+        write!(w,
+               "{{\"step\":{},\"ip\":{},\"raw\":[{}],\"op\":{},\"decoded\":{},\"regs\":[{}],\
+                \"sp\":{},\"depth\":{},\"effects\":[",
+               self.step,
+               self.ip,
+               list(&self.raw),
+               json::quote(&op),
+               json::quote(&decoded),
+               list(&self.regs),
+               self.sp,
+               self.depth)?;
+        for (i, e) in self.effects.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            match *e {
+                Effect::Reg { reg, value } => write!(w, "{{\"reg\":{},\"value\":{}}}", reg, value)?,
+                Effect::Mem { addr, old, new } => {
+                    write!(w, "{{\"mem\":{},\"old\":{},\"new\":{}}}", addr, old, new)?
+                }
+                Effect::Push(v) => write!(w, "{{\"push\":{}}}", v)?,
+                Effect::Pop(v) => write!(w, "{{\"pop\":{}}}", v)?,
+                Effect::Out(c) => write!(w, "{{\"out\":{}}}", json::quote(&c.to_string()))?,
+            }
+        }
+        writeln!(w, "]}}")?;
+        Ok(())
+    }
+
+    /// Parses a line written by `write_json`
+    pub fn from_json(line: &str) -> Result<Record> {
+        let v = json::parse(line)?;
+        let num = |v: &json::Value, k: &str| v.get(k).and_then(|n| n.num()).ok_or_else(|| {
+            Error::from(format!("missing or invalid '{}'", k))
+        });
+        let nums = |k: &str| -> Result<Vec<u16>> {
+            match v.get(k) {
+                Some(&json::Value::Arr(ref vs)) => {
+                    vs.iter()
+                        .map(|n| n.num().map(|n| n as u16).ok_or_else(|| "invalid number".into()))
+                        .collect()
+                }
+                _ => bail!("missing or invalid '{}'", k),
+            }
+        };
+        let mut regs = [0; 8];
+        let rs = nums("regs")?;
+        if rs.len() != 8 {
+            bail!("expected 8 registers");
+        }
+        regs.copy_from_slice(&rs);
+        let mut effects = Vec::new();
+        if let Some(&json::Value::Arr(ref es)) = v.get("effects") {
+            for e in es {
+                effects.push(if e.get("reg").is_some() {
+                    Effect::Reg {
+                        reg: num(e, "reg")? as u8,
+                        value: num(e, "value")? as u16,
+                    }
+                } else if e.get("mem").is_some() {
+                    Effect::Mem {
+                        addr: num(e, "mem")? as u16,
+                        old: num(e, "old")? as u16,
+                        new: num(e, "new")? as u16,
+                    }
+                } else if e.get("push").is_some() {
+                    Effect::Push(num(e, "push")? as u16)
+                } else if e.get("pop").is_some() {
+                    Effect::Pop(num(e, "pop")? as u16)
+                } else {
+                    match e.get("out") {
+                        Some(&json::Value::Str(ref s)) if s.chars().count() == 1 => {
+                            Effect::Out(s.chars().next().unwrap())
+                        }
+                        _ => bail!("unknown effect"),
+                    }
+                });
+            }
+        }
+        Ok(Record {
+            step: num(&v, "step")?,
+            ip: num(&v, "ip")? as u16,
+            raw: nums("raw")?,
+            regs: regs,
+            sp: num(&v, "sp")? as u16,
+            depth: num(&v, "depth")? as u16,
+            effects: effects,
+            top: None,
+            store: None,
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>8} {:?}: ", self.step, Addr::from(self.ip))?;
+        match (self.op(), self.decoded()) {
+            (Ok(op), Ok(d)) => write!(f, "{} | {}", op, d)?,
+            (Ok(op), Err(_)) => write!(f, "{}", op)?,
+            _ => write!(f, "{:?}", self.raw)?,
+        }
+        for e in &self.effects {
+            write!(f, "  {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes records in either format
+pub struct Writer<W: Write> {
+    w: W,
+    format: Format,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut w: W, format: Format) -> Result<Writer<W>> {
+        if format == Format::Binary {
+            w.write_all(MAGIC)?;
+            w.write_u16::<LittleEndian>(VERSION)?;
+        }
+        Ok(Writer {
+            w: w,
+            format: format,
+        })
+    }
+
+    pub fn write(&mut self, r: &Record) -> Result<()> {
+        match self.format {
+            Format::Binary => r.write_binary(&mut self.w),
+            Format::Json => r.write_json(&mut self.w),
+        }
+    }
+}
+
+/// Reads the records of a trace in either format, telling them apart by the
+/// binary header
+pub struct Reader<R: BufRead> {
+    r: R,
+    format: Format,
+    line: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(mut r: R) -> Result<Reader<R>> {
+        let binary = r.fill_buf()?.starts_with(MAGIC);
+        if binary {
+            r.consume(MAGIC.len());
+            let version = r.read_u16::<LittleEndian>()?;
+            if version != VERSION {
+                bail!("unsupported trace version {}", version);
+            }
+        }
+        Ok(Reader {
+            r: r,
+            format: if binary { Format::Binary } else { Format::Json },
+            line: 0,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    fn next_json(&mut self) -> Result<Option<Record>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            self.line += 1;
+            if self.r.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        let n = self.line;
+        Record::from_json(&line).map(Some).chain_err(|| format!("line {}", n))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        let r = match self.format {
+            Format::Binary => Record::read_binary(&mut self.r),
+            Format::Json => self.next_json(),
+        };
+        match r {
+            Ok(Some(r)) => Some(Ok(r)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Which records a query selects; every given condition must hold
+#[derive(Default)]
+pub struct Filter {
+    pub ips: Vec<AddrRange>,
+    pub ops: Vec<&'static Instruction>,
+    /// register values before the instruction runs
+    pub regs: Vec<(usize, u16)>,
+    /// inclusive bounds on the call depth
+    pub depth: Option<(u16, u16)>,
+}
+
+impl Filter {
+    /// Parses a register condition such as `r7=0x8000`
+    pub fn parse_reg(s: &str) -> Result<(usize, u16)> {
+        let mut parts = s.splitn(2, '=');
+        let (reg, v) = match (parts.next(), parts.next()) {
+            (Some(reg), Some(v)) if reg.starts_with('r') => (&reg[1..], v.trim()),
+            _ => bail!("expected 'rN=VALUE', found '{}'", s),
+        };
+        let v = if v.starts_with("0x") {
+            u16::from_str_radix(&v[2..], 16)
+        } else {
+            u16::from_str(v)
+        };
+        Ok((usize::from(&Register::from_str(reg.trim())?), v.chain_err(|| "invalid value")?))
+    }
+
+    /// Parses a call depth, either `N` or the inclusive range `MIN..MAX`
+    pub fn parse_depth(s: &str) -> Result<(u16, u16)> {
+        let parse = |n: &str, default| if n.is_empty() {
+            Ok(default)
+        } else {
+            u16::from_str(n).chain_err(|| format!("invalid depth '{}'", n))
+        };
+        let mut parts = s.splitn(2, "..");
+        let lo = parse(parts.next().unwrap_or(""), 0)?;
+        match parts.next() {
+            Some(hi) => Ok((lo, parse(hi, u16::max_value())?)),
+            None => Ok((lo, lo)),
+        }
+    }
+
+    pub fn matches(&self, r: &Record) -> bool {
+        (self.ips.is_empty() || self.ips.iter().any(|ips| ips.contains(Addr::from(r.ip)))) &&
+        (self.ops.is_empty() || self.ops.iter().any(|i| r.raw.first() == Some(&i.num))) &&
+        self.regs.iter().all(|&(reg, v)| r.regs[reg] == v) &&
+        self.depth.map(|(lo, hi)| lo <= r.depth && r.depth <= hi).unwrap_or(true)
+    }
+}
+
+/// Just enough JSON to read back the trace records written above
+mod json {
+    use errors::*;
+
+    pub enum Value {
+        Num(u64),
+        Str(String),
+        Arr(Vec<Value>),
+        Obj(Vec<(String, Value)>),
+        /// `true`, `false` or `null`
+        Lit,
+    }
+
+    impl Value {
+        pub fn get(&self, key: &str) -> Option<&Value> {
+            match *self {
+                Value::Obj(ref fields) => fields.iter().find(|&&(ref k, _)| k == key).map(|f| &f.1),
+                _ => None,
+            }
+        }
+
+        pub fn num(&self) -> Option<u64> {
+            match *self {
+                Value::Num(n) => Some(n),
+                _ => None,
+            }
+        }
+    }
+
+    pub fn quote(s: &str) -> String {
+        let mut q = String::with_capacity(s.len() + 2);
+        q.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => q.push_str("\\\""),
+                '\\' => q.push_str("\\\\"),
+                '\n' => q.push_str("\\n"),
+                '\t' => q.push_str("\\t"),
+                c if (c as u32) < 0x20 => q.push_str(&format!("\\u{:04x}", c as u32)),
+                c => q.push(c),
+            }
+        }
+        q.push('"');
+        q
+    }
+
+    pub fn parse(s: &str) -> Result<Value> {
+        let chars = s.chars().collect::<Vec<_>>();
+        let mut p = Parser {
+            s: &chars,
+            pos: 0,
+        };
+        let v = p.value()?;
+        p.skip_space();
+        if p.pos != chars.len() {
+            bail!("trailing characters after JSON value");
+        }
+        Ok(v)
+    }
+
+    struct Parser<'a> {
+        s: &'a [char],
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn skip_space(&mut self) {
+            while self.pos < self.s.len() && self.s[self.pos].is_whitespace() {
+                self.pos += 1;
+            }
+        }
+
+        fn next(&mut self) -> Result<char> {
+            match self.s.get(self.pos) {
+                Some(&c) => {
+                    self.pos += 1;
+                    Ok(c)
+                }
+                None => bail!("unexpected end of JSON"),
+            }
+        }
+
+        fn expect(&mut self, c: char) -> Result<()> {
+            self.skip_space();
+            let n = self.next()?;
+            if n != c {
+                bail!("expected '{}', found '{}'", c, n);
+            }
+            Ok(())
+        }
+
+        fn peek(&mut self) -> Option<char> {
+            self.skip_space();
+            self.s.get(self.pos).cloned()
+        }
+
+        fn value(&mut self) -> Result<Value> {
+            match self.peek() {
+                Some('{') => {
+                    self.pos += 1;
+                    let mut fields = Vec::new();
+                    if self.peek() == Some('}') {
+                        self.pos += 1;
+                        return Ok(Value::Obj(fields));
+                    }
+                    loop {
+                        self.skip_space();
+                        self.expect('"')?;
+                        let k = self.string()?;
+                        self.expect(':')?;
+                        fields.push((k, self.value()?));
+                        match self.peek() {
+                            Some(',') => self.pos += 1,
+                            _ => break,
+                        }
+                    }
+                    self.expect('}')?;
+                    Ok(Value::Obj(fields))
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let mut items = Vec::new();
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(Value::Arr(items));
+                    }
+                    loop {
+                        items.push(self.value()?);
+                        match self.peek() {
+                            Some(',') => self.pos += 1,
+                            _ => break,
+                        }
+                    }
+                    self.expect(']')?;
+                    Ok(Value::Arr(items))
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    self.string().map(Value::Str)
+                }
+                Some(c) if c.is_digit(10) => {
+                    let start = self.pos;
+                    while self.pos < self.s.len() && self.s[self.pos].is_digit(10) {
+                        self.pos += 1;
+                    }
+                    let n = self.s[start..self.pos].iter().cloned().collect::<String>();
+                    Ok(Value::Num(n.parse().chain_err(|| "invalid number")?))
+                }
+                Some(c) if c.is_alphabetic() => {
+                    while self.pos < self.s.len() && self.s[self.pos].is_alphabetic() {
+                        self.pos += 1;
+                    }
+                    Ok(Value::Lit)
+                }
+                Some(c) => bail!("unexpected '{}' in JSON", c),
+                None => bail!("unexpected end of JSON"),
+            }
+        }
+
+        /// The rest of a string whose opening quote has been consumed
+        fn string(&mut self) -> Result<String> {
+            let mut s = String::new();
+            loop {
+                match self.next()? {
+                    '"' => return Ok(s),
+                    '\\' => {
+                        match self.next()? {
+                            'n' => s.push('\n'),
+                            't' => s.push('\t'),
+                            'r' => s.push('\r'),
+                            'b' => s.push('\u{8}'),
+                            'f' => s.push('\u{c}'),
+                            'u' => {
+                                let mut n = 0;
+                                for _ in 0..4 {
+                                    let d = self.next()?;
+                                    n = n * 16 + d.to_digit(16).ok_or("invalid \\u escape")?;
+                                }
+                                s.push(::std::char::from_u32(n).unwrap_or('?'));
+                            }
+                            c => s.push(c),
+                        }
+                    }
+                    c => s.push(c),
+                }
+            }
+        }
+    }
+}